@@ -0,0 +1,237 @@
+//! Compares ways of fanning a message out to WebSocket clients: one mutex
+//! over every client, held for the whole fan-out with a copy of the message
+//! per client (the server's original design); the sharded registry it uses
+//! now, sharing one `Arc<str>`; and a `tokio::sync::broadcast` channel.
+//!
+//! For each design it reports how long a fan-out to every client takes and
+//! how long a client connecting and leaving waits while fan-outs run
+//! back to back.
+//!
+//!     cargo bench --bench fanout -- [clients]
+
+#[allow(dead_code)]
+#[path = "../src/registry.rs"]
+mod registry;
+
+use std::collections::{HashMap, VecDeque};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use registry::{Member, Registry};
+
+const DEFAULT_CLIENTS: usize = 10_000;
+/// Fan-outs timed per design.
+const ROUNDS: usize = 50;
+/// How long connects are timed under load per design.
+const CONTENDED: Duration = Duration::from_secs(2);
+const CHANNELS: usize = 10;
+
+/// A client with an outbound queue, as in `queue.rs`.
+struct Client<T> {
+    channel: String,
+    queue: Mutex<VecDeque<T>>,
+}
+
+impl<T> Client<T> {
+    fn new(id: usize) -> Self {
+        Self { channel: format!("channel-{}", id % CHANNELS), queue: Mutex::new(VecDeque::new()) }
+    }
+
+    fn push(&self, message: T) {
+        self.queue.lock().unwrap().push_back(message);
+    }
+
+    fn drain(&self) {
+        self.queue.lock().unwrap().clear();
+    }
+}
+
+impl<T> Member for Client<T> {
+    fn channel(&self) -> &str {
+        &self.channel
+    }
+}
+
+trait Design: Send + Sync + 'static {
+    const NAME: &'static str;
+    fn new(clients: usize) -> Self;
+    /// Queues `json` for every client; returns how many got it.
+    fn fan_out(&self, json: &str) -> impl std::future::Future<Output = usize> + Send;
+    /// Connects a client and disconnects it again.
+    fn connect(&self, id: usize) -> impl std::future::Future<Output = ()> + Send;
+    /// Empties the clients' queues between rounds.
+    fn drain(&self);
+}
+
+struct GlobalMutex {
+    users: tokio::sync::Mutex<HashMap<usize, Client<String>>>,
+}
+
+impl Design for GlobalMutex {
+    const NAME: &'static str = "global mutex";
+
+    fn new(clients: usize) -> Self {
+        Self { users: tokio::sync::Mutex::new((0..clients).map(|id| (id, Client::new(id))).collect()) }
+    }
+
+    async fn fan_out(&self, json: &str) -> usize {
+        let users = self.users.lock().await;
+        for user in users.values() {
+            user.push(json.to_string());
+        }
+        users.len()
+    }
+
+    async fn connect(&self, id: usize) {
+        self.users.lock().await.insert(id, Client::new(id));
+        self.users.lock().await.remove(&id);
+    }
+
+    fn drain(&self) {
+        // A connect holding the lock only delays draining to the next round.
+        if let Ok(users) = self.users.try_lock() {
+            users.values().for_each(Client::drain);
+        }
+    }
+}
+
+struct Sharded {
+    users: Registry<Client<Arc<str>>>,
+}
+
+impl Design for Sharded {
+    const NAME: &'static str = "sharded registry";
+
+    fn new(clients: usize) -> Self {
+        let users = Registry::new();
+        (0..clients).for_each(|id| users.insert(id, Client::new(id)));
+        Self { users }
+    }
+
+    async fn fan_out(&self, json: &str) -> usize {
+        let json: Arc<str> = json.into();
+        let mut sent = 0;
+        self.users.for_each(None, |user| {
+            user.push(json.clone());
+            sent += 1;
+        });
+        sent
+    }
+
+    async fn connect(&self, id: usize) {
+        self.users.insert(id, Client::new(id));
+        self.users.remove(id);
+    }
+
+    fn drain(&self) {
+        self.users.for_each(None, Client::drain);
+    }
+}
+
+/// Each client's queue is its receiver; the per-client work of a fan-out
+/// happens when the receivers take the message, which is timed with it.
+struct Broadcast {
+    tx: tokio::sync::broadcast::Sender<Arc<str>>,
+    receivers: Mutex<Vec<tokio::sync::broadcast::Receiver<Arc<str>>>>,
+}
+
+impl Design for Broadcast {
+    const NAME: &'static str = "tokio broadcast";
+
+    fn new(clients: usize) -> Self {
+        let (tx, _) = tokio::sync::broadcast::channel(ROUNDS);
+        let receivers = Mutex::new((0..clients).map(|_| tx.subscribe()).collect());
+        Self { tx, receivers }
+    }
+
+    async fn fan_out(&self, json: &str) -> usize {
+        let sent = self.tx.send(json.into()).unwrap_or(0);
+        // Under contention the receivers are busy elsewhere; skip them.
+        if let Ok(mut receivers) = self.receivers.try_lock() {
+            for rx in receivers.iter_mut() {
+                let _ = black_box(rx.try_recv());
+            }
+        }
+        sent
+    }
+
+    async fn connect(&self, _id: usize) {
+        drop(black_box(self.tx.subscribe()));
+    }
+
+    fn drain(&self) {}
+}
+
+async fn run<D: Design>(clients: usize, json: &str) {
+    let design = Arc::new(D::new(clients));
+
+    let mut fan_outs = Vec::with_capacity(ROUNDS);
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        black_box(design.fan_out(json).await);
+        fan_outs.push(start.elapsed());
+        design.drain();
+    }
+
+    let running = Arc::new(AtomicBool::new(true));
+    let load = {
+        let (design, running, json) = (design.clone(), running.clone(), json.to_string());
+        tokio::spawn(async move {
+            let mut rounds = 0usize;
+            while running.load(Ordering::Relaxed) {
+                black_box(design.fan_out(&json).await);
+                rounds += 1;
+                // Keep queues from growing without bound.
+                if rounds.is_multiple_of(16) {
+                    design.drain();
+                }
+                tokio::task::yield_now().await;
+            }
+            rounds
+        })
+    };
+    let mut connects = Vec::new();
+    let deadline = Instant::now() + CONTENDED;
+    while Instant::now() < deadline {
+        let start = Instant::now();
+        design.connect(clients + connects.len()).await;
+        connects.push(start.elapsed());
+        tokio::task::yield_now().await;
+    }
+    running.store(false, Ordering::Relaxed);
+    let rounds = load.await.unwrap();
+
+    fan_outs.sort();
+    connects.sort();
+    println!(
+        "{:<18} {:>12?} {:>12?} {:>10} {:>12?} {:>12?} {:>12?}",
+        D::NAME,
+        percentile(&fan_outs, 50),
+        percentile(&fan_outs, 99),
+        rounds,
+        percentile(&connects, 50),
+        percentile(&connects, 99),
+        connects.last().copied().unwrap_or_default(),
+    );
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    sorted.get((sorted.len().saturating_sub(1)) * p / 100).copied().unwrap_or_default()
+}
+
+fn main() {
+    let clients = std::env::args().skip(1).find_map(|arg| arg.parse().ok()).unwrap_or(DEFAULT_CLIENTS);
+    let json = format!("{{\"event\":\"push\",\"body\":\"{}\"}}", "x".repeat(1024));
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    println!("{} clients, {} byte message, {} fan-outs timed per design", clients, json.len(), ROUNDS);
+    println!(
+        "{:<18} {:>12} {:>12} {:>10} {:>12} {:>12} {:>12}",
+        "design", "fan-out p50", "fan-out p99", "under load", "connect p50", "connect p99", "connect max"
+    );
+    runtime.block_on(async {
+        run::<GlobalMutex>(clients, &json).await;
+        run::<Sharded>(clients, &json).await;
+        run::<Broadcast>(clients, &json).await;
+    });
+}
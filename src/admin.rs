@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use warp::http::{HeaderMap, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::Reply;
+use log::{error, info, warn};
+use crate::auth::{Auth, AuthError};
+use crate::deadletter::{PayloadEncoding, Reason, Undeliverable};
+use crate::forward::Forwarder;
+use crate::payload;
+use crate::server::{self, Backpressure, Delivery, SendError, WebSocketServer, ALL_CHANNELS};
+
+/// Admin endpoints need a token granted every channel (`*`). Without tokens
+/// configured they are refused, unless explicitly opened to everyone.
+pub fn authorize(auth: &Auth, headers: &HeaderMap, query: &HashMap<String, String>) -> Result<(), (&'static str, StatusCode)> {
+    if auth.is_admin_open() {
+        return Ok(());
+    }
+    if !auth.is_enabled() {
+        warn!("Rejected admin request: no tokens configured");
+        return Err(("Admin API is disabled until tokens are configured", StatusCode::FORBIDDEN));
+    }
+    match auth.authenticate(headers, query, ALL_CHANNELS) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            warn!("Rejected admin request: {:?}", e);
+            Err(match e {
+                AuthError::MissingToken | AuthError::InvalidToken => ("Unauthorized", StatusCode::UNAUTHORIZED),
+                AuthError::Forbidden => ("Forbidden", StatusCode::FORBIDDEN),
+            })
+        }
+    }
+}
+
+/// `GET /admin/deliveries`: recent outbound forwarding attempts, newest first.
+pub async fn handle_deliveries(
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    auth: Arc<Auth>,
+    forwarder: Forwarder,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    Ok(warp::reply::json(&forwarder.deliveries()).into_response())
+}
+
+/// `GET /admin/dead-letters`: undeliverable messages, newest first,
+/// optionally filtered by `?reason=` and `?channel=`.
+pub async fn handle_dead_letters(
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    auth: Arc<Auth>,
+    ws_server: WebSocketServer,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    let reason = match query.get("reason").map(|reason| Reason::parse(reason)) {
+        Some(None) => return Ok(warp::reply::with_status("Invalid reason", StatusCode::BAD_REQUEST).into_response()),
+        Some(reason) => reason,
+        None => None,
+    };
+    let letters = ws_server.dead_letters.list(reason, query.get("channel").map(String::as_str));
+    Ok(warp::reply::json(&letters).into_response())
+}
+
+/// `GET /admin/dead-letters/{id}`
+pub async fn handle_dead_letter(
+    id: u64,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    auth: Arc<Auth>,
+    ws_server: WebSocketServer,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    match ws_server.dead_letters.get(id) {
+        Some(letter) => Ok(warp::reply::json(&letter).into_response()),
+        None => Ok(warp::reply::with_status("No such dead letter", StatusCode::NOT_FOUND).into_response()),
+    }
+}
+
+/// What a dead letter is published as: its payload if that is JSON, a body
+/// that was not UTF-8 as `payload::decode` publishes binary webhooks, and any
+/// other text as a string.
+fn published_payload(message: &Undeliverable) -> Value {
+    match message.payload_encoding {
+        PayloadEncoding::Text => serde_json::from_str(&message.payload).unwrap_or_else(|_| Value::String(message.payload.clone())),
+        PayloadEncoding::Base64 => {
+            let bytes = message.payload_encoding.decode(&message.payload).unwrap_or_default();
+            payload::decode(&HeaderMap::new(), &bytes).map(|(_, value)| value).unwrap_or_default()
+        }
+    }
+}
+
+/// Where to redeliver a dead letter. Without either field, a forward failure
+/// is forwarded again to its target and anything else is published again on
+/// its original channel.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Redelivery {
+    client: Option<usize>,
+    channel: Option<String>,
+}
+
+/// `POST /admin/dead-letters/{id}/redeliver`: sends the message to one
+/// client, publishes it to a channel or forwards it to its target, removing
+/// it from the dead letters once it has been accepted.
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_redeliver(
+    id: u64,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    body: Bytes,
+    auth: Arc<Auth>,
+    ws_server: WebSocketServer,
+    forwarder: Forwarder,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    let redelivery: Redelivery = if body.iter().all(u8::is_ascii_whitespace) {
+        Redelivery::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(redelivery) => redelivery,
+            Err(_) => {
+                return Ok(warp::reply::with_status("Expected {\"client\": <id>} or {\"channel\": <name>}", StatusCode::BAD_REQUEST)
+                    .into_response())
+            }
+        }
+    };
+    let Some(letter) = ws_server.dead_letters.get(id) else {
+        return Ok(warp::reply::with_status("No such dead letter", StatusCode::NOT_FOUND).into_response());
+    };
+    let recipient = (redelivery.client, &redelivery.channel);
+    if let ((None, None), Some(target), Some(forward)) = (recipient, &letter.message.target, &letter.message.forward) {
+        // Retried in the background; failing again makes a new dead letter.
+        if !forwarder.redeliver(target, forward.clone()) {
+            return Ok(warp::reply::with_status("No such forward target", StatusCode::NOT_FOUND).into_response());
+        }
+        ws_server.dead_letters.remove(id);
+        let reply = json!({ "target": target, "event_id": forward.event_id });
+        info!("Redelivering dead letter {}: {}", id, reply);
+        return Ok(warp::reply::with_status(warp::reply::json(&reply), StatusCode::ACCEPTED).into_response());
+    }
+    let reply = match (redelivery.client, redelivery.channel.or(letter.message.channel.clone())) {
+        (Some(client), _) => {
+            let text = match letter.message.payload_encoding {
+                PayloadEncoding::Text => letter.message.payload.clone(),
+                // WebSocket text frames cannot carry arbitrary bytes.
+                PayloadEncoding::Base64 => published_payload(&letter.message).to_string(),
+            };
+            let delivery = Delivery::text(letter.message.event_id, text);
+            match ws_server.try_send_to(client, delivery).await {
+                Ok(()) => json!({ "client": client }),
+                Err(SendError::NoSuchUser) => {
+                    return Ok(warp::reply::with_status("No such client", StatusCode::NOT_FOUND).into_response())
+                }
+                Err(SendError::Closed) => {
+                    return Ok(warp::reply::with_status("Client connection closed", StatusCode::CONFLICT).into_response())
+                }
+                Err(SendError::Full) => {
+                    let reply = warp::reply::with_status("Client queue full", StatusCode::SERVICE_UNAVAILABLE);
+                    return Ok(warp::reply::with_header(reply, "retry-after", "1").into_response());
+                }
+            }
+        }
+        (None, Some(channel)) if server::is_valid_channel(&channel) => {
+            match ws_server.publish(&channel, published_payload(&letter.message)).await {
+                Ok(event_id) => json!({ "channel": channel, "event_id": event_id }),
+                Err(e) if e.is::<Backpressure>() => {
+                    let reply = warp::reply::with_status("Subscribers are falling behind, retry later", StatusCode::SERVICE_UNAVAILABLE);
+                    return Ok(warp::reply::with_header(reply, "retry-after", "1").into_response());
+                }
+                Err(e) => {
+                    error!("Error redelivering dead letter {}: {}", id, e);
+                    return Ok(warp::reply::with_status("Error broadcasting message", StatusCode::INTERNAL_SERVER_ERROR)
+                        .into_response());
+                }
+            }
+        }
+        (None, Some(_)) => return Ok(warp::reply::with_status("Invalid channel name", StatusCode::BAD_REQUEST).into_response()),
+        (None, None) => {
+            return Ok(warp::reply::with_status("Dead letter has no channel; give a client or channel", StatusCode::BAD_REQUEST)
+                .into_response())
+        }
+    };
+    ws_server.dead_letters.remove(id);
+    info!("Redelivered dead letter {}: {}", id, reply);
+    Ok(warp::reply::json(&reply).into_response())
+}
+
+/// `DELETE /admin/dead-letters/{id}`
+pub async fn handle_delete_dead_letter(
+    id: u64,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    auth: Arc<Auth>,
+    ws_server: WebSocketServer,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    match ws_server.dead_letters.remove(id) {
+        Some(_) => Ok(StatusCode::NO_CONTENT.into_response()),
+        None => Ok(warp::reply::with_status("No such dead letter", StatusCode::NOT_FOUND).into_response()),
+    }
+}
+
+/// `DELETE /admin/dead-letters`: purges every dead letter.
+pub async fn handle_purge_dead_letters(
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    auth: Arc<Auth>,
+    ws_server: WebSocketServer,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    let purged = ws_server.dead_letters.purge();
+    info!("Purged {} dead letters", purged);
+    Ok(warp::reply::json(&json!({ "purged": purged })).into_response())
+}
+
+/// `GET /admin/clients`: connected WebSocket and SSE clients by id,
+/// optionally only those of `?channel=`.
+pub async fn handle_clients(
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    auth: Arc<Auth>,
+    ws_server: WebSocketServer,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    let clients = ws_server.clients(query.get("channel").map(String::as_str)).await;
+    Ok(warp::reply::json(&clients).into_response())
+}
+
+/// `GET /admin/clients/{id}`
+pub async fn handle_client(
+    id: usize,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    auth: Arc<Auth>,
+    ws_server: WebSocketServer,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    match ws_server.client_info(id).await {
+        Some(client) => Ok(warp::reply::json(&client).into_response()),
+        None => Ok(warp::reply::with_status("No such client", StatusCode::NOT_FOUND).into_response()),
+    }
+}
+
+/// `POST /admin/clients/{id}/send`: queues the JSON body for one client.
+/// A message that cannot be queued is dead-lettered.
+pub async fn handle_send(
+    id: usize,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    body: Bytes,
+    auth: Arc<Auth>,
+    ws_server: WebSocketServer,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    let Ok(message) = serde_json::from_slice::<Value>(&body) else {
+        return Ok(warp::reply::with_status("Invalid JSON body", StatusCode::BAD_REQUEST).into_response());
+    };
+    match ws_server.send_to(id, message).await {
+        Ok(()) => Ok(warp::reply::json(&json!({ "client": id })).into_response()),
+        Err(e) => match e.downcast_ref::<SendError>() {
+            Some(SendError::NoSuchUser) => Ok(warp::reply::with_status("No such client", StatusCode::NOT_FOUND).into_response()),
+            Some(SendError::Closed) => {
+                Ok(warp::reply::with_status("Client connection closed", StatusCode::CONFLICT).into_response())
+            }
+            Some(SendError::Full) => {
+                let reply = warp::reply::with_status("Client queue full", StatusCode::SERVICE_UNAVAILABLE);
+                Ok(warp::reply::with_header(reply, "retry-after", "1").into_response())
+            }
+            None => {
+                error!("Error sending message to user {}: {}", id, e);
+                Ok(warp::reply::with_status("Error sending message", StatusCode::INTERNAL_SERVER_ERROR).into_response())
+            }
+        },
+    }
+}
+
+/// `DELETE /admin/clients/{id}`: closes the client's connection with code
+/// 1008 straight away; messages still queued for it are dead-lettered.
+pub async fn handle_disconnect(
+    id: usize,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    auth: Arc<Auth>,
+    ws_server: WebSocketServer,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    match ws_server.disconnect(id, 1008, "disconnected by an administrator", Reason::Disconnected).await {
+        Ok(()) => {
+            ws_server.metrics.eviction("admin");
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        Err(_) => Ok(warp::reply::with_status("No such client", StatusCode::NOT_FOUND).into_response()),
+    }
+}
+
+/// `POST /admin/channels/{channel}/broadcast`: publishes the JSON body to a
+/// channel as an event, or to every client, unrecorded, for `*`.
+pub async fn handle_channel_broadcast(
+    channel: String,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    body: Bytes,
+    auth: Arc<Auth>,
+    ws_server: WebSocketServer,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    if channel != ALL_CHANNELS && !server::is_valid_channel(&channel) {
+        return Ok(warp::reply::with_status("Invalid channel name", StatusCode::BAD_REQUEST).into_response());
+    }
+    let Ok(message) = serde_json::from_slice::<Value>(&body) else {
+        return Ok(warp::reply::with_status("Invalid JSON body", StatusCode::BAD_REQUEST).into_response());
+    };
+    let published = if channel == ALL_CHANNELS {
+        ws_server.broadcast(message).await.map(|()| None)
+    } else {
+        ws_server.publish(&channel, message).await.map(Some)
+    };
+    match published {
+        Ok(event_id) => {
+            info!("Admin broadcast to channel {}", channel);
+            Ok(warp::reply::json(&json!({ "channel": channel, "event_id": event_id })).into_response())
+        }
+        Err(e) if e.is::<Backpressure>() => {
+            let reply = warp::reply::with_status("Subscribers are falling behind, retry later", StatusCode::SERVICE_UNAVAILABLE);
+            Ok(warp::reply::with_header(reply, "retry-after", "1").into_response())
+        }
+        Err(e) => {
+            error!("Error broadcasting admin message to channel {}: {}", channel, e);
+            Ok(warp::reply::with_status("Error broadcasting message", StatusCode::INTERNAL_SERVER_ERROR).into_response())
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::{HashMap, HashSet};
+use warp::http::HeaderMap;
+use log::warn;
+use crate::server::ALL_CHANNELS;
+
+/// WebSocket subprotocol browsers use to carry a token, since they cannot
+/// set `Authorization` on an upgrade: `Sec-WebSocket-Protocol: bearer, <token>`.
+pub const BEARER_PROTOCOL: &str = "bearer";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+    pub name: String,
+    token: String,
+    channels: HashSet<String>,
+    /// Channels this principal may publish to from a WebSocket.
+    publish: HashSet<String>,
+}
+
+impl Principal {
+    pub fn new(name: &str, token: &str, channels: &[&str], publish: &[&str]) -> Self {
+        Self {
+            name: name.to_string(),
+            token: token.to_string(),
+            channels: channels.iter().map(|c| c.to_string()).collect(),
+            publish: publish.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    /// Whether this principal may subscribe to `channel`. Subscribing to
+    /// [`ALL_CHANNELS`] requires the `*` grant.
+    pub fn allows(&self, channel: &str) -> bool {
+        self.channels.contains(ALL_CHANNELS) || self.channels.contains(channel)
+    }
+}
+
+/// Channels a WebSocket client may publish to over the control protocol.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum PublishGrant {
+    #[default]
+    None,
+    Channels(HashSet<String>),
+    /// Every channel, including broadcasting to all of them with `*`.
+    All,
+}
+
+impl PublishGrant {
+    pub fn allows(&self, channel: &str) -> bool {
+        match self {
+            PublishGrant::None => false,
+            PublishGrant::Channels(channels) => channels.contains(channel),
+            PublishGrant::All => true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken,
+    Forbidden,
+}
+
+/// Where the token came from; the subprotocol has to be echoed back on the
+/// upgrade response or browsers abort the connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenSource {
+    Header,
+    Protocol,
+    Query,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    principals: Vec<Principal>,
+    /// Lets unauthenticated clients publish when no tokens are configured.
+    anonymous_publish: bool,
+    /// Opens the admin API to everyone when no tokens are configured.
+    anonymous_admin: bool,
+}
+
+impl Auth {
+    pub fn insert(&mut self, principal: Principal) {
+        self.principals.push(principal);
+    }
+
+    pub fn set_anonymous_publish(&mut self, allowed: bool) {
+        self.anonymous_publish = allowed;
+    }
+
+    pub fn set_anonymous_admin(&mut self, allowed: bool) {
+        self.anonymous_admin = allowed;
+    }
+
+    /// Whether admin requests are accepted without a token, which takes no
+    /// tokens being configured and `anonymous_admin`.
+    pub fn is_admin_open(&self) -> bool {
+        !self.is_enabled() && self.anonymous_admin
+    }
+
+    /// What a client authenticated as `principal` may publish. Nobody may
+    /// publish unless explicitly granted.
+    pub fn publish_grant(&self, principal: Option<&Principal>) -> PublishGrant {
+        match principal {
+            Some(principal) if principal.publish.contains(ALL_CHANNELS) => PublishGrant::All,
+            Some(principal) => PublishGrant::Channels(principal.publish.clone()),
+            None if !self.is_enabled() && self.anonymous_publish => PublishGrant::All,
+            None => PublishGrant::None,
+        }
+    }
+
+    /// With no tokens configured, connections are accepted anonymously.
+    pub fn is_enabled(&self) -> bool {
+        !self.principals.is_empty()
+    }
+
+    /// Authenticates an upgrade request for `channel`. Returns `None` as the
+    /// principal when authentication is disabled.
+    pub fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        query: &HashMap<String, String>,
+        channel: &str,
+    ) -> Result<(Option<Principal>, Option<TokenSource>), AuthError> {
+        if !self.is_enabled() {
+            return Ok((None, None));
+        }
+        let (token, source) = extract_token(headers, query).ok_or(AuthError::MissingToken)?;
+        let principal = self.find(&token).ok_or(AuthError::InvalidToken)?;
+        if !principal.allows(channel) {
+            warn!("{} is not allowed to subscribe to channel {}", principal.name, channel);
+            return Err(AuthError::Forbidden);
+        }
+        Ok((Some(principal.clone()), Some(source)))
+    }
+
+    /// Like `authenticate`, but also accepts `secret` as a token for
+    /// `channel`, as an endpoint's token is for the endpoint's channel. A
+    /// client admitted by the secret has no principal.
+    pub fn authenticate_with_secret(
+        &self,
+        headers: &HeaderMap,
+        query: &HashMap<String, String>,
+        channel: &str,
+        secret: &str,
+    ) -> Result<(Option<Principal>, Option<TokenSource>), AuthError> {
+        if let (true, Some((token, source))) = (self.is_enabled(), extract_token(headers, query)) {
+            if constant_time_eq(secret.as_bytes(), token.as_bytes()) {
+                return Ok((None, Some(source)));
+            }
+        }
+        self.authenticate(headers, query, channel)
+    }
+
+    /// The principal whose token a request carries in `Authorization` or
+    /// `X-Api-Key`, if any, regardless of what it may access.
+    pub fn identify(&self, headers: &HeaderMap) -> Option<&Principal> {
+        let (token, _) = extract_token(headers, &HashMap::new())?;
+        self.find(&token)
+    }
+
+    fn find(&self, token: &str) -> Option<&Principal> {
+        // Compare against every token so timing does not reveal which one matched.
+        self.principals
+            .iter()
+            .fold(None, |found, p| if constant_time_eq(p.token.as_bytes(), token.as_bytes()) { Some(p) } else { found })
+    }
+}
+
+fn extract_token(headers: &HeaderMap, query: &HashMap<String, String>) -> Option<(String, TokenSource)> {
+    if let Some(value) = headers.get("authorization").and_then(|v| v.to_str().ok()) {
+        if let Some(token) = value.strip_prefix("Bearer ").or_else(|| value.strip_prefix("bearer ")) {
+            return Some((token.trim().to_string(), TokenSource::Header));
+        }
+    }
+    if let Some(value) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return Some((value.trim().to_string(), TokenSource::Header));
+    }
+    if let Some(value) = headers.get("sec-websocket-protocol").and_then(|v| v.to_str().ok()) {
+        let mut protocols = value.split(',').map(str::trim);
+        if protocols.next() == Some(BEARER_PROTOCOL) {
+            if let Some(token) = protocols.next() {
+                return Some((token.to_string(), TokenSource::Protocol));
+            }
+        }
+    }
+    query.get("token").map(|token| (token.clone(), TokenSource::Query))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
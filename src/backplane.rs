@@ -0,0 +1,341 @@
+use std::sync::Arc;
+use std::time::Duration;
+use futures_util::StreamExt;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+use log::{info, warn};
+use crate::server::WebSocketServer;
+
+/// Frames buffered for a slow subscriber of the in-process backplane, and
+/// for Redis while it is unreachable, before new ones are dropped.
+const BUFFER: usize = 10_000;
+
+/// Delay before reconnecting to Redis after the connection failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// What one node tells the others.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// An event published on `channel`; `event_id` is the id it was given on
+    /// the node it originated on.
+    Publish { channel: String, event_id: u64, payload: String },
+    /// A message for every client regardless of channel.
+    Broadcast { payload: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Frame {
+    /// The node the message originated on, which ignores it when it comes
+    /// back so that nothing is delivered twice or relayed in a loop.
+    pub node: String,
+    #[serde(flatten)]
+    pub message: Message,
+}
+
+/// Carries frames between the nodes of a cluster. Delivery is best effort:
+/// frames sent while a node is unreachable are lost.
+pub trait Backplane: Send + Sync {
+    /// Sends `frame` to every node, possibly including this one, without
+    /// waiting for the network.
+    fn send(&self, frame: Frame);
+    /// Passes every frame received from now on to `tx`.
+    fn subscribe(&self, tx: mpsc::UnboundedSender<Frame>);
+}
+
+/// A backplane between the nodes of one process; a single instance needs
+/// nothing more.
+#[derive(Clone)]
+pub struct InProcess {
+    tx: broadcast::Sender<Frame>,
+}
+
+impl InProcess {
+    pub fn new() -> Self {
+        Self { tx: broadcast::channel(BUFFER).0 }
+    }
+}
+
+impl Default for InProcess {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backplane for InProcess {
+    fn send(&self, frame: Frame) {
+        // Fails only when nobody subscribed, and then nobody is listening.
+        let _ = self.tx.send(frame);
+    }
+
+    fn subscribe(&self, tx: mpsc::UnboundedSender<Frame>) {
+        let mut rx = self.tx.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(frame) => {
+                        if tx.send(frame).is_err() {
+                            return;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => warn!("Backplane subscriber fell behind, {} frames lost", n),
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+}
+
+/// Redis pub/sub: every node publishes its frames to, and subscribes to, one
+/// Redis channel. Connections are re-established after failures.
+pub struct Redis {
+    client: redis::Client,
+    channel: String,
+    outgoing: mpsc::Sender<String>,
+}
+
+impl Redis {
+    /// Starts the task that publishes this node's frames.
+    pub fn start(client: redis::Client, channel: String) -> Self {
+        let (outgoing, rx) = mpsc::channel(BUFFER);
+        tokio::spawn(run_publisher(client.clone(), channel.clone(), rx));
+        Self { client, channel, outgoing }
+    }
+}
+
+impl Backplane for Redis {
+    fn send(&self, frame: Frame) {
+        let payload = match serde_json::to_string(&frame) {
+            Ok(payload) => payload,
+            Err(e) => return warn!("Failed to serialize backplane frame: {}", e),
+        };
+        if self.outgoing.try_send(payload).is_err() {
+            warn!("Redis backplane is not keeping up, frame dropped");
+        }
+    }
+
+    fn subscribe(&self, tx: mpsc::UnboundedSender<Frame>) {
+        let (client, channel) = (self.client.clone(), self.channel.clone());
+        tokio::spawn(async move {
+            loop {
+                match receive(&client, &channel, &tx).await {
+                    Ok(()) if tx.is_closed() => return,
+                    Ok(()) => warn!("Redis backplane subscription closed, reconnecting"),
+                    Err(e) => warn!("Redis backplane subscription failed: {}", e),
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        });
+    }
+}
+
+async fn run_publisher(client: redis::Client, channel: String, mut rx: mpsc::Receiver<String>) {
+    let mut connection = None;
+    while let Some(payload) = rx.recv().await {
+        // A connection that went stale while idle only fails on use, so
+        // reconnect once before giving up on the frame.
+        for attempt in 1..=2 {
+            let conn = match &mut connection {
+                Some(conn) => conn,
+                None => match client.get_multiplexed_async_connection().await {
+                    Ok(conn) => connection.insert(conn),
+                    Err(e) => {
+                        warn!("Failed to connect to the Redis backplane, frame dropped: {}", e);
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                        break;
+                    }
+                },
+            };
+            match redis::cmd("PUBLISH").arg(&channel).arg(&payload).query_async::<i64>(conn).await {
+                Ok(_) => break,
+                Err(e) => {
+                    connection = None;
+                    if attempt == 2 {
+                        warn!("Failed to publish to the Redis backplane, frame dropped: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Forwards frames from Redis to `tx` until the subscription ends.
+async fn receive(client: &redis::Client, channel: &str, tx: &mpsc::UnboundedSender<Frame>) -> redis::RedisResult<()> {
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(channel).await?;
+    info!("Subscribed to Redis backplane channel {}", channel);
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let frame = message.get_payload::<String>().map_err(|e| e.to_string()).and_then(|payload| {
+            serde_json::from_str::<Frame>(&payload).map_err(|e| e.to_string())
+        });
+        match frame {
+            Ok(frame) => {
+                if tx.send(frame).is_err() {
+                    return Ok(());
+                }
+            }
+            Err(e) => warn!("Ignoring malformed backplane frame: {}", e),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub enum BackplaneKind {
+    InProcess,
+    Redis { client: redis::Client, channel: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct BackplaneSettings {
+    /// `None` picks a random id at startup.
+    pub node_id: Option<String>,
+    pub kind: BackplaneKind,
+}
+
+impl BackplaneSettings {
+    pub fn start(self) -> Node {
+        let id = self
+            .node_id
+            .unwrap_or_else(|| rand::thread_rng().sample_iter(&Alphanumeric).take(12).map(char::from).collect());
+        let backplane: Arc<dyn Backplane> = match self.kind {
+            BackplaneKind::InProcess => Arc::new(InProcess::new()),
+            BackplaneKind::Redis { client, channel } => {
+                info!("Node {} joining the Redis backplane on channel {}", id, channel);
+                Arc::new(Redis::start(client, channel))
+            }
+        };
+        Node { id: id.into(), backplane }
+    }
+}
+
+/// This instance's membership in the cluster: relays what is published here
+/// to the other nodes and what they publish to the clients connected here.
+#[derive(Clone)]
+pub struct Node {
+    pub id: Arc<str>,
+    backplane: Arc<dyn Backplane>,
+}
+
+impl Node {
+    pub fn publish(&self, channel: &str, event_id: u64, payload: &str) {
+        let message = Message::Publish { channel: channel.to_string(), event_id, payload: payload.to_string() };
+        self.backplane.send(Frame { node: self.id.to_string(), message });
+    }
+
+    pub fn broadcast(&self, payload: &str) {
+        let message = Message::Broadcast { payload: payload.to_string() };
+        self.backplane.send(Frame { node: self.id.to_string(), message });
+    }
+
+    /// Receives the frames other nodes send from now on.
+    fn inbox(&self) -> Inbox {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.backplane.subscribe(tx);
+        Inbox { node: self.id.clone(), rx }
+    }
+
+    /// Delivers what other nodes publish to this node's clients.
+    pub fn spawn_receiver(&self, ws_server: WebSocketServer) {
+        let mut inbox = self.inbox();
+        tokio::spawn(async move {
+            while let Some(message) = inbox.recv().await {
+                match message {
+                    Message::Publish { channel, payload, .. } => ws_server.relay(Some(&channel), payload).await,
+                    Message::Broadcast { payload } => ws_server.relay(None, payload).await,
+                }
+            }
+        });
+    }
+}
+
+struct Inbox {
+    node: Arc<str>,
+    rx: mpsc::UnboundedReceiver<Frame>,
+}
+
+impl Inbox {
+    /// Waits for the next message from another node; the node's own frames,
+    /// which the backplane may echo back, are skipped.
+    async fn recv(&mut self) -> Option<Message> {
+        loop {
+            let frame = self.rx.recv().await?;
+            if *frame.node != *self.node {
+                return Some(frame.message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str, backplane: Arc<dyn Backplane>) -> Node {
+        Node { id: id.into(), backplane }
+    }
+
+    async fn next(inbox: &mut Inbox, wait: Duration) -> Option<Message> {
+        tokio::time::timeout(wait, inbox.recv()).await.ok().flatten()
+    }
+
+    fn published(message: Option<Message>) -> Option<(String, u64, String)> {
+        match message? {
+            Message::Publish { channel, event_id, payload } => Some((channel, event_id, payload)),
+            Message::Broadcast { .. } => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn in_process_node_ignores_its_own_frames() {
+        let backplane: Arc<dyn Backplane> = Arc::new(InProcess::new());
+        let (a, b) = (node("a", backplane.clone()), node("b", backplane));
+        let (mut inbox_a, mut inbox_b) = (a.inbox(), b.inbox());
+
+        a.publish("orders", 7, "{}");
+        assert_eq!(published(next(&mut inbox_b, Duration::from_secs(1)).await), Some(("orders".into(), 7, "{}".into())));
+        b.broadcast("hello");
+        match next(&mut inbox_a, Duration::from_secs(1)).await {
+            Some(Message::Broadcast { payload }) => assert_eq!(payload, "hello"),
+            other => panic!("expected b's broadcast, got {:?}", other),
+        }
+        assert!(next(&mut inbox_a, Duration::from_millis(100)).await.is_none());
+        assert!(next(&mut inbox_b, Duration::from_millis(100)).await.is_none());
+    }
+
+    /// Needs a Redis server, at `NWEBHOOK_TEST_REDIS_URL` or the default port.
+    #[tokio::test]
+    #[ignore = "needs a Redis server"]
+    async fn redis_nodes_exchange_frames() {
+        let url = std::env::var("NWEBHOOK_TEST_REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379/".to_string());
+        let client = redis::Client::open(url).unwrap();
+        let channel = format!("nwebhook-test-{}", std::process::id());
+        let a = node("a", Arc::new(Redis::start(client.clone(), channel.clone())));
+        let b = node("b", Arc::new(Redis::start(client, channel)));
+        let (mut inbox_a, mut inbox_b) = (a.inbox(), b.inbox());
+
+        // Frames sent before the subscriptions are in place are lost, so
+        // keep publishing until one arrives.
+        let mut received = None;
+        for event_id in 1..=50 {
+            a.publish("orders", event_id, "{}");
+            received = published(next(&mut inbox_b, Duration::from_millis(100)).await);
+            if received.is_some() {
+                break;
+            }
+        }
+        let (channel, event_id, payload) = received.expect("b receives a's events");
+        assert_eq!((channel.as_str(), payload.as_str()), ("orders", "{}"));
+
+        b.publish("orders", event_id + 1, "{}");
+        assert_eq!(published(next(&mut inbox_a, Duration::from_secs(2)).await).map(|(_, id, _)| id), Some(event_id + 1));
+        // Neither node sees its own frames, which Redis echoes to both.
+        while let Some(message) = next(&mut inbox_b, Duration::from_millis(300)).await {
+            assert!(published(Some(message)).is_some_and(|(_, id, _)| id <= event_id));
+        }
+        assert!(next(&mut inbox_a, Duration::from_millis(300)).await.is_none());
+    }
+}
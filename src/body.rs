@@ -0,0 +1,38 @@
+use futures_util::{Stream, StreamExt};
+use warp::hyper::body::{Buf, Bytes};
+use warp::Filter;
+
+/// Rejection for request bodies over the configured `limits.max_body_bytes`.
+#[derive(Debug)]
+pub struct PayloadTooLarge;
+
+impl warp::reject::Reject for PayloadTooLarge {}
+
+/// Collects the request body, rejecting with [`PayloadTooLarge`] as soon as it
+/// exceeds `limit` bytes. Unlike `warp::body::content_length_limit` this also
+/// bounds chunked requests that carry no `Content-Length`.
+pub fn limited(limit: u64) -> impl Filter<Extract = (Bytes,), Error = warp::Rejection> + Clone {
+    warp::body::stream().and_then(move |stream| read_limited(stream, limit))
+}
+
+async fn read_limited<S, B>(stream: S, limit: u64) -> Result<Bytes, warp::Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    let mut stream = Box::pin(stream);
+    let mut body = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let mut chunk = chunk.map_err(|_| warp::reject::reject())?;
+        if body.len() as u64 + chunk.remaining() as u64 > limit {
+            return Err(warp::reject::custom(PayloadTooLarge));
+        }
+        while chunk.has_remaining() {
+            let bytes = chunk.chunk();
+            body.extend_from_slice(bytes);
+            let len = bytes.len();
+            chunk.advance(len);
+        }
+    }
+    Ok(Bytes::from(body))
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+use warp::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use warp::http::{HeaderMap, StatusCode};
+use warp::Reply;
+use log::{info, warn};
+use crate::protocol::PROTOCOL_VERSION;
+use crate::server::{Delivery, WebSocketServer};
+
+/// Headers a responder may not set, since hyper manages them for the
+/// connection the webhook arrived on.
+const RESERVED_HEADERS: &[&str] =
+    &["connection", "content-length", "keep-alive", "proxy-connection", "te", "trailer", "transfer-encoding", "upgrade"];
+
+/// The HTTP response a responder chose for a bridged webhook.
+#[derive(Debug)]
+pub struct BridgeResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl BridgeResponse {
+    /// Builds the response from a `response` message. The status must be a
+    /// final one, from 200 to 599. A string body is sent as is; any other
+    /// JSON value is serialized, as `application/json` unless the responder
+    /// set a content type.
+    pub fn parse(status: Option<u16>, headers: HashMap<String, String>, body: Option<Value>) -> Result<Self, String> {
+        let status = match status {
+            Some(status @ 200..=599) => StatusCode::from_u16(status).map_err(|_| format!("invalid status {}", status))?,
+            Some(status) => return Err(format!("invalid status {}", status)),
+            None => StatusCode::OK,
+        };
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name {:?}", name))?;
+            if RESERVED_HEADERS.contains(&name.as_str()) {
+                return Err(format!("header {} cannot be set", name));
+            }
+            let value = HeaderValue::from_str(&value).map_err(|_| format!("invalid value for header {}", name))?;
+            map.append(name, value);
+        }
+        let body = match body {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::String(text)) => text.into_bytes(),
+            Some(value) => {
+                if !map.contains_key(CONTENT_TYPE) {
+                    map.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                }
+                value.to_string().into_bytes()
+            }
+        };
+        Ok(Self { status, headers: map, body })
+    }
+
+    fn into_response(self) -> warp::reply::Response {
+        let mut response = warp::reply::Response::new(self.body.into());
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+struct Pending {
+    user_id: usize,
+    tx: oneshot::Sender<BridgeResponse>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    /// Channel -> the client that answers its webhooks.
+    responders: HashMap<String, usize>,
+    /// Requests waiting for an answer, by request id.
+    pending: HashMap<u64, Pending>,
+}
+
+/// Lets one client per channel answer that channel's webhooks: each webhook
+/// is sent to it as a `request` and the HTTP response waits for the client's
+/// matching `response` message. Responders and pending requests are known
+/// only to this node.
+#[derive(Clone)]
+pub struct Bridge {
+    timeout: Duration,
+    /// False when nodes share a backplane, since a webhook arriving at
+    /// another node than the responder's would never reach it.
+    enabled: bool,
+    state: Arc<Mutex<State>>,
+}
+
+impl Bridge {
+    pub fn new(timeout: Duration, enabled: bool) -> Self {
+        Self { timeout, enabled, state: Arc::new(Mutex::new(State::default())) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Makes `user_id` the responder for `channel`, replacing any previous one.
+    pub fn designate(&self, channel: &str, user_id: usize) {
+        let previous = self.state.lock().unwrap().responders.insert(channel.to_string(), user_id);
+        match previous {
+            Some(previous) if previous != user_id => {
+                info!("User {} replaced user {} as responder for channel {}", user_id, previous, channel)
+            }
+            _ => info!("User {} is the responder for channel {}", user_id, channel),
+        }
+    }
+
+    pub fn responder(&self, channel: &str) -> Option<usize> {
+        self.state.lock().unwrap().responders.get(channel).copied()
+    }
+
+    /// Forgets a user that disconnected; webhooks waiting on it fail at once.
+    pub fn remove_user(&self, user_id: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.responders.retain(|_, responder| *responder != user_id);
+        state.pending.retain(|_, pending| pending.user_id != user_id);
+    }
+
+    /// Hands the response for `request_id` to the waiting webhook. Only the
+    /// client the request was sent to may answer it, and only once.
+    pub fn complete(&self, user_id: usize, request_id: u64, response: BridgeResponse) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.pending.get(&request_id).is_none_or(|pending| pending.user_id != user_id) {
+            return false;
+        }
+        if let Some(pending) = state.pending.remove(&request_id) {
+            // Fails only if the webhook has just timed out.
+            let _ = pending.tx.send(response);
+        }
+        true
+    }
+
+    /// Sends a webhook to the channel's responder and waits for its answer,
+    /// which becomes the HTTP response.
+    pub async fn exchange(&self, ws_server: &WebSocketServer, user_id: usize, event: Value) -> warp::reply::Response {
+        let (tx, rx) = oneshot::channel();
+        let request_id = {
+            let mut state = self.state.lock().unwrap();
+            state.next_id += 1;
+            let request_id = state.next_id;
+            state.pending.insert(request_id, Pending { user_id, tx });
+            request_id
+        };
+        let message = json!({ "v": PROTOCOL_VERSION, "type": "request", "request_id": request_id, "event": event });
+        if let Err(e) = ws_server.try_send_to(user_id, Delivery::text(None, message.to_string())).await {
+            warn!("Failed to send request {} to responder {}: {}", request_id, user_id, e);
+            self.state.lock().unwrap().pending.remove(&request_id);
+            return warp::reply::with_status("Responder unavailable", StatusCode::BAD_GATEWAY).into_response();
+        }
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(response)) => response.into_response(),
+            Ok(Err(_)) => warp::reply::with_status("Responder disconnected", StatusCode::BAD_GATEWAY).into_response(),
+            Err(_) => {
+                warn!("Responder {} did not answer request {} within {:?}", user_id, request_id, self.timeout);
+                self.state.lock().unwrap().pending.remove(&request_id);
+                warp::reply::with_status("Responder did not answer in time", StatusCode::GATEWAY_TIMEOUT).into_response()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::QueueReceiver;
+    use crate::subscription::Subscription;
+
+    fn parse(status: Option<u16>, headers: &[(&str, &str)], body: Option<Value>) -> Result<BridgeResponse, String> {
+        let headers = headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        BridgeResponse::parse(status, headers, body)
+    }
+
+    #[test]
+    fn accepts_final_statuses_only() {
+        assert_eq!(parse(None, &[], None).unwrap().status, StatusCode::OK);
+        for status in [200, 204, 302, 404, 599] {
+            assert_eq!(parse(Some(status), &[], None).unwrap().status.as_u16(), status);
+        }
+        for status in [0, 100, 101, 199, 600, 999, 1000] {
+            assert_eq!(parse(Some(status), &[], None).unwrap_err(), format!("invalid status {}", status));
+        }
+    }
+
+    #[test]
+    fn refuses_reserved_and_invalid_headers() {
+        for name in RESERVED_HEADERS {
+            assert_eq!(parse(None, &[(name, "x")], None).unwrap_err(), format!("header {} cannot be set", name));
+        }
+        let mixed_case = parse(None, &[("Transfer-Encoding", "chunked")], None);
+        assert_eq!(mixed_case.unwrap_err(), "header transfer-encoding cannot be set");
+        assert!(parse(None, &[("bad header", "x")], None).unwrap_err().starts_with("invalid header name"));
+        assert!(parse(None, &[("x-ok", "line\nbreak")], None).unwrap_err().starts_with("invalid value"));
+        let response = parse(None, &[("X-Custom", "1")], None).unwrap();
+        assert_eq!(response.headers["x-custom"], "1");
+    }
+
+    #[test]
+    fn json_bodies_default_to_json_content_type() {
+        let response = parse(None, &[], Some(json!({"ok": true}))).unwrap();
+        assert_eq!(response.headers[CONTENT_TYPE], "application/json");
+        assert_eq!(response.body, br#"{"ok":true}"#);
+        let response = parse(None, &[("content-type", "application/vnd.api+json")], Some(json!([1]))).unwrap();
+        assert_eq!(response.headers[CONTENT_TYPE], "application/vnd.api+json");
+        // Strings are sent as they are, and nulls as nothing.
+        let response = parse(None, &[], Some(json!("plain"))).unwrap();
+        assert_eq!((response.headers.get(CONTENT_TYPE), response.body), (None, b"plain".to_vec()));
+        let response = parse(None, &[], Some(Value::Null)).unwrap();
+        assert!(response.body.is_empty() && response.headers.is_empty());
+    }
+
+    fn orders() -> Subscription {
+        Subscription {
+            channel: "orders".to_string(),
+            remote_addr: "127.0.0.1:1".parse().unwrap(),
+            principal: None,
+            token_source: None,
+            replay: Default::default(),
+            publish: crate::auth::PublishGrant::All,
+        }
+    }
+
+    /// A server whose bridge gives up after `timeout`, and a responder
+    /// registered on channel `orders`.
+    async fn responder(timeout: Duration) -> (WebSocketServer, usize, QueueReceiver) {
+        let mut ws_server = WebSocketServer::for_tests();
+        ws_server.bridge = Bridge::new(timeout, true);
+        let subscription = orders();
+        let (user_id, rx) = ws_server.register(&subscription).await;
+        ws_server.bridge.designate("orders", user_id);
+        (ws_server, user_id, rx)
+    }
+
+    /// The id of the next request sent to the responder.
+    async fn request_id(rx: &mut QueueReceiver) -> u64 {
+        let delivery = rx.recv().await.unwrap();
+        let request: Value = serde_json::from_str(&delivery.message.to_string()).unwrap();
+        assert_eq!((request["v"].as_u64(), request["type"].as_str()), (Some(PROTOCOL_VERSION), Some("request")));
+        request["request_id"].as_u64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn responses_reach_the_waiting_webhook() {
+        let (ws_server, user_id, mut rx) = responder(Duration::from_secs(5)).await;
+        assert_eq!(ws_server.bridge.responder("orders"), Some(user_id));
+        let exchange = tokio::spawn({
+            let ws_server = ws_server.clone();
+            async move { ws_server.bridge.exchange(&ws_server, user_id, json!({"n": 1})).await }
+        });
+        let request_id = request_id(&mut rx).await;
+        let answer = || parse(Some(201), &[("x-answer", "yes")], Some(json!("done"))).unwrap();
+        // Only the client the request went to may answer it, and only once.
+        assert!(!ws_server.bridge.complete(user_id + 1, request_id, answer()));
+        assert!(!ws_server.bridge.complete(user_id, request_id + 1, answer()));
+        assert!(ws_server.bridge.complete(user_id, request_id, answer()));
+        assert!(!ws_server.bridge.complete(user_id, request_id, answer()));
+
+        let response = exchange.await.unwrap();
+        assert_eq!((response.status(), response.headers()["x-answer"].to_str().unwrap()), (StatusCode::CREATED, "yes"));
+        assert_eq!(&warp::hyper::body::to_bytes(response.into_body()).await.unwrap()[..], b"done");
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_are_told_apart() {
+        let (ws_server, user_id, mut rx) = responder(Duration::from_secs(5)).await;
+        let exchanges: Vec<_> = (0..2)
+            .map(|n| {
+                let ws_server = ws_server.clone();
+                tokio::spawn(async move { ws_server.bridge.exchange(&ws_server, user_id, json!({"n": n})).await })
+            })
+            .collect();
+        let (first, second) = (request_id(&mut rx).await, request_id(&mut rx).await);
+        assert_ne!(first, second);
+        // Answered out of order, each with its own status.
+        assert!(ws_server.bridge.complete(user_id, second, parse(Some(202), &[], None).unwrap()));
+        assert!(ws_server.bridge.complete(user_id, first, parse(Some(203), &[], None).unwrap()));
+        let mut statuses = Vec::new();
+        for exchange in exchanges {
+            statuses.push(exchange.await.unwrap().status().as_u16());
+        }
+        statuses.sort();
+        assert_eq!(statuses, [202, 203]);
+    }
+
+    #[tokio::test]
+    async fn unanswered_requests_time_out() {
+        let (ws_server, user_id, mut rx) = responder(Duration::from_millis(50)).await;
+        let response = ws_server.bridge.exchange(&ws_server, user_id, json!({})).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        // A late answer finds nothing waiting.
+        let request_id = request_id(&mut rx).await;
+        assert!(!ws_server.bridge.complete(user_id, request_id, parse(None, &[], None).unwrap()));
+        assert!(ws_server.bridge.state.lock().unwrap().pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn disconnected_responders_fail_waiting_webhooks() {
+        let (ws_server, user_id, mut rx) = responder(Duration::from_secs(5)).await;
+        let exchange = tokio::spawn({
+            let ws_server = ws_server.clone();
+            async move { ws_server.bridge.exchange(&ws_server, user_id, json!({})).await }
+        });
+        request_id(&mut rx).await;
+        ws_server.bridge.remove_user(user_id);
+        assert_eq!(exchange.await.unwrap().status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(ws_server.bridge.responder("orders"), None);
+        // A responder that is gone before the request is sent fails at once.
+        let response = ws_server.bridge.exchange(&ws_server, user_id + 1, json!({})).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert!(ws_server.bridge.state.lock().unwrap().pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn respond_is_refused_while_disabled() {
+        let mut ws_server = WebSocketServer::for_tests();
+        let subscription = orders();
+        let (user_id, _rx) = ws_server.register(&subscription).await;
+        let respond = |ws_server: WebSocketServer| async move {
+            let reply = crate::protocol::handle(&ws_server, user_id, &orders(), r#"{"type": "respond"}"#).await;
+            serde_json::to_value(reply).unwrap()
+        };
+        assert_eq!(respond(ws_server.clone()).await["type"], "responding");
+
+        ws_server.bridge = Bridge::new(Duration::from_secs(1), false);
+        let reply = respond(ws_server.clone()).await;
+        assert_eq!((reply["type"].as_str(), reply["code"].as_str()), (Some("error"), Some("unsupported")));
+        assert_eq!(ws_server.bridge.responder("orders"), None);
+    }
+}
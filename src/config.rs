@@ -0,0 +1,1088 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::Parser;
+use serde::Deserialize;
+use crate::auth::{Auth, Principal};
+use crate::backplane::{BackplaneKind, BackplaneSettings};
+use crate::bridge::Bridge;
+use crate::endpoints::EndpointSettings;
+use crate::envelope::EnvelopeSettings;
+use crate::eventlog::{EventLogConfig, FsyncPolicy};
+use crate::forward::ForwardTarget;
+use crate::queue::{OverflowPolicy, QueueLimit, QueueSettings};
+use crate::ratelimit::{Rate, RateLimitSettings};
+use crate::server::{self, Heartbeat, ALL_CHANNELS};
+use crate::signature::{Provider, Verifier, Verifiers};
+use crate::tls::TlsSettings;
+
+const ENV_PREFIX: &str = "NWEBHOOK_";
+
+/// First segments of the routes that are not configurable, which the
+/// configurable ones must stay clear of.
+const FIXED_ROUTES: &[&str] = &["admin", "api", "metrics", "healthz", "readyz"];
+
+/// Command line flags. Every flag overrides the matching config file key and
+/// `NWEBHOOK_*` environment variable.
+#[derive(Debug, Parser)]
+#[command(version, about = "Relay incoming webhooks to WebSocket and SSE subscribers")]
+pub struct Cli {
+    /// TOML config file to load.
+    #[arg(short, long, env = "NWEBHOOK_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on; repeat to listen on several.
+    #[arg(long = "listen", value_name = "ADDR")]
+    pub listen: Vec<SocketAddr>,
+    /// Directory of static files to serve.
+    #[arg(long, value_name = "DIR")]
+    pub static_dir: Option<PathBuf>,
+    /// Do not serve static files.
+    #[arg(long, conflicts_with = "static_dir")]
+    pub no_static: bool,
+    /// Do not serve the request inspector at `/`.
+    #[arg(long)]
+    pub no_ui: bool,
+    /// Path of the WebSocket endpoint.
+    #[arg(long, value_name = "PATH")]
+    pub ws_path: Option<String>,
+    /// Path webhooks are posted to.
+    #[arg(long, value_name = "PATH")]
+    pub webhook_path: Option<String>,
+    /// Path of the Server-Sent Events endpoint.
+    #[arg(long, value_name = "PATH")]
+    pub events_path: Option<String>,
+    /// Path of the endpoints created through `/api/endpoints`.
+    #[arg(long, value_name = "PATH")]
+    pub hook_path: Option<String>,
+    /// Allowed CORS origin, or `*`; repeat for several.
+    #[arg(long = "cors-origin", value_name = "ORIGIN")]
+    pub cors_origins: Vec<String>,
+    /// Largest accepted webhook body.
+    #[arg(long, value_name = "BYTES")]
+    pub max_body_bytes: Option<u64>,
+    /// Events kept in memory per channel for replay.
+    #[arg(long, value_name = "EVENTS")]
+    pub replay_capacity: Option<usize>,
+    /// Directory for the durable event log.
+    #[arg(long, value_name = "DIR")]
+    pub event_log_dir: Option<PathBuf>,
+    /// PEM certificate chain; enables HTTPS/WSS.
+    #[arg(long, value_name = "FILE")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for `--tls-cert`.
+    #[arg(long, value_name = "FILE")]
+    pub tls_key: Option<PathBuf>,
+    /// PEM CA bundle; webhooks then require a client certificate signed by it.
+    #[arg(long, value_name = "FILE")]
+    pub tls_client_ca: Option<PathBuf>,
+    /// Validate the configuration and exit.
+    #[arg(long)]
+    pub check: bool,
+}
+
+/// A configuration problem, reported against the config key (or environment
+/// variable) that caused it.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(key: impl Into<String>, message: impl Into<String>) -> Self {
+        Self { key: key.into(), message: message.into() }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    /// Directory of static files, served alongside the inspector UI;
+    /// `None` disables static files.
+    pub static_dir: Option<PathBuf>,
+    pub paths: Paths,
+    pub cors: Cors,
+    pub limits: Limits,
+    pub signatures: Signatures,
+    /// WebSocket/SSE principals by name. Empty disables authentication.
+    pub tokens: BTreeMap<String, TokenConfig>,
+    pub event_log: EventLogSection,
+    pub tls: TlsSection,
+    /// Downstream HTTP targets every accepted webhook is relayed to, by name.
+    pub forward: BTreeMap<String, ForwardConfig>,
+    pub envelope: EnvelopeSection,
+    pub websocket: WebSocketSection,
+    pub queues: QueuesSection,
+    pub shutdown: ShutdownSection,
+    pub ui: UiSection,
+    pub admin: AdminSection,
+    pub endpoints: EndpointsSection,
+    pub bridge: BridgeSection,
+    pub backplane: BackplaneSection,
+    pub rate_limits: RateLimitsSection,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 3030))],
+            static_dir: None,
+            paths: Paths::default(),
+            cors: Cors::default(),
+            limits: Limits::default(),
+            signatures: Signatures::default(),
+            tokens: BTreeMap::new(),
+            event_log: EventLogSection::default(),
+            tls: TlsSection::default(),
+            forward: BTreeMap::new(),
+            envelope: EnvelopeSection::default(),
+            websocket: WebSocketSection::default(),
+            queues: QueuesSection::default(),
+            shutdown: ShutdownSection::default(),
+            ui: UiSection::default(),
+            admin: AdminSection::default(),
+            endpoints: EndpointsSection::default(),
+            bridge: BridgeSection::default(),
+            backplane: BackplaneSection::default(),
+            rate_limits: RateLimitsSection::default(),
+        }
+    }
+}
+
+/// Route prefixes, each one or more `/`-separated segments.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Paths {
+    pub ws: String,
+    pub webhook: String,
+    pub events: String,
+    /// Endpoints created at runtime live at `<hook>/<token>`.
+    pub hook: String,
+}
+
+impl Default for Paths {
+    fn default() -> Self {
+        Self {
+            ws: "ws".to_string(),
+            webhook: "webhook".to_string(),
+            events: "events".to_string(),
+            hook: "hook".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cors {
+    /// Allowed origins such as `https://example.com`, or `*` for any.
+    pub origins: Vec<String>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self { origins: vec!["*".to_string()] }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_body_bytes: u64,
+    /// Events kept in memory per channel for replay.
+    pub replay_capacity: usize,
+    /// Channels with events kept in memory; beyond it, the channel published
+    /// to least recently is dropped.
+    pub replay_channels: usize,
+    /// Undeliverable messages kept for inspection and redelivery; 0 discards them.
+    pub dead_letter_capacity: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self { max_body_bytes: 1024 * 1024, replay_capacity: 100, replay_channels: 1000, dead_letter_capacity: 1000 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Signatures {
+    /// Maximum clock skew accepted on Stripe and Slack signature timestamps.
+    pub tolerance_secs: u64,
+    /// Verifier per channel. Channels without one accept unsigned requests.
+    pub channels: BTreeMap<String, SignatureConfig>,
+}
+
+impl Default for Signatures {
+    fn default() -> Self {
+        Self { tolerance_secs: 300, channels: BTreeMap::new() }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignatureConfig {
+    /// `github`, `stripe`, `slack` or `hmac`.
+    pub provider: String,
+    pub secret: String,
+    /// Header carrying the signature, for the `hmac` provider only.
+    pub header: Option<String>,
+}
+
+impl SignatureConfig {
+    /// Parses the compact environment form: `github:<secret>`,
+    /// `stripe:<secret>`, `slack:<secret>` or `hmac:<header>:<secret>`.
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        let (provider, rest) = spec.split_once(':').ok_or("expected <provider>:<secret>")?;
+        let (header, secret) = if provider == "hmac" {
+            let (header, secret) = rest.split_once(':').ok_or("expected hmac:<header>:<secret>")?;
+            (Some(header.to_string()), secret)
+        } else {
+            (None, rest)
+        };
+        Ok(Self { provider: provider.to_string(), secret: secret.to_string(), header })
+    }
+
+    /// Builds the verifier, or names the offending field and why.
+    pub fn verifier(&self) -> Result<Verifier, (&'static str, String)> {
+        let provider = match (self.provider.as_str(), &self.header) {
+            ("github", None) => Provider::GitHub,
+            ("stripe", None) => Provider::Stripe,
+            ("slack", None) => Provider::Slack,
+            ("hmac", Some(header)) if !header.is_empty() => Provider::Generic { header: header.clone() },
+            ("hmac", _) => return Err(("header", "required for the hmac provider".to_string())),
+            ("github" | "stripe" | "slack", Some(_)) => return Err(("header", "only allowed for the hmac provider".to_string())),
+            (other, _) => return Err(("provider", format!("unknown provider {:?}", other))),
+        };
+        if self.secret.is_empty() {
+            return Err(("secret", "must not be empty".to_string()));
+        }
+        Ok(Verifier::new(provider, self.secret.as_bytes()))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    pub token: String,
+    /// Channels the token may subscribe to; `*` grants all of them.
+    pub channels: Vec<String>,
+    /// Channels the token may publish to from a WebSocket; `*` grants all.
+    #[serde(default)]
+    pub publish: Vec<String>,
+}
+
+impl TokenConfig {
+    /// Parses the compact environment form
+    /// `<token>:<channel>[,<channel>...][:<publish channel>[,...]]`.
+    fn from_spec(spec: &str) -> Result<Self, String> {
+        let (token, channels) = spec.split_once(':').ok_or("expected <token>:<channels>")?;
+        let (channels, publish) = channels.split_once(':').unwrap_or((channels, ""));
+        let list = |channels: &str| channels.split(',').map(str::trim).filter(|c| !c.is_empty()).map(String::from).collect();
+        Ok(Self { token: token.to_string(), channels: list(channels), publish: list(publish) })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventLogSection {
+    /// Directory for the durable event log; unset disables it.
+    pub dir: Option<PathBuf>,
+    /// `always`, `never` or `interval:<ms>`.
+    pub fsync: String,
+    pub segment_bytes: u64,
+    /// Oldest segments beyond this count are deleted; 0 keeps everything.
+    pub max_segments: usize,
+}
+
+impl Default for EventLogSection {
+    fn default() -> Self {
+        Self { dir: None, fsync: "always".to_string(), segment_bytes: 16 * 1024 * 1024, max_segments: 8 }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    /// PEM certificate chain; setting it serves HTTPS/WSS on every listener.
+    /// Send SIGHUP to reload it and `key` without dropping connections.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// PEM CA bundle for client certificates. Webhook requests must then
+    /// present a certificate it signed; other endpoints do not require one.
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardConfig {
+    pub url: String,
+    /// Channels relayed to this target; empty relays every channel.
+    pub channels: Vec<String>,
+    pub timeout_ms: u64,
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Also relay the sender's credential headers, such as `authorization`.
+    pub credentials: bool,
+}
+
+impl Default for ForwardConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            channels: Vec::new(),
+            timeout_ms: 10_000,
+            max_attempts: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            credentials: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnvelopeSection {
+    /// Wrap webhook bodies in an envelope with the request metadata; `false`
+    /// sends subscribers the bare body, as before envelopes existed.
+    pub enabled: bool,
+    /// Headers copied into the envelope; empty copies all but credentials.
+    pub headers: Vec<String>,
+}
+
+impl Default for EnvelopeSection {
+    fn default() -> Self {
+        Self { enabled: true, headers: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketSection {
+    /// Lets any client publish when no tokens are configured. With tokens,
+    /// only those with a `publish` grant may.
+    pub anonymous_publish: bool,
+    /// Time between heartbeat pings; 0 disables heartbeats.
+    pub ping_interval_ms: u64,
+    /// Consecutive unanswered pings after which a client is disconnected.
+    pub max_missed_pongs: u32,
+}
+
+impl Default for WebSocketSection {
+    fn default() -> Self {
+        Self { anonymous_publish: false, ping_interval_ms: 30_000, max_missed_pongs: 3 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueuesSection {
+    /// Messages queued per client before `policy` applies.
+    pub capacity: usize,
+    /// `drop_oldest`, `drop_newest`, `disconnect` or `block`.
+    pub policy: String,
+    /// Overrides for subscribers of particular channels.
+    pub channels: BTreeMap<String, QueueConfig>,
+}
+
+impl Default for QueuesSection {
+    fn default() -> Self {
+        Self { capacity: 1024, policy: "drop_oldest".to_string(), channels: BTreeMap::new() }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiSection {
+    /// Serve the embedded request inspector at `/`.
+    pub enabled: bool,
+}
+
+impl Default for UiSection {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSection {
+    /// Opens the admin API, `/api/endpoints` and `/metrics` to anyone when no
+    /// tokens are configured; otherwise they are refused until there are.
+    pub anonymous: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndpointsSection {
+    /// Endpoints that may exist at once; 0 disables creating them.
+    pub capacity: usize,
+    /// Longest lifetime an endpoint may ask for, and the lifetime of those
+    /// that do not ask; 0 lets them live until deleted.
+    pub max_ttl_secs: u64,
+}
+
+impl Default for EndpointsSection {
+    fn default() -> Self {
+        Self { capacity: 1000, max_ttl_secs: 0 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackplaneSection {
+    /// `in_process` for a single instance, or `redis` to share events with
+    /// every instance connected to the same Redis channel.
+    pub kind: String,
+    pub url: String,
+    pub channel: String,
+    /// Identifies this instance to the others; empty picks a random id.
+    pub node_id: String,
+}
+
+impl Default for BackplaneSection {
+    fn default() -> Self {
+        Self {
+            kind: "in_process".to_string(),
+            url: "redis://127.0.0.1:6379/".to_string(),
+            channel: "nwebhook".to_string(),
+            node_id: String::new(),
+        }
+    }
+}
+
+/// Token buckets; an unset limit does not apply.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitsSection {
+    pub webhook_ip: Option<RateConfig>,
+    /// Keyed by the channel the webhook is published on.
+    pub webhook_endpoint: Option<RateConfig>,
+    /// Keyed by the principal whose token the webhook carries.
+    pub webhook_key: Option<RateConfig>,
+    pub ws_upgrade_ip: Option<RateConfig>,
+    /// Open WebSocket connections per source IP; 0 for no limit.
+    pub ws_connections_per_ip: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateConfig {
+    /// Tokens added per second.
+    pub per_second: f64,
+    /// Tokens a bucket holds, and so requests accepted at once.
+    pub burst: u32,
+}
+
+impl RateConfig {
+    /// Parses the compact environment form `<per_second>:<burst>`; an
+    /// empty value removes the limit.
+    fn from_spec(spec: &str) -> Result<Option<Self>, String> {
+        if spec.is_empty() {
+            return Ok(None);
+        }
+        let (per_second, burst) = spec.split_once(':').ok_or("expected <per_second>:<burst>")?;
+        let per_second = per_second.trim().parse().map_err(|_| format!("invalid rate {:?}", per_second))?;
+        let burst = burst.trim().parse().map_err(|_| format!("invalid burst {:?}", burst))?;
+        Ok(Some(Self { per_second, burst }))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BridgeSection {
+    /// How long a webhook waits for its channel's responder to answer.
+    pub timeout_ms: u64,
+}
+
+impl Default for BridgeSection {
+    fn default() -> Self {
+        Self { timeout_ms: 10_000 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownSection {
+    /// How long `/readyz` reports 503 before the listeners close, for load
+    /// balancers to stop sending new connections.
+    pub pre_stop_delay_ms: u64,
+    /// How long SIGTERM/SIGINT waits for in-flight webhooks and for clients
+    /// to acknowledge close frames before exiting anyway.
+    pub drain_timeout_ms: u64,
+}
+
+impl Default for ShutdownSection {
+    fn default() -> Self {
+        Self { pre_stop_delay_ms: 5_000, drain_timeout_ms: 30_000 }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    pub capacity: Option<usize>,
+    pub policy: Option<String>,
+}
+
+impl Config {
+    /// Builds the configuration from defaults, the config file, `NWEBHOOK_*`
+    /// environment variables and command line flags, in increasing order of
+    /// precedence, and validates the result.
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let key = path.display().to_string();
+                let text = std::fs::read_to_string(path).map_err(|e| ConfigError::new(&key, e.to_string()))?;
+                toml::from_str(&text).map_err(|e| ConfigError::new(&key, e.to_string()))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env(std::env::vars())?;
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self, vars: impl Iterator<Item = (String, String)>) -> Result<(), ConfigError> {
+        for (key, value) in vars {
+            let Some(name) = key.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let invalid = |message: &str| ConfigError::new(&key, message);
+            match name {
+                "CONFIG" => {}
+                "LISTEN" => {
+                    self.listen = value
+                        .split(',')
+                        .map(|addr| addr.trim().parse())
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| invalid("expected comma-separated host:port addresses"))?;
+                }
+                "STATIC_DIR" if value.is_empty() => self.static_dir = None,
+                "STATIC_DIR" => self.static_dir = Some(PathBuf::from(value)),
+                "WS_PATH" => self.paths.ws = value,
+                "WEBHOOK_PATH" => self.paths.webhook = value,
+                "EVENTS_PATH" => self.paths.events = value,
+                "HOOK_PATH" => self.paths.hook = value,
+                "CORS_ORIGINS" => self.cors.origins = value.split(',').map(|o| o.trim().to_string()).collect(),
+                "MAX_BODY_BYTES" => self.limits.max_body_bytes = value.parse().map_err(|_| invalid("expected a number of bytes"))?,
+                "REPLAY_CAPACITY" => self.limits.replay_capacity = value.parse().map_err(|_| invalid("expected a number of events"))?,
+                "REPLAY_CHANNELS" => self.limits.replay_channels = value.parse().map_err(|_| invalid("expected a number of channels"))?,
+                "DEAD_LETTER_CAPACITY" => {
+                    self.limits.dead_letter_capacity = value.parse().map_err(|_| invalid("expected a number of messages"))?
+                }
+                "SIGNATURE_TOLERANCE" => {
+                    self.signatures.tolerance_secs = value.parse().map_err(|_| invalid("expected a number of seconds"))?
+                }
+                "EVENT_LOG_DIR" => self.event_log.dir = Some(PathBuf::from(value)),
+                "EVENT_LOG_FSYNC" => self.event_log.fsync = value,
+                "EVENT_LOG_SEGMENT_BYTES" => {
+                    self.event_log.segment_bytes = value.parse().map_err(|_| invalid("expected a number of bytes"))?
+                }
+                "EVENT_LOG_MAX_SEGMENTS" => {
+                    self.event_log.max_segments = value.parse().map_err(|_| invalid("expected a number of segments"))?
+                }
+                "TLS_CERT" => self.tls.cert = Some(PathBuf::from(value)),
+                "TLS_KEY" => self.tls.key = Some(PathBuf::from(value)),
+                "TLS_CLIENT_CA" => self.tls.client_ca = Some(PathBuf::from(value)),
+                "WS_ANONYMOUS_PUBLISH" => {
+                    self.websocket.anonymous_publish = value.parse().map_err(|_| invalid("expected true or false"))?
+                }
+                "WS_PING_INTERVAL_MS" => {
+                    self.websocket.ping_interval_ms = value.parse().map_err(|_| invalid("expected a number of milliseconds"))?
+                }
+                "WS_MAX_MISSED_PONGS" => {
+                    self.websocket.max_missed_pongs = value.parse().map_err(|_| invalid("expected a number of pings"))?
+                }
+                "QUEUE_CAPACITY" => self.queues.capacity = value.parse().map_err(|_| invalid("expected a number of messages"))?,
+                "QUEUE_POLICY" => self.queues.policy = value,
+                "ENDPOINT_CAPACITY" => {
+                    self.endpoints.capacity = value.parse().map_err(|_| invalid("expected a number of endpoints"))?
+                }
+                "ENDPOINT_MAX_TTL_SECS" => {
+                    self.endpoints.max_ttl_secs = value.parse().map_err(|_| invalid("expected a number of seconds"))?
+                }
+                "BACKPLANE" => self.backplane.kind = value,
+                "BACKPLANE_URL" => self.backplane.url = value,
+                "BACKPLANE_CHANNEL" => self.backplane.channel = value,
+                "NODE_ID" => self.backplane.node_id = value,
+                "RATE_LIMIT_WEBHOOK_IP" => self.rate_limits.webhook_ip = RateConfig::from_spec(&value).map_err(|e| invalid(&e))?,
+                "RATE_LIMIT_WEBHOOK_ENDPOINT" => {
+                    self.rate_limits.webhook_endpoint = RateConfig::from_spec(&value).map_err(|e| invalid(&e))?
+                }
+                "RATE_LIMIT_WEBHOOK_KEY" => self.rate_limits.webhook_key = RateConfig::from_spec(&value).map_err(|e| invalid(&e))?,
+                "RATE_LIMIT_WS_UPGRADE_IP" => {
+                    self.rate_limits.ws_upgrade_ip = RateConfig::from_spec(&value).map_err(|e| invalid(&e))?
+                }
+                "RATE_LIMIT_WS_CONNECTIONS_PER_IP" => {
+                    self.rate_limits.ws_connections_per_ip = value.parse().map_err(|_| invalid("expected a number of connections"))?
+                }
+                "BRIDGE_TIMEOUT_MS" => {
+                    self.bridge.timeout_ms = value.parse().map_err(|_| invalid("expected a number of milliseconds"))?
+                }
+                "UI" => self.ui.enabled = value.parse().map_err(|_| invalid("expected true or false"))?,
+                "ADMIN_ANONYMOUS" => self.admin.anonymous = value.parse().map_err(|_| invalid("expected true or false"))?,
+                "PRE_STOP_DELAY_MS" => {
+                    self.shutdown.pre_stop_delay_ms = value.parse().map_err(|_| invalid("expected a number of milliseconds"))?
+                }
+                "DRAIN_TIMEOUT_MS" => {
+                    self.shutdown.drain_timeout_ms = value.parse().map_err(|_| invalid("expected a number of milliseconds"))?
+                }
+                "ENVELOPE" => self.envelope.enabled = value.parse().map_err(|_| invalid("expected true or false"))?,
+                "ENVELOPE_HEADERS" => {
+                    self.envelope.headers = value.split(',').map(|h| h.trim().to_string()).filter(|h| !h.is_empty()).collect()
+                }
+                _ => {
+                    if let Some(channel) = name.strip_prefix("VERIFY_") {
+                        let signature = SignatureConfig::from_spec(&value).map_err(|e| invalid(&e))?;
+                        self.signatures.channels.insert(channel.to_ascii_lowercase(), signature);
+                    } else if let Some(channel) = name.strip_prefix("QUEUE_CAPACITY_") {
+                        let capacity = value.parse().map_err(|_| invalid("expected a number of messages"))?;
+                        self.queues.channels.entry(channel.to_ascii_lowercase()).or_default().capacity = Some(capacity);
+                    } else if let Some(channel) = name.strip_prefix("QUEUE_POLICY_") {
+                        self.queues.channels.entry(channel.to_ascii_lowercase()).or_default().policy = Some(value);
+                    } else if let Some(target) = name.strip_prefix("FORWARD_") {
+                        let forward = ForwardConfig { url: value, ..ForwardConfig::default() };
+                        self.forward.insert(target.to_ascii_lowercase(), forward);
+                    } else if let Some(principal) = name.strip_prefix("TOKEN_") {
+                        let token = TokenConfig::from_spec(&value).map_err(|e| invalid(&e))?;
+                        self.tokens.insert(principal.to_ascii_lowercase(), token);
+                    } else {
+                        return Err(invalid("unknown setting"));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) {
+        if !cli.listen.is_empty() {
+            self.listen = cli.listen.clone();
+        }
+        if cli.no_static {
+            self.static_dir = None;
+        }
+        if cli.no_ui {
+            self.ui.enabled = false;
+        }
+        if let Some(dir) = &cli.static_dir {
+            self.static_dir = Some(dir.clone());
+        }
+        if let Some(path) = &cli.ws_path {
+            self.paths.ws = path.clone();
+        }
+        if let Some(path) = &cli.webhook_path {
+            self.paths.webhook = path.clone();
+        }
+        if let Some(path) = &cli.events_path {
+            self.paths.events = path.clone();
+        }
+        if let Some(path) = &cli.hook_path {
+            self.paths.hook = path.clone();
+        }
+        if !cli.cors_origins.is_empty() {
+            self.cors.origins = cli.cors_origins.clone();
+        }
+        if let Some(bytes) = cli.max_body_bytes {
+            self.limits.max_body_bytes = bytes;
+        }
+        if let Some(capacity) = cli.replay_capacity {
+            self.limits.replay_capacity = capacity;
+        }
+        if let Some(dir) = &cli.event_log_dir {
+            self.event_log.dir = Some(dir.clone());
+        }
+        if let Some(path) = &cli.tls_cert {
+            self.tls.cert = Some(path.clone());
+        }
+        if let Some(path) = &cli.tls_key {
+            self.tls.key = Some(path.clone());
+        }
+        if let Some(path) = &cli.tls_client_ca {
+            self.tls.client_ca = Some(path.clone());
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.listen.is_empty() {
+            return Err(ConfigError::new("listen", "at least one address is required"));
+        }
+        let paths = [
+            ("paths.ws", &self.paths.ws),
+            ("paths.webhook", &self.paths.webhook),
+            ("paths.events", &self.paths.events),
+            ("paths.hook", &self.paths.hook),
+        ];
+        for (i, (key, path)) in paths.iter().enumerate() {
+            let segments = path_segments(path);
+            if segments.is_empty() || segments.iter().any(|segment| !server::is_valid_channel(segment)) {
+                return Err(ConfigError::new(*key, format!("invalid path {:?}", path)));
+            }
+            if FIXED_ROUTES.contains(&segments[0].as_str()) {
+                return Err(ConfigError::new(*key, format!("{:?} collides with the fixed /{} routes", path, segments[0])));
+            }
+            if let Some((other, _)) = paths[..i].iter().find(|(_, other)| path_segments(other) == path_segments(path)) {
+                return Err(ConfigError::new(*key, format!("same path as {}", other)));
+            }
+        }
+        if self.cors.origins.is_empty() {
+            return Err(ConfigError::new("cors.origins", "at least one origin (or \"*\") is required"));
+        }
+        for origin in &self.cors.origins {
+            if origin != "*" && !is_valid_origin(origin) {
+                return Err(ConfigError::new("cors.origins", format!("invalid origin {:?}", origin)));
+            }
+        }
+        if self.limits.max_body_bytes == 0 {
+            return Err(ConfigError::new("limits.max_body_bytes", "must be greater than 0"));
+        }
+        if self.limits.replay_channels == 0 {
+            return Err(ConfigError::new("limits.replay_channels", "must be greater than 0"));
+        }
+        self.verifiers()?;
+        self.auth()?;
+        self.event_log()?;
+        self.tls()?;
+        self.forward_targets()?;
+        self.envelope()?;
+        self.queues()?;
+        self.heartbeat()?;
+        self.bridge()?;
+        self.backplane()?;
+        // Endpoints live in the memory of the node that created them, so no
+        // other node would accept their webhooks or subscribers.
+        if self.backplane.kind == "redis" && self.endpoints.capacity > 0 {
+            let message = "must be 0 with the redis backplane; endpoints are not shared between nodes";
+            return Err(ConfigError::new("endpoints.capacity", message));
+        }
+        self.rate_limits()?;
+        Ok(())
+    }
+
+    pub fn rate_limits(&self) -> Result<RateLimitSettings, ConfigError> {
+        let rate = |key: &str, rate: &Option<RateConfig>| match rate {
+            Some(rate) if !(rate.per_second.is_finite() && rate.per_second > 0.0) => {
+                Err(ConfigError::new(format!("rate_limits.{}.per_second", key), "must be greater than 0"))
+            }
+            Some(rate) if rate.burst == 0 => Err(ConfigError::new(format!("rate_limits.{}.burst", key), "must be at least 1")),
+            Some(rate) => Ok(Some(Rate { per_second: rate.per_second, burst: rate.burst })),
+            None => Ok(None),
+        };
+        let limits = &self.rate_limits;
+        Ok(RateLimitSettings {
+            webhook_ip: rate("webhook_ip", &limits.webhook_ip)?,
+            webhook_endpoint: rate("webhook_endpoint", &limits.webhook_endpoint)?,
+            webhook_key: rate("webhook_key", &limits.webhook_key)?,
+            ws_upgrade_ip: rate("ws_upgrade_ip", &limits.ws_upgrade_ip)?,
+            ws_connections_per_ip: (limits.ws_connections_per_ip > 0).then_some(limits.ws_connections_per_ip),
+        })
+    }
+
+    pub fn endpoints(&self) -> EndpointSettings {
+        let prefix = |path: &str| path_segments(path).iter().map(|segment| format!("/{}", segment)).collect();
+        EndpointSettings {
+            capacity: self.endpoints.capacity,
+            max_ttl: (self.endpoints.max_ttl_secs > 0).then(|| Duration::from_secs(self.endpoints.max_ttl_secs)),
+            hook_path: prefix(&self.paths.hook),
+            ws_path: prefix(&self.paths.ws),
+            tls: self.tls.cert.is_some(),
+            tolerance_secs: self.signatures.tolerance_secs,
+        }
+    }
+
+    pub fn backplane(&self) -> Result<BackplaneSettings, ConfigError> {
+        let kind = match self.backplane.kind.as_str() {
+            "in_process" => BackplaneKind::InProcess,
+            "redis" => {
+                let client = redis::Client::open(self.backplane.url.as_str())
+                    .map_err(|e| ConfigError::new("backplane.url", e.to_string()))?;
+                if self.backplane.channel.is_empty() {
+                    return Err(ConfigError::new("backplane.channel", "must not be empty"));
+                }
+                BackplaneKind::Redis { client, channel: self.backplane.channel.clone() }
+            }
+            other => return Err(ConfigError::new("backplane.kind", format!("expected in_process or redis, not {:?}", other))),
+        };
+        let node_id = (!self.backplane.node_id.is_empty()).then(|| self.backplane.node_id.clone());
+        Ok(BackplaneSettings { node_id, kind })
+    }
+
+    pub fn bridge(&self) -> Result<Bridge, ConfigError> {
+        if self.bridge.timeout_ms == 0 {
+            return Err(ConfigError::new("bridge.timeout_ms", "must be greater than 0"));
+        }
+        Ok(Bridge::new(Duration::from_millis(self.bridge.timeout_ms), self.backplane.kind != "redis"))
+    }
+
+    pub fn heartbeat(&self) -> Result<Option<Heartbeat>, ConfigError> {
+        if self.websocket.ping_interval_ms == 0 {
+            return Ok(None);
+        }
+        if self.websocket.max_missed_pongs == 0 {
+            return Err(ConfigError::new("websocket.max_missed_pongs", "must be greater than 0"));
+        }
+        Ok(Some(Heartbeat {
+            interval: Duration::from_millis(self.websocket.ping_interval_ms),
+            max_missed: self.websocket.max_missed_pongs,
+        }))
+    }
+
+    pub fn queues(&self) -> Result<QueueSettings, ConfigError> {
+        let limit = |key: &str, capacity: usize, policy: &str| {
+            if capacity == 0 {
+                return Err(ConfigError::new(format!("{}.capacity", key), "must be greater than 0"));
+            }
+            let policy = OverflowPolicy::parse(policy).ok_or_else(|| {
+                ConfigError::new(format!("{}.policy", key), "expected drop_oldest, drop_newest, disconnect or block")
+            })?;
+            Ok(QueueLimit { capacity, policy })
+        };
+        let default = limit("queues", self.queues.capacity, &self.queues.policy)?;
+        let mut channels = std::collections::HashMap::new();
+        for (channel, queue) in &self.queues.channels {
+            let key = format!("queues.channels.{}", channel);
+            if channel != ALL_CHANNELS && !server::is_valid_channel(channel) {
+                return Err(ConfigError::new(key, "invalid channel name"));
+            }
+            let capacity = queue.capacity.unwrap_or(self.queues.capacity);
+            let policy = queue.policy.as_deref().unwrap_or(&self.queues.policy);
+            channels.insert(channel.clone(), limit(&key, capacity, policy)?);
+        }
+        Ok(QueueSettings { default, channels })
+    }
+
+    pub fn envelope(&self) -> Result<EnvelopeSettings, ConfigError> {
+        let mut headers = std::collections::HashSet::new();
+        for header in &self.envelope.headers {
+            let name = warp::http::HeaderName::from_bytes(header.as_bytes())
+                .map_err(|_| ConfigError::new("envelope.headers", format!("invalid header name {:?}", header)))?;
+            headers.insert(name.as_str().to_string());
+        }
+        Ok(EnvelopeSettings { enabled: self.envelope.enabled, headers: (!headers.is_empty()).then_some(headers) })
+    }
+
+    pub fn forward_targets(&self) -> Result<Vec<ForwardTarget>, ConfigError> {
+        let mut targets = Vec::new();
+        for (name, forward) in &self.forward {
+            let key = format!("forward.{}", name);
+            let url = reqwest::Url::parse(&forward.url).map_err(|e| ConfigError::new(format!("{}.url", key), e.to_string()))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(ConfigError::new(key + ".url", "expected an http or https URL"));
+            }
+            if let Some(channel) = forward.channels.iter().find(|c| !server::is_valid_channel(c)) {
+                return Err(ConfigError::new(key + ".channels", format!("invalid channel name {:?}", channel)));
+            }
+            if forward.max_attempts == 0 {
+                return Err(ConfigError::new(key + ".max_attempts", "must be at least 1"));
+            }
+            if forward.timeout_ms == 0 {
+                return Err(ConfigError::new(key + ".timeout_ms", "must be greater than 0"));
+            }
+            targets.push(ForwardTarget {
+                name: name.clone(),
+                url,
+                channels: (!forward.channels.is_empty()).then(|| forward.channels.iter().cloned().collect()),
+                timeout: Duration::from_millis(forward.timeout_ms),
+                max_attempts: forward.max_attempts,
+                initial_backoff: Duration::from_millis(forward.initial_backoff_ms),
+                max_backoff: Duration::from_millis(forward.max_backoff_ms),
+                credentials: forward.credentials,
+            });
+        }
+        Ok(targets)
+    }
+
+    pub fn verifiers(&self) -> Result<Verifiers, ConfigError> {
+        let mut verifiers = Verifiers::new(self.signatures.tolerance_secs);
+        for (channel, signature) in &self.signatures.channels {
+            let key = format!("signatures.channels.{}", channel);
+            if !server::is_valid_channel(channel) {
+                return Err(ConfigError::new(&key, "invalid channel name"));
+            }
+            let verifier = signature.verifier().map_err(|(field, e)| ConfigError::new(format!("{}.{}", key, field), e))?;
+            verifiers.insert(channel, verifier);
+        }
+        Ok(verifiers)
+    }
+
+    pub fn auth(&self) -> Result<Auth, ConfigError> {
+        let mut auth = Auth::default();
+        auth.set_anonymous_publish(self.websocket.anonymous_publish);
+        auth.set_anonymous_admin(self.admin.anonymous);
+        for (name, token) in &self.tokens {
+            let key = format!("tokens.{}", name);
+            if token.token.is_empty() {
+                return Err(ConfigError::new(key + ".token", "must not be empty"));
+            }
+            if token.channels.is_empty() {
+                return Err(ConfigError::new(key + ".channels", "no channels granted"));
+            }
+            if let Some(channel) = token.channels.iter().find(|c| *c != ALL_CHANNELS && !server::is_valid_channel(c)) {
+                return Err(ConfigError::new(key + ".channels", format!("invalid channel name {:?}", channel)));
+            }
+            if let Some(channel) = token.publish.iter().find(|c| *c != ALL_CHANNELS && !server::is_valid_channel(c)) {
+                return Err(ConfigError::new(key + ".publish", format!("invalid channel name {:?}", channel)));
+            }
+            let channels: Vec<&str> = token.channels.iter().map(String::as_str).collect();
+            let publish: Vec<&str> = token.publish.iter().map(String::as_str).collect();
+            auth.insert(Principal::new(name, &token.token, &channels, &publish));
+        }
+        Ok(auth)
+    }
+
+    pub fn event_log(&self) -> Result<Option<EventLogConfig>, ConfigError> {
+        let Some(dir) = &self.event_log.dir else {
+            return Ok(None);
+        };
+        let fsync = FsyncPolicy::parse(&self.event_log.fsync).map_err(|e| ConfigError::new("event_log.fsync", e))?;
+        if self.event_log.segment_bytes == 0 {
+            return Err(ConfigError::new("event_log.segment_bytes", "must be greater than 0"));
+        }
+        Ok(Some(EventLogConfig {
+            dir: dir.clone(),
+            fsync,
+            segment_bytes: self.event_log.segment_bytes,
+            max_segments: self.event_log.max_segments,
+        }))
+    }
+
+    pub fn tls(&self) -> Result<Option<TlsSettings>, ConfigError> {
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(cert), Some(key)) => Ok(Some(TlsSettings {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: self.tls.client_ca.clone(),
+            })),
+            (Some(_), None) => Err(ConfigError::new("tls.key", "required when tls.cert is set")),
+            (None, Some(_)) => Err(ConfigError::new("tls.cert", "required when tls.key is set")),
+            (None, None) if self.tls.client_ca.is_some() => {
+                Err(ConfigError::new("tls.client_ca", "requires tls.cert and tls.key"))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Splits a configured route path such as `/hooks/incoming/` into segments.
+pub fn path_segments(path: &str) -> Vec<String> {
+    path.split('/').filter(|s| !s.is_empty()).map(String::from).collect()
+}
+
+fn is_valid_origin(origin: &str) -> bool {
+    let Some(rest) = origin.strip_prefix("https://").or_else(|| origin.strip_prefix("http://")) else {
+        return false;
+    };
+    !rest.is_empty() && !rest.contains('/') && rest.parse::<warp::http::uri::Authority>().is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect::<Vec<_>>().into_iter()
+    }
+
+    fn invalid(config: &Config) -> String {
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn flags_override_env_which_overrides_the_file() {
+        let file = "listen = [\"127.0.0.1:1\"]\n[limits]\nmax_body_bytes = 10\nreplay_capacity = 10\ndead_letter_capacity = 10\n";
+        let mut config: Config = toml::from_str(file).unwrap();
+        config.apply_env(env(&[("NWEBHOOK_MAX_BODY_BYTES", "20"), ("NWEBHOOK_REPLAY_CAPACITY", "20"), ("HOME", "/")])).unwrap();
+        config.apply_cli(&Cli::parse_from(["nwebhook", "--max-body-bytes", "30"]));
+        config.validate().unwrap();
+        assert_eq!(config.listen, [SocketAddr::from(([127, 0, 0, 1], 1))]);
+        assert_eq!(config.limits.dead_letter_capacity, 10);
+        assert_eq!(config.limits.replay_capacity, 20);
+        assert_eq!(config.limits.max_body_bytes, 30);
+        // Anything left unset keeps its default.
+        assert_eq!(config.limits.replay_channels, 1000);
+    }
+
+    #[test]
+    fn unknown_env_settings_are_errors() {
+        let mut config = Config::default();
+        let error = config.apply_env(env(&[("NWEBHOOK_MAX_BODY", "20")])).unwrap_err();
+        assert_eq!(error.to_string(), "NWEBHOOK_MAX_BODY: unknown setting");
+        let error = config.apply_env(env(&[("NWEBHOOK_QUEUE_CAPACITY", "lots")])).unwrap_err();
+        assert_eq!(error.to_string(), "NWEBHOOK_QUEUE_CAPACITY: expected a number of messages");
+        // Variables without the prefix are not ours to judge.
+        config.apply_env(env(&[("MAX_BODY", "20")])).unwrap();
+    }
+
+    #[test]
+    fn unknown_file_settings_are_errors() {
+        let error = toml::from_str::<Config>("[limits]\nmax_body = 10\n").unwrap_err();
+        assert!(error.to_string().contains("unknown field `max_body`"), "{}", error);
+    }
+
+    #[test]
+    fn validation_errors_name_the_key() {
+        let mut config = Config::default();
+        config.limits.max_body_bytes = 0;
+        assert_eq!(invalid(&config), "limits.max_body_bytes: must be greater than 0");
+
+        let mut config = Config::default();
+        config.cors.origins = vec!["example.com".to_string()];
+        assert_eq!(invalid(&config), "cors.origins: invalid origin \"example.com\"");
+
+        let mut config = Config::default();
+        config.queues.channels.insert("orders".to_string(), QueueConfig { capacity: None, policy: Some("drop".to_string()) });
+        assert_eq!(invalid(&config), "queues.channels.orders.policy: expected drop_oldest, drop_newest, disconnect or block");
+
+        let mut config = Config::default();
+        config.apply_env(env(&[("NWEBHOOK_FORWARD_AUDIT", "ftp://example.com/")])).unwrap();
+        assert_eq!(invalid(&config), "forward.audit.url: expected an http or https URL");
+
+        let mut config = Config::default();
+        config.apply_env(env(&[("NWEBHOOK_TOKEN_CI", "secret:builds,Not Valid")])).unwrap();
+        assert_eq!(invalid(&config), "tokens.ci.channels: invalid channel name \"Not Valid\"");
+    }
+
+    #[test]
+    fn paths_stay_clear_of_fixed_routes_and_each_other() {
+        for route in FIXED_ROUTES {
+            let mut config = Config::default();
+            config.paths.webhook = format!("/{}/hooks", route);
+            let expected = format!("paths.webhook: \"/{}/hooks\" collides with the fixed /{} routes", route, route);
+            assert_eq!(invalid(&config), expected);
+        }
+        let mut config = Config::default();
+        config.paths.events = "/ws/".to_string();
+        assert_eq!(invalid(&config), "paths.events: same path as paths.ws");
+        let mut config = Config::default();
+        config.paths.hook = "/".to_string();
+        assert_eq!(invalid(&config), "paths.hook: invalid path \"/\"");
+        // Nesting under a fixed route's name is fine further down.
+        config.paths.hook = "/hooks/admin".to_string();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn endpoints_are_refused_with_the_redis_backplane() {
+        let mut config = Config::default();
+        config.apply_env(env(&[("NWEBHOOK_BACKPLANE", "redis"), ("NWEBHOOK_BACKPLANE_URL", "redis://127.0.0.1/")])).unwrap();
+        let expected = "endpoints.capacity: must be 0 with the redis backplane; endpoints are not shared between nodes";
+        assert_eq!(invalid(&config), expected);
+        config.apply_env(env(&[("NWEBHOOK_ENDPOINT_CAPACITY", "0")])).unwrap();
+        config.validate().unwrap();
+        assert!(!config.bridge().unwrap().is_enabled());
+        assert!(Config::default().bridge().unwrap().is_enabled());
+    }
+}
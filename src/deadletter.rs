@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use serde::Serialize;
+use log::warn;
+use crate::forward::Forward;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// The client's connection closed before the message was written.
+    ClientClosed,
+    /// An administrator, or the deletion of its endpoint, disconnected the
+    /// client before the message was written.
+    Disconnected,
+    /// The client's subscription filter did not let the event through.
+    FilterRejected,
+    /// Every attempt to forward the webhook to a downstream target failed.
+    ForwardFailed,
+    /// The client stopped answering pings and was disconnected.
+    HeartbeatTimeout,
+    /// The client's outbound queue was full; see its overflow policy.
+    QueueOverflow,
+    /// The server was shutting down and had closed the client's connection.
+    Shutdown,
+}
+
+impl Reason {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "client_closed" => Some(Reason::ClientClosed),
+            "disconnected" => Some(Reason::Disconnected),
+            "filter_rejected" => Some(Reason::FilterRejected),
+            "forward_failed" => Some(Reason::ForwardFailed),
+            "heartbeat_timeout" => Some(Reason::HeartbeatTimeout),
+            "queue_overflow" => Some(Reason::QueueOverflow),
+            "shutdown" => Some(Reason::Shutdown),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Reason::ClientClosed => "client_closed",
+            Reason::Disconnected => "disconnected",
+            Reason::FilterRejected => "filter_rejected",
+            Reason::ForwardFailed => "forward_failed",
+            Reason::HeartbeatTimeout => "heartbeat_timeout",
+            Reason::QueueOverflow => "queue_overflow",
+            Reason::Shutdown => "shutdown",
+        })
+    }
+}
+
+/// How [`Undeliverable::payload`] holds the original bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadEncoding {
+    /// The payload is the message itself.
+    #[default]
+    Text,
+    /// The message was not UTF-8; the payload is its standard base64.
+    Base64,
+}
+
+impl PayloadEncoding {
+    fn is_text(&self) -> bool {
+        *self == PayloadEncoding::Text
+    }
+
+    /// Encodes `bytes` as a payload: as is when they are UTF-8, as base64
+    /// otherwise.
+    pub fn encode(bytes: &[u8]) -> (String, Self) {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => (text.to_string(), PayloadEncoding::Text),
+            Err(_) => (base64::engine::general_purpose::STANDARD.encode(bytes), PayloadEncoding::Base64),
+        }
+    }
+
+    /// The original bytes of a payload made by `encode`.
+    pub fn decode(self, payload: &str) -> Option<Vec<u8>> {
+        match self {
+            PayloadEncoding::Text => Some(payload.as_bytes().to_vec()),
+            PayloadEncoding::Base64 => base64::engine::general_purpose::STANDARD.decode(payload).ok(),
+        }
+    }
+}
+
+/// A message that could not be delivered, as handed to [`DeadLetters::push`].
+#[derive(Debug, Clone, Serialize)]
+pub struct Undeliverable {
+    pub reason: Reason,
+    /// Human-readable cause, e.g. the last forwarding error.
+    pub detail: String,
+    /// Id of the published event, if the message was one.
+    pub event_id: Option<u64>,
+    pub channel: Option<String>,
+    /// Id of the WebSocket/SSE client it was meant for.
+    pub client: Option<usize>,
+    /// Name of the forward target it was meant for.
+    pub target: Option<String>,
+    pub payload: String,
+    #[serde(skip_serializing_if = "PayloadEncoding::is_text")]
+    pub payload_encoding: PayloadEncoding,
+    /// The webhook as it was to be forwarded to `target`, for redelivery.
+    #[serde(skip)]
+    pub forward: Option<Arc<Forward>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    pub id: u64,
+    /// Unix time in milliseconds at which delivery was given up.
+    pub created_at: u64,
+    #[serde(flatten)]
+    pub message: Undeliverable,
+}
+
+/// Bounded in-memory store of undeliverable messages; the oldest entries are
+/// dropped once `capacity` is reached.
+#[derive(Clone)]
+pub struct DeadLetters {
+    capacity: usize,
+    inner: Arc<Mutex<Store>>,
+}
+
+struct Store {
+    next_id: u64,
+    letters: VecDeque<DeadLetter>,
+}
+
+impl DeadLetters {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, inner: Arc::new(Mutex::new(Store { next_id: 1, letters: VecDeque::new() })) }
+    }
+
+    pub fn push(&self, message: Undeliverable) {
+        warn!(
+            "Dead-lettered message for {} ({}): {}",
+            match (&message.client, &message.target) {
+                (Some(client), _) => format!("user {}", client),
+                (None, Some(target)) => format!("target {}", target),
+                (None, None) => "unknown recipient".to_string(),
+            },
+            message.reason,
+            message.detail
+        );
+        if self.capacity == 0 {
+            return;
+        }
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let mut store = self.inner.lock().unwrap();
+        let id = store.next_id;
+        store.next_id += 1;
+        if store.letters.len() == self.capacity {
+            store.letters.pop_front();
+        }
+        store.letters.push_back(DeadLetter { id, created_at, message });
+    }
+
+    /// Dead letters matching the optional filters, newest first.
+    pub fn list(&self, reason: Option<Reason>, channel: Option<&str>) -> Vec<DeadLetter> {
+        self.inner
+            .lock()
+            .unwrap()
+            .letters
+            .iter()
+            .rev()
+            .filter(|letter| reason.is_none_or(|reason| letter.message.reason == reason))
+            .filter(|letter| channel.is_none_or(|channel| letter.message.channel.as_deref() == Some(channel)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<DeadLetter> {
+        self.inner.lock().unwrap().letters.iter().find(|letter| letter.id == id).cloned()
+    }
+
+    pub fn remove(&self, id: u64) -> Option<DeadLetter> {
+        let mut store = self.inner.lock().unwrap();
+        let index = store.letters.iter().position(|letter| letter.id == id)?;
+        store.letters.remove(index)
+    }
+
+    /// Drops every dead letter and returns how many there were.
+    pub fn purge(&self) -> usize {
+        let mut store = self.inner.lock().unwrap();
+        let purged = store.letters.len();
+        store.letters.clear();
+        purged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reasons_round_trip() {
+        let reasons = [
+            Reason::ClientClosed,
+            Reason::Disconnected,
+            Reason::FilterRejected,
+            Reason::ForwardFailed,
+            Reason::HeartbeatTimeout,
+            Reason::QueueOverflow,
+            Reason::Shutdown,
+        ];
+        for reason in reasons {
+            assert_eq!(Reason::parse(&reason.to_string()), Some(reason));
+            assert_eq!(serde_json::to_value(reason).unwrap(), reason.to_string());
+        }
+        assert_eq!(Reason::parse("filtered"), None);
+    }
+}
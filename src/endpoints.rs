@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use warp::http::{HeaderMap, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::Reply;
+use log::{info, warn};
+use crate::admin;
+use crate::auth::Auth;
+use crate::config::SignatureConfig;
+use crate::deadletter::Reason;
+use crate::server::WebSocketServer;
+use crate::signature::{Verifier, VerifyError};
+
+/// Prefix of the channels backing runtime endpoints. The plain webhook route
+/// refuses these channels so that an endpoint's secret cannot be bypassed.
+pub const CHANNEL_PREFIX: &str = "hook.";
+
+const TOKEN_LEN: usize = 32;
+
+/// How often expired endpoints are removed and their subscribers closed.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Close code sent to subscribers of an endpoint that was deleted or expired.
+const CLOSE_GONE: u16 = 4410;
+
+pub fn is_endpoint_channel(channel: &str) -> bool {
+    channel.starts_with(CHANNEL_PREFIX)
+}
+
+#[derive(Debug, Clone)]
+pub struct EndpointSettings {
+    /// Endpoints that may exist at once; 0 disables creating them.
+    pub capacity: usize,
+    /// Longest lifetime an endpoint may ask for, and the lifetime of those
+    /// that do not ask; `None` lets them live until deleted.
+    pub max_ttl: Option<Duration>,
+    /// Route prefixes, with a leading `/`, used to build endpoint URLs.
+    pub hook_path: String,
+    pub ws_path: String,
+    pub tls: bool,
+    pub tolerance_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Endpoint {
+    #[serde(rename = "id")]
+    pub token: String,
+    /// Channel the endpoint's webhooks are published on.
+    pub channel: String,
+    /// Unix time in milliseconds.
+    pub created_at: u64,
+    /// Unix time in milliseconds after which the endpoint is gone.
+    pub expires_at: Option<u64>,
+    /// Provider requests must be signed for, if the endpoint has a secret.
+    pub signature: Option<String>,
+    #[serde(skip)]
+    verifier: Option<Verifier>,
+}
+
+impl Endpoint {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Webhook endpoints created at runtime through `/api/endpoints`, by token.
+#[derive(Clone)]
+pub struct Endpoints {
+    settings: Arc<EndpointSettings>,
+    inner: Arc<Mutex<HashMap<String, Endpoint>>>,
+}
+
+impl Endpoints {
+    pub fn new(settings: EndpointSettings) -> Self {
+        Self { settings: Arc::new(settings), inner: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Creates an endpoint with a fresh random token, or returns `None` when
+    /// `capacity` endpoints already exist.
+    fn create(&self, ttl: Option<Duration>, signature: Option<(String, Verifier)>) -> Option<Endpoint> {
+        let now = now_ms();
+        let mut endpoints = self.inner.lock().unwrap();
+        // Expired endpoints are left for the expiry task, which also closes
+        // their subscribers.
+        if endpoints.values().filter(|endpoint| !endpoint.is_expired(now)).count() >= self.settings.capacity {
+            return None;
+        }
+        let token: String = rand::thread_rng().sample_iter(&Alphanumeric).take(TOKEN_LEN).map(char::from).collect();
+        let (signature, verifier) = signature.unzip();
+        let endpoint = Endpoint {
+            channel: format!("{}{}", CHANNEL_PREFIX, token),
+            token: token.clone(),
+            created_at: now,
+            expires_at: ttl.map(|ttl| now + ttl.as_millis() as u64),
+            signature,
+            verifier,
+        };
+        endpoints.insert(token, endpoint.clone());
+        Some(endpoint)
+    }
+
+    /// The endpoint for `token`, unless it was deleted or has expired.
+    pub fn get(&self, token: &str) -> Option<Endpoint> {
+        let now = now_ms();
+        self.inner.lock().unwrap().get(token).filter(|endpoint| !endpoint.is_expired(now)).cloned()
+    }
+
+    /// Live endpoints, oldest first.
+    pub fn list(&self) -> Vec<Endpoint> {
+        let now = now_ms();
+        let mut endpoints: Vec<Endpoint> =
+            self.inner.lock().unwrap().values().filter(|endpoint| !endpoint.is_expired(now)).cloned().collect();
+        endpoints.sort_by(|a, b| (a.created_at, &a.token).cmp(&(b.created_at, &b.token)));
+        endpoints
+    }
+
+    pub fn remove(&self, token: &str) -> Option<Endpoint> {
+        self.inner.lock().unwrap().remove(token)
+    }
+
+    fn remove_expired(&self) -> Vec<Endpoint> {
+        let now = now_ms();
+        let mut endpoints = self.inner.lock().unwrap();
+        let expired: Vec<String> =
+            endpoints.values().filter(|endpoint| endpoint.is_expired(now)).map(|endpoint| endpoint.token.clone()).collect();
+        expired.iter().filter_map(|token| endpoints.remove(token)).collect()
+    }
+
+    /// Checks the request against the endpoint's secret, if it has one.
+    pub fn verify(&self, endpoint: &Endpoint, headers: &HeaderMap, body: &[u8]) -> Result<(), VerifyError> {
+        match &endpoint.verifier {
+            Some(verifier) => verifier.verify(headers, body, self.settings.tolerance_secs).map_err(|e| {
+                warn!("Signature verification failed on endpoint {}: {}", endpoint.channel, e);
+                e
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// The endpoint as returned by the API, with its webhook and WebSocket
+    /// URLs made absolute when the request named a host.
+    fn describe(&self, endpoint: &Endpoint, host: Option<&str>) -> Value {
+        let hook = format!("{}/{}", self.settings.hook_path, endpoint.token);
+        let ws = format!("{}/{}", self.settings.ws_path, endpoint.channel);
+        let (url, ws_url) = match host {
+            Some(host) => {
+                let (http, ws_scheme) = if self.settings.tls { ("https", "wss") } else { ("http", "ws") };
+                (format!("{}://{}{}", http, host, hook), format!("{}://{}{}", ws_scheme, host, ws))
+            }
+            None => (hook, ws),
+        };
+        let mut value = serde_json::to_value(endpoint).unwrap_or_default();
+        if let Value::Object(fields) = &mut value {
+            fields.insert("url".to_string(), Value::String(url));
+            fields.insert("ws_url".to_string(), Value::String(ws_url));
+        }
+        value
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Closes the connections subscribed to a deleted or expired endpoint and
+/// drops its buffered events.
+async fn close_subscribers(ws_server: &WebSocketServer, channel: &str, reason: &str) {
+    ws_server.replay.lock().await.forget(channel);
+    for client in ws_server.clients(Some(channel)).await {
+        if ws_server.disconnect(client.id, CLOSE_GONE, reason, Reason::Disconnected).await.is_ok() {
+            ws_server.metrics.eviction("endpoint_gone");
+        }
+    }
+}
+
+/// Periodically removes expired endpoints.
+pub fn spawn_expiry_task(endpoints: Endpoints, ws_server: WebSocketServer) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+        loop {
+            interval.tick().await;
+            for endpoint in endpoints.remove_expired() {
+                info!("Endpoint {} expired", endpoint.channel);
+                close_subscribers(&ws_server, &endpoint.channel, "endpoint expired").await;
+            }
+        }
+    });
+}
+
+/// Body of `POST /api/endpoints`; an empty body takes every default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CreateRequest {
+    ttl_secs: Option<u64>,
+    /// Secret webhooks must be signed with, as in `[signatures.channels]`.
+    signature: Option<SignatureConfig>,
+}
+
+/// `POST /api/endpoints`: creates an endpoint at `/hook/{token}` whose
+/// webhooks are published on its own channel.
+pub async fn handle_create(
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    body: Bytes,
+    auth: Arc<Auth>,
+    endpoints: Endpoints,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = admin::authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    let request = if body.is_empty() {
+        CreateRequest::default()
+    } else {
+        match serde_json::from_slice::<CreateRequest>(&body) {
+            Ok(request) => request,
+            Err(e) => return Ok(warp::reply::with_status(format!("Invalid request: {}", e), StatusCode::BAD_REQUEST).into_response()),
+        }
+    };
+    let max_ttl = endpoints.settings.max_ttl;
+    let ttl = match request.ttl_secs.map(Duration::from_secs) {
+        Some(ttl) if ttl.is_zero() => {
+            return Ok(warp::reply::with_status("ttl_secs must be greater than 0", StatusCode::BAD_REQUEST).into_response());
+        }
+        Some(ttl) if max_ttl.is_some_and(|max_ttl| ttl > max_ttl) => {
+            let message = format!("ttl_secs must be at most {}", max_ttl.unwrap_or_default().as_secs());
+            return Ok(warp::reply::with_status(message, StatusCode::BAD_REQUEST).into_response());
+        }
+        Some(ttl) => Some(ttl),
+        None => max_ttl,
+    };
+    let signature = match request.signature {
+        Some(signature) => match signature.verifier() {
+            Ok(verifier) => Some((signature.provider, verifier)),
+            Err((field, e)) => {
+                let message = format!("Invalid signature.{}: {}", field, e);
+                return Ok(warp::reply::with_status(message, StatusCode::BAD_REQUEST).into_response());
+            }
+        },
+        None => None,
+    };
+    let Some(endpoint) = endpoints.create(ttl, signature) else {
+        warn!("Refused to create an endpoint: limit of {} reached", endpoints.settings.capacity);
+        return Ok(warp::reply::with_status("Endpoint limit reached", StatusCode::SERVICE_UNAVAILABLE).into_response());
+    };
+    info!("Created endpoint {}", endpoint.channel);
+    let host = headers.get("host").and_then(|host| host.to_str().ok());
+    let reply = warp::reply::with_status(warp::reply::json(&endpoints.describe(&endpoint, host)), StatusCode::CREATED);
+    Ok(warp::reply::with_header(reply, "location", format!("/api/endpoints/{}", endpoint.token)).into_response())
+}
+
+/// `GET /api/endpoints`: live endpoints, oldest first.
+pub async fn handle_list(
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    auth: Arc<Auth>,
+    endpoints: Endpoints,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = admin::authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    let host = headers.get("host").and_then(|host| host.to_str().ok());
+    let list: Vec<Value> = endpoints.list().iter().map(|endpoint| endpoints.describe(endpoint, host)).collect();
+    Ok(warp::reply::json(&list).into_response())
+}
+
+/// `GET /api/endpoints/{token}`
+pub async fn handle_get(
+    token: String,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    auth: Arc<Auth>,
+    endpoints: Endpoints,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = admin::authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    match endpoints.get(&token) {
+        Some(endpoint) => {
+            let host = headers.get("host").and_then(|host| host.to_str().ok());
+            Ok(warp::reply::json(&endpoints.describe(&endpoint, host)).into_response())
+        }
+        None => Ok(warp::reply::with_status("No such endpoint", StatusCode::NOT_FOUND).into_response()),
+    }
+}
+
+/// `DELETE /api/endpoints/{token}`: later webhooks to the endpoint get 404
+/// and its subscribers are closed with code 4410.
+pub async fn handle_delete(
+    token: String,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    auth: Arc<Auth>,
+    endpoints: Endpoints,
+    ws_server: WebSocketServer,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = admin::authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    match endpoints.remove(&token) {
+        Some(endpoint) => {
+            info!("Deleted endpoint {}", endpoint.channel);
+            close_subscribers(&ws_server, &endpoint.channel, "endpoint deleted").await;
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        None => Ok(warp::reply::with_status("No such endpoint", StatusCode::NOT_FOUND).into_response()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AuthError, Principal, TokenSource};
+    use crate::signature::Provider;
+
+    fn endpoints(capacity: usize) -> Endpoints {
+        Endpoints::new(EndpointSettings {
+            capacity,
+            max_ttl: None,
+            hook_path: "/hook".to_string(),
+            ws_path: "/ws".to_string(),
+            tls: false,
+            tolerance_secs: 300,
+        })
+    }
+
+    /// Moves an endpoint's expiry into the past.
+    fn expire(endpoints: &Endpoints, token: &str) {
+        endpoints.inner.lock().unwrap().get_mut(token).unwrap().expires_at = Some(now_ms() - 1);
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn create_get_and_delete() {
+        let endpoints = endpoints(2);
+        let first = endpoints.create(None, None).unwrap();
+        assert_eq!(first.token.len(), TOKEN_LEN);
+        assert_eq!(first.channel, format!("hook.{}", first.token));
+        assert!(is_endpoint_channel(&first.channel));
+        assert_eq!(first.expires_at, None);
+        let second = endpoints.create(Some(Duration::from_secs(60)), None).unwrap();
+        assert_ne!(first.token, second.token);
+        assert_eq!(second.expires_at, Some(second.created_at + 60_000));
+
+        assert!(endpoints.create(None, None).is_none(), "capacity is 2");
+        assert_eq!(endpoints.get(&first.token).map(|endpoint| endpoint.channel), Some(first.channel.clone()));
+        assert!(endpoints.get("unknown").is_none());
+        let tokens: Vec<String> = endpoints.list().into_iter().map(|endpoint| endpoint.token).collect();
+        assert!(tokens.contains(&first.token) && tokens.contains(&second.token));
+
+        assert_eq!(endpoints.remove(&first.token).map(|endpoint| endpoint.token), Some(first.token.clone()));
+        assert!(endpoints.get(&first.token).is_none());
+        assert!(endpoints.remove(&first.token).is_none());
+        assert!(endpoints.create(None, None).is_some(), "deleting frees a slot");
+    }
+
+    #[test]
+    fn expired_endpoints_are_gone_before_they_are_removed() {
+        let endpoints = endpoints(1);
+        let endpoint = endpoints.create(Some(Duration::from_secs(60)), None).unwrap();
+        assert!(endpoints.remove_expired().is_empty());
+        expire(&endpoints, &endpoint.token);
+        assert!(endpoints.get(&endpoint.token).is_none());
+        assert!(endpoints.list().is_empty());
+        // Expired endpoints do not count against the capacity, but stay
+        // until the expiry task removes them and closes their subscribers.
+        let replacement = endpoints.create(None, None).unwrap();
+        let expired: Vec<String> = endpoints.remove_expired().into_iter().map(|endpoint| endpoint.token).collect();
+        assert_eq!(expired, [endpoint.token]);
+        assert!(endpoints.remove_expired().is_empty());
+        assert!(endpoints.get(&replacement.token).is_some());
+    }
+
+    #[test]
+    fn verifies_webhooks_against_the_endpoint_secret() {
+        let endpoints = endpoints(2);
+        let verifier = Verifier::new(Provider::GitHub, "It's a Secret to Everybody");
+        let signed = endpoints.create(None, Some(("github".to_string(), verifier))).unwrap();
+        assert_eq!(signed.signature.as_deref(), Some("github"));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-hub-signature-256",
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17".parse().unwrap(),
+        );
+        assert_eq!(endpoints.verify(&signed, &headers, b"Hello, World!"), Ok(()));
+        assert_eq!(endpoints.verify(&signed, &headers, b"Hello, World?"), Err(VerifyError::Mismatch));
+        assert!(endpoints.verify(&signed, &HeaderMap::new(), b"Hello, World!").is_err());
+
+        let unsigned = endpoints.create(None, None).unwrap();
+        assert_eq!(endpoints.verify(&unsigned, &HeaderMap::new(), b"anything"), Ok(()));
+    }
+
+    #[test]
+    fn endpoint_token_admits_subscribers_to_its_channel_only() {
+        let endpoints = endpoints(1);
+        let endpoint = endpoints.create(None, None).unwrap();
+        let mut auth = Auth::default();
+        auth.insert(Principal::new("dashboard", "dashboard-token", &["orders"], &[]));
+        let query = HashMap::new();
+        let subscribe =
+            |headers: &HeaderMap, channel: &str| auth.authenticate_with_secret(headers, &query, channel, &endpoint.token);
+
+        assert_eq!(subscribe(&bearer(&endpoint.token), &endpoint.channel), Ok((None, Some(TokenSource::Header))));
+        let mut by_query = HashMap::new();
+        by_query.insert("token".to_string(), endpoint.token.clone());
+        assert_eq!(
+            auth.authenticate_with_secret(&HeaderMap::new(), &by_query, &endpoint.channel, &endpoint.token),
+            Ok((None, Some(TokenSource::Query)))
+        );
+        // Other tokens go through the usual grants.
+        assert_eq!(subscribe(&bearer("dashboard-token"), &endpoint.channel), Err(AuthError::Forbidden));
+        assert_eq!(subscribe(&bearer("wrong"), &endpoint.channel), Err(AuthError::InvalidToken));
+        assert_eq!(subscribe(&HeaderMap::new(), &endpoint.channel), Err(AuthError::MissingToken));
+        let prefix = &endpoint.token[..TOKEN_LEN - 1];
+        assert_eq!(subscribe(&bearer(prefix), &endpoint.channel), Err(AuthError::InvalidToken));
+    }
+
+    #[test]
+    fn describes_urls_for_the_request_host() {
+        let endpoints = endpoints(1);
+        let endpoint = endpoints.create(None, None).unwrap();
+        let described = endpoints.describe(&endpoint, Some("example.com:3030"));
+        assert_eq!(described["id"], endpoint.token.as_str());
+        assert_eq!(described["url"], format!("http://example.com:3030/hook/{}", endpoint.token));
+        assert_eq!(described["ws_url"], format!("ws://example.com:3030/ws/hook.{}", endpoint.token));
+        assert_eq!(endpoints.describe(&endpoint, None)["url"], format!("/hook/{}", endpoint.token));
+    }
+
+    #[tokio::test]
+    async fn deleting_closes_subscribers() {
+        let ws_server = WebSocketServer::for_tests();
+        let endpoints = endpoints(1);
+        let endpoint = endpoints.create(None, None).unwrap();
+        let subscription = crate::subscription::Subscription {
+            channel: endpoint.channel.clone(),
+            remote_addr: "127.0.0.1:1".parse().unwrap(),
+            principal: None,
+            token_source: None,
+            replay: Default::default(),
+            publish: Default::default(),
+        };
+        let (_, mut rx) = ws_server.register(&subscription).await;
+        ws_server.publish(&endpoint.channel, "before").await.unwrap();
+
+        let mut auth = Auth::default();
+        auth.set_anonymous_admin(true);
+        let (headers, query, auth) = (HeaderMap::new(), HashMap::new(), Arc::new(auth));
+        let token = endpoint.token.clone();
+        let response = handle_delete(token, headers, query, auth, endpoints.clone(), ws_server.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(endpoints.get(&endpoint.token).is_none());
+        match rx.try_recv().map(|delivery| delivery.message) {
+            Some(crate::server::Outbound::Close { code, undelivered, .. }) => {
+                assert_eq!((code, undelivered), (CLOSE_GONE, Reason::Disconnected));
+            }
+            other => panic!("expected the close frame first, got {:?}", other.map(|message| message.to_string())),
+        }
+        let everything = crate::replay::ReplayRequest { since: Some(0), last: None };
+        let (events, _) = ws_server.replay.lock().await.replay(&endpoint.channel, everything);
+        assert!(events.is_empty(), "buffered events are dropped");
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use warp::http::{HeaderMap, Method};
+use warp::Filter;
+use crate::listener::{self, ConnectionInfo};
+use crate::payload;
+
+/// Headers left out of envelopes unless explicitly selected, since every
+/// subscriber would otherwise see the sender's credentials.
+pub const CREDENTIAL_HEADERS: &[&str] = &["authorization", "cookie", "proxy-authorization", "x-api-key"];
+
+#[derive(Debug, Clone)]
+pub struct EnvelopeSettings {
+    /// Wrap bodies in an [`Envelope`]; otherwise the bare body is sent.
+    pub enabled: bool,
+    /// Lowercase names of the headers to include; `None` includes all but
+    /// [`CREDENTIAL_HEADERS`].
+    pub headers: Option<HashSet<String>>,
+}
+
+impl EnvelopeSettings {
+    fn includes(&self, name: &str) -> bool {
+        match &self.headers {
+            Some(headers) => headers.contains(name),
+            None => !CREDENTIAL_HEADERS.contains(&name),
+        }
+    }
+}
+
+/// Facts about an incoming webhook request other than its headers and body.
+#[derive(Debug, Clone)]
+pub struct Received {
+    pub method: Method,
+    pub path: String,
+    /// Raw query string, without the `?`.
+    pub query: String,
+    pub remote_addr: SocketAddr,
+    /// Unix time in milliseconds.
+    pub received_at: u64,
+}
+
+/// Extracts the [`Received`] metadata of the current request.
+pub fn received() -> impl Filter<Extract = (Received,), Error = warp::Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(listener::connection_info())
+        .map(|method, path: warp::path::FullPath, query, info: ConnectionInfo| Received {
+            method,
+            path: path.as_str().to_string(),
+            query,
+            remote_addr: info.remote_addr,
+            received_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+        })
+}
+
+/// What subscribers receive for each webhook when envelopes are enabled.
+#[derive(Debug, Serialize)]
+pub struct Envelope<'a> {
+    /// The event id, as also sent in `x-event-id` and SSE `id:` fields.
+    /// Each node numbers its own events, so only `node` and `id` together
+    /// identify one across a cluster.
+    pub id: u64,
+    /// The node the webhook was received on.
+    pub node: &'a str,
+    pub channel: &'a str,
+    pub received_at: u64,
+    pub method: &'a str,
+    pub path: &'a str,
+    /// Query parameters; repeated parameters become arrays.
+    pub query: Value,
+    /// Lowercase header names; repeated headers become arrays.
+    pub headers: Value,
+    pub remote_addr: SocketAddr,
+    pub content_type: Option<String>,
+    pub body: &'a Value,
+}
+
+impl<'a> Envelope<'a> {
+    pub fn new(
+        id: u64,
+        node: &'a str,
+        channel: &'a str,
+        received: &'a Received,
+        headers: &HeaderMap,
+        body: &'a Value,
+        settings: &EnvelopeSettings,
+    ) -> Self {
+        let mut selected = Map::new();
+        for (name, value) in headers.iter().filter(|(name, _)| settings.includes(name.as_str())) {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            payload::insert_repeated(&mut selected, name.as_str().to_string(), Value::String(value));
+        }
+        Self {
+            id,
+            node,
+            channel,
+            received_at: received.received_at,
+            method: received.method.as_str(),
+            path: &received.path,
+            query: payload::form_to_json(received.query.as_bytes()),
+            headers: Value::Object(selected),
+            remote_addr: received.remote_addr,
+            content_type: payload::content_type(headers),
+            body,
+        }
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use log::{info, warn};
+use crate::replay::{Event, ReplayRequest};
+use crate::server::ALL_CHANNELS;
+
+const SEGMENT_EXTENSION: &str = "log";
+
+/// Each record is framed as `[len: u32 LE][crc32: u32 LE][len bytes of JSON]`,
+/// so a write torn by a crash is detected and cut off on the next start.
+const HEADER_LEN: usize = 8;
+
+/// When appended records are forced to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// fsync before every append returns; nothing acknowledged is lost.
+    Always,
+    /// fsync from a background task; up to one interval of events can be
+    /// lost on power failure, though not on a process crash.
+    Interval(Duration),
+    /// Leave flushing to the OS.
+    Never,
+}
+
+impl FsyncPolicy {
+    /// Parses `always`, `never` or `interval:<milliseconds>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            other => other
+                .strip_prefix("interval:")
+                .and_then(|ms| ms.parse().ok())
+                .filter(|ms| *ms > 0)
+                .map(|ms| FsyncPolicy::Interval(Duration::from_millis(ms)))
+                .ok_or_else(|| format!("expected always, never or interval:<ms>, got {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EventLogConfig {
+    pub dir: PathBuf,
+    pub fsync: FsyncPolicy,
+    /// A segment is closed and a new one started once it would exceed this size.
+    pub segment_bytes: u64,
+    /// Oldest segments beyond this count are deleted; 0 keeps everything.
+    pub max_segments: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub id: u64,
+    pub channel: String,
+    pub payload: String,
+}
+
+#[derive(Debug)]
+struct Segment {
+    /// Id of the first record in the segment, which is also its file name.
+    first_id: u64,
+    path: PathBuf,
+}
+
+/// Append-only event log split into segment files named after the id of
+/// their first record.
+#[derive(Debug)]
+pub struct EventLog {
+    config: EventLogConfig,
+    segments: Vec<Segment>,
+    active: Option<File>,
+    active_len: u64,
+    last_id: u64,
+    dirty: bool,
+    /// Set when a failed append could not be rolled back; the log refuses
+    /// further appends until it is reopened and the torn tail cut off.
+    poisoned: bool,
+}
+
+impl EventLog {
+    /// Opens the log in `config.dir`, creating it if needed, and truncates a
+    /// torn record left at the end of the newest segment by a crash.
+    pub fn open(config: EventLogConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(first_id) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                segments.push(Segment { first_id, path });
+            }
+        }
+        segments.sort_by_key(|segment| segment.first_id);
+
+        let mut log = Self { config, segments, active: None, active_len: 0, last_id: 0, dirty: false, poisoned: false };
+        if let Some(segment) = log.segments.last() {
+            let mut last_id = segment.first_id.saturating_sub(1);
+            let (valid_len, clean) = read_segment(&segment.path, |record| last_id = record.id)?;
+            let file = OpenOptions::new().append(true).open(&segment.path)?;
+            if !clean {
+                warn!("Truncating torn record at offset {} of {}", valid_len, segment.path.display());
+                file.set_len(valid_len)?;
+                file.sync_all()?;
+            }
+            log.active = Some(file);
+            log.active_len = valid_len;
+            log.last_id = last_id;
+        }
+        info!(
+            "Opened event log in {} ({} segments, last event {})",
+            log.config.dir.display(),
+            log.segments.len(),
+            log.last_id
+        );
+        Ok(log)
+    }
+
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        self.config.fsync
+    }
+
+    /// Id of the newest record in the log, or 0 when it is empty.
+    pub fn last_id(&self) -> u64 {
+        self.last_id
+    }
+
+    /// Appends a record. On error nothing of it is left in the log, so the
+    /// caller can reuse its id.
+    pub fn append(&mut self, record: &LogRecord) -> io::Result<()> {
+        if self.poisoned {
+            return Err(io::Error::other("event log needs a restart after a failed write"));
+        }
+        let body = serde_json::to_vec(record)?;
+        let len = HEADER_LEN as u64 + body.len() as u64;
+        if self.active.is_none() || (self.active_len > 0 && self.active_len + len > self.config.segment_bytes) {
+            self.rotate(record.id)?;
+        }
+        let mut frame = Vec::with_capacity(len as usize);
+        frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        frame.extend_from_slice(&body);
+
+        let file = self.active.as_mut().expect("rotate opens a segment");
+        let written = file.write_all(&frame).and_then(|()| match self.config.fsync {
+            FsyncPolicy::Always => file.sync_data(),
+            _ => Ok(()),
+        });
+        if let Err(e) = written {
+            // Cut off whatever part of the frame reached the file: left in
+            // place, it would hide every later record from the next start.
+            if let Err(rollback) = file.set_len(self.active_len) {
+                warn!("Failed to roll back event log after a failed append: {}", rollback);
+                self.poisoned = true;
+            }
+            self.dirty = true;
+            return Err(e);
+        }
+        self.active_len += len;
+        self.last_id = record.id;
+        self.dirty = self.config.fsync != FsyncPolicy::Always;
+        Ok(())
+    }
+
+    /// Flushes appended records to disk if anything was written since the
+    /// last sync.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let (true, Some(file)) = (self.dirty, self.active.as_ref()) {
+            file.sync_data()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self, first_id: u64) -> io::Result<()> {
+        self.sync()?;
+        let path = self.config.dir.join(format!("{:020}.{}", first_id, SEGMENT_EXTENSION));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // Make the new directory entry itself durable.
+        File::open(&self.config.dir)?.sync_all()?;
+        info!("Started event log segment {}", path.display());
+        self.segments.push(Segment { first_id, path });
+        self.active = Some(file);
+        self.active_len = 0;
+
+        if self.config.max_segments > 0 {
+            while self.segments.len() > self.config.max_segments {
+                let oldest = self.segments.remove(0);
+                info!("Removing expired event log segment {}", oldest.path.display());
+                fs::remove_file(&oldest.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with every record in the log, oldest first.
+    pub fn scan(&self, mut f: impl FnMut(LogRecord)) -> io::Result<()> {
+        for segment in &self.segments {
+            read_segment(&segment.path, &mut f)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the logged events matching `request` for a subscriber of
+    /// `channel`, oldest first.
+    pub fn replay(&self, channel: &str, request: ReplayRequest) -> io::Result<Vec<Event>> {
+        let since = request.since.unwrap_or(0);
+        let last = request.last.unwrap_or(usize::MAX);
+        // Skip whole segments whose successor starts at or before the first wanted id.
+        let start = self
+            .segments
+            .windows(2)
+            .take_while(|pair| pair[1].first_id <= since + 1)
+            .count();
+        // Newest segment first, so a `last` replay reads only the segments
+        // holding the events it returns; the caller holds the replay lock.
+        let mut events = VecDeque::new();
+        for segment in self.segments[start..].iter().rev() {
+            let wanted = last - events.len();
+            if wanted == 0 {
+                break;
+            }
+            let mut found = VecDeque::new();
+            read_segment(&segment.path, |record| {
+                if record.id > since && (channel == ALL_CHANNELS || record.channel == channel) {
+                    found.push_back(Event { id: record.id, payload: record.payload.into() });
+                    if found.len() > wanted {
+                        found.pop_front();
+                    }
+                }
+            })?;
+            found.into_iter().rev().for_each(|event| events.push_front(event));
+        }
+        Ok(events.into())
+    }
+}
+
+/// Reads the valid records of a segment, returning the length of the valid
+/// prefix and whether the whole file was valid.
+fn read_segment(path: &Path, mut f: impl FnMut(LogRecord)) -> io::Result<(u64, bool)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut offset = 0u64;
+    loop {
+        let mut header = [0u8; HEADER_LEN];
+        match read_full(&mut reader, &mut header)? {
+            0 => return Ok((offset, true)),
+            n if n < HEADER_LEN => return Ok((offset, false)),
+            _ => {}
+        }
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let mut body = vec![0u8; len];
+        if read_full(&mut reader, &mut body)? < len || crc32fast::hash(&body) != crc {
+            return Ok((offset, false));
+        }
+        match serde_json::from_slice(&body) {
+            Ok(record) => f(record),
+            Err(_) => return Ok((offset, false)),
+        }
+        offset += (HEADER_LEN + len) as u64;
+    }
+}
+
+/// Like `read_exact`, but reports how much was read instead of failing at EOF.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+/// Periodically syncs the log when it uses [`FsyncPolicy::Interval`].
+pub fn spawn_sync_task(log: Arc<Mutex<EventLog>>) {
+    let FsyncPolicy::Interval(period) = log.lock().unwrap().fsync_policy() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let log = log.clone();
+            match tokio::task::spawn_blocking(move || log.lock().unwrap().sync()).await {
+                Ok(Err(e)) => warn!("Failed to sync event log: {}", e),
+                Err(e) => warn!("Event log sync task failed: {}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(name: &str, segment_bytes: u64, max_segments: usize) -> EventLogConfig {
+        let dir = std::env::temp_dir().join(format!("nwebhook-eventlog-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        EventLogConfig { dir, fsync: FsyncPolicy::Always, segment_bytes, max_segments }
+    }
+
+    fn record(id: u64) -> LogRecord {
+        LogRecord { id, channel: "default".to_string(), payload: format!("{{\"id\":{}}}", id) }
+    }
+
+    fn ids(log: &EventLog) -> Vec<u64> {
+        let mut ids = Vec::new();
+        log.scan(|record| ids.push(record.id)).unwrap();
+        ids
+    }
+
+    #[test]
+    fn truncates_torn_tail_on_open() {
+        let config = config("torn", 1 << 20, 0);
+        let mut log = EventLog::open(config.clone()).unwrap();
+        (1..=3).for_each(|id| log.append(&record(id)).unwrap());
+        let path = log.segments.last().unwrap().path.clone();
+        let len = fs::metadata(&path).unwrap().len();
+        drop(log);
+
+        // Half a frame, as left by a crash mid-write.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let mut log = EventLog::open(config.clone()).unwrap();
+        assert_eq!(log.last_id(), 3);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        log.append(&record(4)).unwrap();
+        drop(log);
+
+        let log = EventLog::open(config.clone()).unwrap();
+        assert_eq!(ids(&log), [1, 2, 3, 4]);
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn failed_append_leaves_no_record_behind() {
+        let config = config("failed", 1 << 20, 0);
+        let mut log = EventLog::open(config.clone()).unwrap();
+        log.append(&record(1)).unwrap();
+        // A handle that cannot be written to or truncated.
+        let path = log.segments.last().unwrap().path.clone();
+        log.active = Some(File::open(&path).unwrap());
+        assert!(log.append(&record(2)).is_err());
+        assert!(log.append(&record(2)).is_err());
+        drop(log);
+
+        let mut log = EventLog::open(config.clone()).unwrap();
+        assert_eq!(log.last_id(), 1);
+        log.append(&record(2)).unwrap();
+        assert_eq!(ids(&log), [1, 2]);
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_and_expires_segments() {
+        // Room for two records per segment.
+        let frame = (HEADER_LEN + serde_json::to_vec(&record(1)).unwrap().len()) as u64;
+        let config = config("rotate", 2 * frame, 2);
+        let mut log = EventLog::open(config.clone()).unwrap();
+        (1..=7).for_each(|id| log.append(&record(id)).unwrap());
+        let first_ids: Vec<u64> = log.segments.iter().map(|segment| segment.first_id).collect();
+        assert_eq!(first_ids, [5, 7]);
+        assert_eq!(fs::read_dir(&config.dir).unwrap().count(), 2);
+        drop(log);
+
+        let log = EventLog::open(config.clone()).unwrap();
+        assert_eq!(log.last_id(), 7);
+        assert_eq!(ids(&log), [5, 6, 7]);
+        let replayed = log.replay("default", ReplayRequest { since: Some(5), last: None }).unwrap();
+        assert_eq!(replayed.iter().map(|event| event.id).collect::<Vec<_>>(), [6, 7]);
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn replays_the_last_events_across_segments() {
+        let frame = (HEADER_LEN + serde_json::to_vec(&record(1)).unwrap().len()) as u64;
+        let config = config("last", 2 * frame, 0);
+        let mut log = EventLog::open(config.clone()).unwrap();
+        for id in 1..=9 {
+            let channel = if id % 3 == 0 { "other" } else { "default" };
+            log.append(&LogRecord { channel: channel.to_string(), ..record(id) }).unwrap();
+        }
+        assert_eq!(log.segments.len(), 5);
+        let replay = |channel: &str, since: Option<u64>, last: Option<usize>| {
+            let events = log.replay(channel, ReplayRequest { since, last }).unwrap();
+            events.iter().map(|event| event.id).collect::<Vec<_>>()
+        };
+        assert_eq!(replay("default", None, Some(3)), [5, 7, 8]);
+        assert_eq!(replay("default", None, Some(1)), [8]);
+        assert!(replay("default", None, Some(0)).is_empty());
+        assert_eq!(replay("default", None, Some(100)), [1, 2, 4, 5, 7, 8]);
+        assert_eq!(replay("other", None, Some(2)), [6, 9]);
+        assert_eq!(replay(ALL_CHANNELS, None, Some(4)), [6, 7, 8, 9]);
+        assert_eq!(replay("default", Some(4), Some(5)), [5, 7, 8]);
+        assert_eq!(replay("default", Some(1), None), [2, 4, 5, 7, 8]);
+        // Deleting the oldest segments shows they were never read.
+        (0..3).for_each(|i| fs::remove_file(&log.segments[i].path).unwrap());
+        assert_eq!(replay("default", None, Some(2)), [7, 8]);
+        assert!(log.replay("default", ReplayRequest { since: None, last: Some(3) }).is_err());
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+}
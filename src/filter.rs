@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A predicate over the JSON of an event, as delivered to the client (the
+/// envelope, when enabled). Written in JSON, e.g.
+/// `{"all": [{"equals": {"path": "/headers/x-github-event", "value": "pull_request"}},
+///           {"equals": {"path": "/body/action", "value": "opened"}}]}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Filter {
+    /// The value at a JSON Pointer equals `value`.
+    Equals { path: String, value: Value },
+    /// The value at a JSON Pointer, as a string, matches a glob where `*`
+    /// matches any run of characters and `?` any single character.
+    Glob { path: String, pattern: String },
+    All(Vec<Filter>),
+    Any(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn matches(&self, event: &Value) -> bool {
+        match self {
+            Filter::Equals { path, value } => event.pointer(path) == Some(value),
+            Filter::Glob { path, pattern } => match event.pointer(path) {
+                Some(Value::String(s)) => glob_match(pattern, s),
+                Some(value @ (Value::Number(_) | Value::Bool(_))) => glob_match(pattern, &value.to_string()),
+                _ => false,
+            },
+            Filter::All(filters) => filters.iter().all(|filter| filter.matches(event)),
+            Filter::Any(filters) => filters.iter().any(|filter| filter.matches(event)),
+            Filter::Not(filter) => !filter.matches(event),
+        }
+    }
+
+    /// Checks that every path is a JSON Pointer, which `Value::pointer`
+    /// would otherwise silently treat as never matching.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Filter::Equals { path, .. } | Filter::Glob { path, .. } => {
+                if path.is_empty() || path.starts_with('/') {
+                    Ok(())
+                } else {
+                    Err(format!("invalid JSON Pointer {:?}", path))
+                }
+            }
+            Filter::All(filters) | Filter::Any(filters) => filters.iter().try_for_each(Filter::validate),
+            Filter::Not(filter) => filter.validate(),
+        }
+    }
+}
+
+/// Matches `text` against a `*`/`?` glob, backtracking only to the most
+/// recent `*`, so it runs in O(pattern × text) at worst.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filter(json: Value) -> Filter {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        for (pattern, text) in [
+            ("", ""),
+            ("*", ""),
+            ("*", "anything"),
+            ("pull_*", "pull_request"),
+            ("*_request", "pull_request"),
+            ("p?ll*st", "pull_request"),
+            ("a*b*c", "aXbYbZc"),
+            ("**a", "a"),
+            ("?", "é"),
+            ("*.*.*", "1.2.3"),
+        ] {
+            assert!(glob_match(pattern, text), "{:?} should match {:?}", pattern, text);
+        }
+        for (pattern, text) in [
+            ("", "a"),
+            ("?", ""),
+            ("pull", "pull_request"),
+            ("*_review", "pull_request"),
+            ("a*b*c", "aXbYbZ"),
+            ("??", "é"),
+            ("Pull*", "pull_request"),
+        ] {
+            assert!(!glob_match(pattern, text), "{:?} should not match {:?}", pattern, text);
+        }
+    }
+
+    #[test]
+    fn glob_backtracks_only_to_the_last_star() {
+        let text = "a".repeat(10_000);
+        assert!(!glob_match("*a*a*a*a*b", &text));
+        assert!(glob_match("*a*a*a*a*", &text));
+    }
+
+    #[test]
+    fn matches_by_json_pointer() {
+        let event = json!({
+            "headers": {"x-github-event": "pull_request"},
+            "body": {"action": "opened", "number": 42, "draft": false, "labels": ["bug"]},
+        });
+        let opened = filter(json!({"all": [
+            {"equals": {"path": "/headers/x-github-event", "value": "pull_request"}},
+            {"equals": {"path": "/body/action", "value": "opened"}},
+        ]}));
+        assert!(opened.matches(&event));
+        assert!(filter(json!({"equals": {"path": "/body/labels/0", "value": "bug"}})).matches(&event));
+        assert!(!filter(json!({"equals": {"path": "/body/number", "value": "42"}})).matches(&event));
+        assert!(!filter(json!({"equals": {"path": "/body/missing", "value": null}})).matches(&event));
+        // Globs see numbers and booleans as their JSON text, and never match
+        // objects, arrays, null or missing values.
+        assert!(filter(json!({"glob": {"path": "/body/number", "pattern": "4?"}})).matches(&event));
+        assert!(filter(json!({"glob": {"path": "/body/draft", "pattern": "f*"}})).matches(&event));
+        assert!(!filter(json!({"glob": {"path": "/body/labels", "pattern": "*"}})).matches(&event));
+        assert!(!filter(json!({"glob": {"path": "/body/missing", "pattern": "*"}})).matches(&event));
+    }
+
+    #[test]
+    fn combinators() {
+        let event = json!({"action": "closed"});
+        let opened = json!({"equals": {"path": "/action", "value": "opened"}});
+        let closed = json!({"equals": {"path": "/action", "value": "closed"}});
+        assert!(filter(json!({"any": [opened, closed]})).matches(&event));
+        assert!(!filter(json!({"all": [opened, closed]})).matches(&event));
+        assert!(filter(json!({"not": opened})).matches(&event));
+        assert!(filter(json!({"all": []})).matches(&event));
+        assert!(!filter(json!({"any": []})).matches(&event));
+    }
+
+    #[test]
+    fn validates_pointers_everywhere() {
+        assert!(filter(json!({"equals": {"path": "", "value": {}}})).validate().is_ok());
+        let nested = filter(json!({"not": {"any": [
+            {"glob": {"path": "/ok", "pattern": "*"}},
+            {"glob": {"path": "body.action", "pattern": "*"}},
+        ]}}));
+        assert_eq!(nested.validate(), Err("invalid JSON Pointer \"body.action\"".to_string()));
+        assert!(serde_json::from_value::<Filter>(json!({"equals": {"path": "/a", "value": 1, "extra": 2}})).is_err());
+    }
+}
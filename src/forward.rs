@@ -0,0 +1,518 @@
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use rand::Rng;
+use reqwest::header::{HeaderName, HeaderValue};
+use serde::Serialize;
+use warp::http::HeaderMap;
+use warp::hyper::body::Bytes;
+use log::{info, warn};
+use crate::deadletter::{DeadLetters, PayloadEncoding, Reason, Undeliverable};
+use crate::endpoints;
+use crate::envelope::CREDENTIAL_HEADERS;
+use crate::metrics::Metrics;
+
+/// Delivery attempts kept in memory for `GET /admin/deliveries`.
+const DELIVERY_HISTORY: usize = 1000;
+
+/// Request headers that describe the inbound connection rather than the
+/// webhook, and so are not copied onto forwarded requests.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "content-length",
+    "host",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[derive(Debug, Clone)]
+pub struct ForwardTarget {
+    pub name: String,
+    pub url: reqwest::Url,
+    /// Channels forwarded to this target; `None` forwards every channel but
+    /// those of runtime endpoints.
+    pub channels: Option<HashSet<String>>,
+    pub timeout: Duration,
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Relay the sender's [`CREDENTIAL_HEADERS`] too; they are meant for
+    /// nwebhook, not the target, so are stripped by default.
+    pub credentials: bool,
+}
+
+impl ForwardTarget {
+    fn accepts(&self, channel: &str) -> bool {
+        match &self.channels {
+            Some(channels) => channels.contains(channel),
+            // An endpoint's channel is named after its secret token, which
+            // only targets listing that channel may see.
+            None => !endpoints::is_endpoint_channel(channel),
+        }
+    }
+
+    /// Exponential backoff before retry number `attempt` (1-based), with
+    /// "equal jitter": half fixed, half random, so retries from many events
+    /// do not arrive in lockstep.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self.initial_backoff.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let capped = exponential.min(self.max_backoff);
+        let half = capped / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Delivered,
+    /// Failed, another attempt is scheduled.
+    Retrying,
+    /// Failed permanently, either with a non-retryable status or after the
+    /// last attempt.
+    Failed,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Delivered => "delivered",
+            Outcome::Retrying => "retrying",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+/// The result of one attempt to deliver an event to one target.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryAttempt {
+    pub event_id: u64,
+    pub target: String,
+    pub url: String,
+    pub attempt: u32,
+    /// Unix time in milliseconds at which the attempt started.
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub outcome: Outcome,
+}
+
+/// A webhook as received, to be replayed against downstream targets.
+#[derive(Debug, Clone)]
+pub struct Forward {
+    pub event_id: u64,
+    pub channel: String,
+    pub remote_addr: SocketAddr,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+#[derive(Clone)]
+pub struct Forwarder {
+    client: reqwest::Client,
+    targets: Arc<Vec<ForwardTarget>>,
+    deliveries: Arc<Mutex<VecDeque<DeliveryAttempt>>>,
+    dead_letters: DeadLetters,
+    metrics: Metrics,
+}
+
+impl Forwarder {
+    pub fn new(targets: Vec<ForwardTarget>, dead_letters: DeadLetters, metrics: Metrics) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            targets: Arc::new(targets),
+            deliveries: Arc::new(Mutex::new(VecDeque::new())),
+            dead_letters,
+            metrics,
+        }
+    }
+
+    /// Starts delivering `forward` to every target subscribed to its channel
+    /// in the background; the webhook response does not wait for them.
+    pub fn forward(&self, forward: Forward) {
+        let forward = Arc::new(forward);
+        for target in self.targets.iter().filter(|target| target.accepts(&forward.channel)) {
+            let forwarder = self.clone();
+            let target = target.clone();
+            let forward = forward.clone();
+            tokio::spawn(async move { forwarder.deliver(&target, &forward).await });
+        }
+    }
+
+    /// Starts delivering `forward` again to the target named `target`, with
+    /// a fresh set of attempts. Returns false if there is no such target.
+    pub fn redeliver(&self, target: &str, forward: Arc<Forward>) -> bool {
+        let Some(target) = self.targets.iter().find(|candidate| candidate.name == target).cloned() else {
+            return false;
+        };
+        let forwarder = self.clone();
+        tokio::spawn(async move { forwarder.deliver(&target, &forward).await });
+        true
+    }
+
+    /// The most recent delivery attempts, newest first.
+    pub fn deliveries(&self) -> Vec<DeliveryAttempt> {
+        self.deliveries.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    async fn deliver(&self, target: &ForwardTarget, forward: &Arc<Forward>) {
+        for attempt in 1..=target.max_attempts {
+            let started_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+            let start = Instant::now();
+            let result = self.send(target, forward).await;
+            let (status, error, retryable) = match result {
+                Ok(status) if status.is_success() => (Some(status.as_u16()), None, false),
+                Ok(status) => {
+                    let retryable = status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                    (Some(status.as_u16()), None, retryable)
+                }
+                Err(e) => (None, Some(e.to_string()), true),
+            };
+            let outcome = match (status, retryable) {
+                (Some(code), _) if (200..300).contains(&code) => Outcome::Delivered,
+                (_, true) if attempt < target.max_attempts => Outcome::Retrying,
+                _ => Outcome::Failed,
+            };
+            let detail = error.clone().unwrap_or_else(|| format!("status {}", status.unwrap_or_default()));
+            self.record(DeliveryAttempt {
+                event_id: forward.event_id,
+                target: target.name.clone(),
+                url: target.url.to_string(),
+                attempt,
+                started_at,
+                duration_ms: start.elapsed().as_millis() as u64,
+                status,
+                error,
+                outcome,
+            });
+            if outcome == Outcome::Failed {
+                // Bodies need not be text; keep the exact bytes for redelivery.
+                let (payload, payload_encoding) = PayloadEncoding::encode(&forward.body);
+                self.dead_letters.push(Undeliverable {
+                    reason: Reason::ForwardFailed,
+                    detail: format!("{} on attempt {}", detail, attempt),
+                    event_id: Some(forward.event_id),
+                    channel: Some(forward.channel.clone()),
+                    client: None,
+                    target: Some(target.name.clone()),
+                    payload,
+                    payload_encoding,
+                    forward: Some(forward.clone()),
+                });
+            }
+            if outcome != Outcome::Retrying {
+                return;
+            }
+            tokio::time::sleep(target.backoff(attempt)).await;
+        }
+    }
+
+    async fn send(&self, target: &ForwardTarget, forward: &Forward) -> Result<reqwest::StatusCode, reqwest::Error> {
+        let mut request = self.client.post(target.url.clone()).timeout(target.timeout).body(forward.body.clone());
+        // Proxies in front of nwebhook are listed ahead of the sender's own
+        // address, in one header as the target would get through any proxy.
+        let mut forwarded_for: Vec<&str> = forward
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|hop| !hop.is_empty())
+            .collect();
+        let remote_ip = forward.remote_addr.ip().to_string();
+        forwarded_for.push(&remote_ip);
+        for (name, value) in forward.headers.iter() {
+            let name_str = name.as_str();
+            if HOP_BY_HOP_HEADERS.contains(&name_str) || name_str == "x-forwarded-for" {
+                continue;
+            }
+            if !target.credentials && CREDENTIAL_HEADERS.contains(&name_str) {
+                continue;
+            }
+            // warp and reqwest use different versions of the `http` crate.
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_ref()), HeaderValue::from_bytes(value.as_bytes())) {
+                request = request.header(name, value);
+            }
+        }
+        let response = request
+            .header("x-forwarded-for", forwarded_for.join(", "))
+            .header("x-nwebhook-event-id", forward.event_id.to_string())
+            .header("x-nwebhook-channel", forward.channel.as_str())
+            .send()
+            .await?;
+        Ok(response.status())
+    }
+
+    fn record(&self, attempt: DeliveryAttempt) {
+        self.metrics.forward(&attempt.target, attempt.outcome.as_str());
+        match attempt.outcome {
+            Outcome::Delivered => info!(
+                "Forwarded event {} to {} (attempt {}, status {})",
+                attempt.event_id,
+                attempt.target,
+                attempt.attempt,
+                attempt.status.unwrap_or_default()
+            ),
+            _ => warn!(
+                "Forwarding event {} to {} failed (attempt {}, {:?}): {}",
+                attempt.event_id,
+                attempt.target,
+                attempt.attempt,
+                attempt.outcome,
+                attempt
+                    .error
+                    .clone()
+                    .unwrap_or_else(|| format!("status {}", attempt.status.unwrap_or_default()))
+            ),
+        }
+        let mut deliveries = self.deliveries.lock().unwrap();
+        if deliveries.len() == DELIVERY_HISTORY {
+            deliveries.pop_front();
+        }
+        deliveries.push_back(attempt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use warp::Filter;
+    use crate::server::WebSocketServer;
+
+    /// A request as the downstream target saw it.
+    struct Seen {
+        headers: HeaderMap,
+        body: Bytes,
+        at: Instant,
+    }
+
+    /// Serves a target answering its nth request with `responses[n]`, or the
+    /// last one after that: a status, after a delay.
+    async fn serve(responses: Vec<(u16, Duration)>) -> (reqwest::Url, Arc<Mutex<Vec<Seen>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let count = Arc::new(AtomicUsize::new(0));
+        let responses = Arc::new(responses);
+        let route = warp::any().and(warp::header::headers_cloned()).and(warp::body::bytes()).then({
+            let seen = seen.clone();
+            move |headers: HeaderMap, body: Bytes| {
+                let (seen, count, responses) = (seen.clone(), count.clone(), responses.clone());
+                async move {
+                    seen.lock().unwrap().push(Seen { headers, body, at: Instant::now() });
+                    let n = count.fetch_add(1, Ordering::SeqCst);
+                    let (status, delay) = responses[n.min(responses.len() - 1)];
+                    tokio::time::sleep(delay).await;
+                    warp::reply::with_status("", warp::http::StatusCode::from_u16(status).unwrap())
+                }
+            }
+        });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}/hook", addr).parse().unwrap(), seen)
+    }
+
+    fn target(url: reqwest::Url, max_attempts: u32) -> ForwardTarget {
+        ForwardTarget {
+            name: "downstream".to_string(),
+            url,
+            channels: None,
+            timeout: Duration::from_millis(200),
+            max_attempts,
+            initial_backoff: Duration::from_millis(40),
+            max_backoff: Duration::from_millis(60),
+            credentials: false,
+        }
+    }
+
+    fn forward(body: &'static [u8]) -> Forward {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json".parse().unwrap());
+        headers.insert("x-github-event", "push".parse().unwrap());
+        headers.insert("host", "nwebhook.example".parse().unwrap());
+        headers.insert("connection", "close".parse().unwrap());
+        Forward {
+            event_id: 42,
+            channel: "github".to_string(),
+            remote_addr: "192.0.2.7:5000".parse().unwrap(),
+            headers,
+            body: Bytes::from_static(body),
+        }
+    }
+
+    /// Forwards one event and waits for its last attempt; returns the
+    /// outcomes of the attempts, oldest first.
+    async fn run(forwarder: &Forwarder, forward: Forward) -> Vec<Outcome> {
+        forwarder.forward(forward);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let deliveries = forwarder.deliveries();
+            if deliveries.first().is_some_and(|last| last.outcome != Outcome::Retrying) {
+                return deliveries.iter().rev().map(|attempt| attempt.outcome).collect();
+            }
+            assert!(Instant::now() < deadline, "forwarding did not finish");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn forwarder(target: ForwardTarget) -> (Forwarder, DeadLetters) {
+        let dead_letters = DeadLetters::new(10);
+        (Forwarder::new(vec![target], dead_letters.clone(), Metrics::new()), dead_letters)
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_with_backoff() {
+        let ok = Duration::ZERO;
+        let (url, seen) = serve(vec![(503, ok), (500, ok), (200, ok)]).await;
+        let (forwarder, dead_letters) = forwarder(target(url, 5));
+        assert_eq!(run(&forwarder, forward(b"{}")).await, [Outcome::Retrying, Outcome::Retrying, Outcome::Delivered]);
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 3);
+        // Equal jitter waits at least half the backoff: 40ms, then 60ms capped.
+        assert!(seen[1].at - seen[0].at >= Duration::from_millis(20));
+        assert!(seen[2].at - seen[1].at >= Duration::from_millis(30));
+        assert!(dead_letters.list(None, None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn retries_timeouts() {
+        let (url, seen) = serve(vec![(200, Duration::from_secs(1)), (200, Duration::ZERO)]).await;
+        let (forwarder, _) = forwarder(target(url, 3));
+        assert_eq!(run(&forwarder, forward(b"{}")).await, [Outcome::Retrying, Outcome::Delivered]);
+        assert!(forwarder.deliveries()[1].error.is_some());
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let (url, seen) = serve(vec![(404, Duration::ZERO), (200, Duration::ZERO)]).await;
+        let (forwarder, dead_letters) = forwarder(target(url, 5));
+        assert_eq!(run(&forwarder, forward(b"{}")).await, [Outcome::Failed]);
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        let letters = dead_letters.list(Some(Reason::ForwardFailed), None);
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].message.detail, "status 404 on attempt 1");
+    }
+
+    #[tokio::test]
+    async fn dead_letters_after_last_attempt() {
+        let (url, seen) = serve(vec![(502, Duration::ZERO)]).await;
+        let (forwarder, dead_letters) = forwarder(target(url, 3));
+        assert_eq!(run(&forwarder, forward(b"{\"a\":1}")).await, [Outcome::Retrying, Outcome::Retrying, Outcome::Failed]);
+        assert_eq!(seen.lock().unwrap().len(), 3);
+        let letters = dead_letters.list(None, Some("github"));
+        assert_eq!(letters.len(), 1);
+        let letter = &letters[0].message;
+        assert_eq!(letter.reason, Reason::ForwardFailed);
+        assert_eq!(letter.detail, "status 502 on attempt 3");
+        assert_eq!((letter.event_id, letter.target.as_deref()), (Some(42), Some("downstream")));
+        assert_eq!(letter.payload, "{\"a\":1}");
+        assert_eq!(letter.payload_encoding, PayloadEncoding::Text);
+    }
+
+    #[tokio::test]
+    async fn dead_letters_binary_bodies_exactly() {
+        let (url, _) = serve(vec![(400, Duration::ZERO)]).await;
+        let (forwarder, dead_letters) = forwarder(target(url, 1));
+        let body = b"\x89PNG\r\n\x1a\n\xff\x00";
+        assert_eq!(run(&forwarder, forward(body)).await, [Outcome::Failed]);
+        let letter = dead_letters.list(None, None).remove(0).message;
+        assert_eq!(letter.payload_encoding, PayloadEncoding::Base64);
+        assert_eq!(letter.payload_encoding.decode(&letter.payload).as_deref(), Some(&body[..]));
+    }
+
+    #[tokio::test]
+    async fn preserves_headers_and_adds_its_own() {
+        let (url, seen) = serve(vec![(204, Duration::ZERO)]).await;
+        let (forwarder, _) = forwarder(target(url.clone(), 1));
+        assert_eq!(run(&forwarder, forward(b"{\"a\":1}")).await, [Outcome::Delivered]);
+        let seen = seen.lock().unwrap();
+        let (headers, body) = (&seen[0].headers, &seen[0].body);
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or_default().to_string();
+        assert_eq!(body.as_ref(), b"{\"a\":1}");
+        assert_eq!(header("content-type"), "application/json");
+        assert_eq!(header("x-github-event"), "push");
+        assert_eq!(header("x-forwarded-for"), "192.0.2.7");
+        assert_eq!(header("x-nwebhook-event-id"), "42");
+        assert_eq!(header("x-nwebhook-channel"), "github");
+        // Hop-by-hop headers describe the inbound connection, not the webhook.
+        assert_eq!(header("host"), format!("127.0.0.1:{}", url.port().unwrap()));
+        assert_ne!(header("connection"), "close");
+    }
+
+    #[tokio::test]
+    async fn strips_credentials_unless_the_target_wants_them() {
+        let mut forward = forward(b"{}");
+        forward.headers.insert("authorization", "Bearer nwebhook-token".parse().unwrap());
+        forward.headers.insert("x-api-key", "nwebhook-key".parse().unwrap());
+        forward.headers.insert("cookie", "session=1".parse().unwrap());
+        for credentials in [false, true] {
+            let (url, seen) = serve(vec![(204, Duration::ZERO)]).await;
+            let (forwarder, _) = forwarder(ForwardTarget { credentials, ..target(url, 1) });
+            assert_eq!(run(&forwarder, forward.clone()).await, [Outcome::Delivered]);
+            let headers = &seen.lock().unwrap()[0].headers;
+            for name in ["authorization", "x-api-key", "cookie"] {
+                assert_eq!(headers.contains_key(name), credentials, "{}", name);
+            }
+            assert_eq!(headers["x-github-event"], "push");
+        }
+    }
+
+    #[tokio::test]
+    async fn appends_the_sender_to_x_forwarded_for() {
+        let (url, seen) = serve(vec![(204, Duration::ZERO)]).await;
+        let (forwarder, _) = forwarder(target(url, 1));
+        let mut forward = forward(b"{}");
+        forward.headers.append("x-forwarded-for", "203.0.113.1, 198.51.100.2".parse().unwrap());
+        forward.headers.append("x-forwarded-for", "198.51.100.3".parse().unwrap());
+        assert_eq!(run(&forwarder, forward).await, [Outcome::Delivered]);
+        let headers = &seen.lock().unwrap()[0].headers;
+        let forwarded_for: Vec<_> = headers.get_all("x-forwarded-for").iter().collect();
+        assert_eq!(forwarded_for, ["203.0.113.1, 198.51.100.2, 198.51.100.3, 192.0.2.7"]);
+    }
+
+    #[tokio::test]
+    async fn redelivers_dead_letters_to_their_target() {
+        let (url, seen) = serve(vec![(500, Duration::ZERO), (200, Duration::ZERO)]).await;
+        let (forwarder, dead_letters) = forwarder(target(url, 1));
+        let ws_server = WebSocketServer { dead_letters: dead_letters.clone(), ..WebSocketServer::for_tests() };
+        assert_eq!(run(&forwarder, forward(b"{\"a\":1}")).await, [Outcome::Failed]);
+        let id = dead_letters.list(None, None)[0].id;
+
+        let mut auth = crate::auth::Auth::default();
+        auth.set_anonymous_admin(true);
+        let (headers, query, body, auth) = (HeaderMap::new(), Default::default(), Bytes::new(), Arc::new(auth));
+        let redeliver = crate::admin::handle_redeliver(id, headers, query, body, auth, ws_server, forwarder.clone());
+        assert_eq!(redeliver.await.unwrap().status(), warp::http::StatusCode::ACCEPTED);
+        assert!(dead_letters.get(id).is_none());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while forwarder.deliveries()[0].outcome != Outcome::Delivered {
+            assert!(Instant::now() < deadline, "redelivery did not finish");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // The target gets the webhook again as it was first forwarded.
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[1].body, seen[0].body);
+        assert_eq!(seen[1].headers["x-github-event"], "push");
+        assert_eq!(seen[1].headers["x-nwebhook-event-id"], "42");
+        assert!(dead_letters.list(None, None).is_empty());
+    }
+
+    #[test]
+    fn catch_all_targets_skip_endpoint_channels() {
+        let mut target = target("http://127.0.0.1/".parse().unwrap(), 1);
+        assert!(target.accepts("github"));
+        assert!(!target.accepts("hook.secret"));
+        target.channels = Some(HashSet::from(["hook.secret".to_string()]));
+        assert!(target.accepts("hook.secret"));
+        assert!(!target.accepts("github"));
+    }
+}
@@ -0,0 +1,101 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{service_fn, Service};
+use warp::hyper::{Body, Request, Response};
+use warp::Filter;
+use log::{debug, warn};
+use crate::shutdown::Shutdown;
+use crate::tls::ReloadableAcceptor;
+
+/// Per-connection facts handlers cannot get from warp when we run the accept
+/// loop ourselves; inserted into every request's extensions.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub remote_addr: SocketAddr,
+    /// The client presented a certificate that chains to `tls.client_ca`.
+    pub client_cert_verified: bool,
+}
+
+/// Extracts the [`ConnectionInfo`] of the current request.
+pub fn connection_info() -> impl Filter<Extract = (ConnectionInfo,), Error = warp::Rejection> + Clone {
+    warp::ext::get::<ConnectionInfo>()
+}
+
+/// Accepts connections on `listener` until shutdown begins, terminating TLS
+/// when an acceptor is given, and serves `service` on each of them.
+pub async fn serve<S>(listener: TcpListener, tls: Option<ReloadableAcceptor>, service: S, shutdown: Shutdown)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.draining() => return,
+        };
+        let (stream, remote_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let service = service.clone();
+        let tls = tls.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            match tls {
+                Some(tls) => match tls.acceptor().accept(stream).await {
+                    Ok(stream) => {
+                        let client_cert_verified = stream.get_ref().1.peer_certificates().is_some();
+                        let info = ConnectionInfo { remote_addr, client_cert_verified };
+                        serve_connection(stream, info, service, shutdown).await
+                    }
+                    Err(e) => debug!("TLS handshake with {} failed: {}", remote_addr, e),
+                },
+                None => {
+                    let info = ConnectionInfo { remote_addr, client_cert_verified: false };
+                    serve_connection(stream, info, service, shutdown).await
+                }
+            }
+        });
+    }
+}
+
+/// Serves one connection; once shutdown begins it finishes the request in
+/// progress, if any, and closes instead of waiting for the next one.
+async fn serve_connection<IO, S>(io: IO, info: ConnectionInfo, service: S, shutdown: Shutdown)
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let remote_addr = info.remote_addr;
+    let requests = shutdown.clone();
+    let service = service_fn(move |mut req: Request<Body>| {
+        req.extensions_mut().insert(info.clone());
+        let in_flight = requests.request();
+        let response = service.clone().call(req);
+        async move {
+            let response = response.await;
+            drop(in_flight);
+            response
+        }
+    });
+    // Upgrades must stay enabled for WebSocket handshakes to complete.
+    let connection = Http::new().serve_connection(io, service).with_upgrades();
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = &mut connection => result,
+        _ = shutdown.draining() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(e) = result {
+        debug!("Connection from {} ended with an error: {}", remote_addr, e);
+    }
+}
@@ -1,143 +1,819 @@
+mod admin;
+mod auth;
+mod backplane;
+mod body;
+mod bridge;
+mod config;
+mod deadletter;
+mod endpoints;
+mod envelope;
+mod eventlog;
+mod filter;
+mod forward;
+mod listener;
+mod metrics;
+mod payload;
+mod protocol;
+mod queue;
+mod ratelimit;
+mod registry;
+mod replay;
+mod server;
+mod shutdown;
+mod signature;
+mod sse;
+mod subscription;
+mod tls;
+mod ui;
+
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
-use tokio_tungstenite::tungstenite::Message;
-use warp::Filter;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use warp::filters::BoxedFilter;
+use warp::{Filter, Reply};
 use futures_util::stream::StreamExt;
 use futures_util::SinkExt;
-use serde::Serialize;
-use serde_json::Value;
+use warp::http::{HeaderMap, StatusCode};
+use warp::hyper::body::Bytes;
 use log::{info, warn, error};
+use auth::{Auth, TokenSource};
+use config::{Cli, Config};
+use deadletter::{DeadLetters, Reason};
+use endpoints::Endpoints;
+use envelope::{Envelope, EnvelopeSettings, Received};
+use eventlog::EventLog;
+use forward::{Forward, Forwarder};
+use protocol::ErrorCode;
+use ratelimit::{ConnectionPermit, RateLimited, RateLimiter};
+use listener::ConnectionInfo;
+use metrics::Metrics;
+use replay::ReplayBuffer;
+use server::{Backpressure, Delivery, Heartbeat, Outbound, WebSocketServer, DEFAULT_CHANNEL};
+use shutdown::Shutdown;
+use signature::Verifiers;
+use subscription::Subscription;
+use tls::ReloadableAcceptor;
+use tokio::net::TcpListener;
 
-#[derive(Debug, Clone)]
-struct User {
-    id: usize,
-    tx: mpsc::UnboundedSender<Message>,
-}
+/// How long a client gets to answer a close frame from the server before its
+/// connection is dropped.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
-#[derive(Clone)]
-struct WebSocketServer {
-    users: Arc<Mutex<HashMap<usize, User>>>,
-    next_id: Arc<Mutex<usize>>,
+#[allow(clippy::too_many_arguments)]
+async fn handle_webhook(
+    channel: Option<String>,
+    received: Received,
+    headers: HeaderMap,
+    body: Bytes,
+    ws_server: WebSocketServer,
+    verifiers: Arc<Verifiers>,
+    forwarder: Forwarder,
+    envelopes: Arc<EnvelopeSettings>,
+    limiter: RateLimiter,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    // Labelled with the route rather than the path, so that senders cannot
+    // grow the metrics without bound with new channel names.
+    let path = received.path.trim_end_matches('/');
+    let route = match &channel {
+        Some(_) => format!("{}/{{channel}}", path.rsplit_once('/').map_or("", |(prefix, _)| prefix)),
+        None => path.to_string(),
+    };
+    let channel = channel.unwrap_or_else(|| DEFAULT_CHANNEL.to_string());
+    let metrics = ws_server.metrics.clone();
+    let response = async move {
+        if !server::is_valid_channel(&channel) {
+            warn!("Rejected webhook for invalid channel {:?}", channel);
+            return warp::reply::with_status("Invalid channel name", StatusCode::BAD_REQUEST).into_response();
+        }
+        if endpoints::is_endpoint_channel(&channel) {
+            warn!("Rejected webhook for endpoint channel {}", channel);
+            return warp::reply::with_status("Channel is reserved for endpoints", StatusCode::FORBIDDEN).into_response();
+        }
+        // Signatures are computed over the exact bytes sent, so verify before parsing.
+        if verifiers.verify(&channel, &headers, &body).is_err() {
+            return warp::reply::with_status("Invalid signature", StatusCode::UNAUTHORIZED).into_response();
+        }
+        deliver(channel, received, headers, body, ws_server, forwarder, envelopes, limiter).await
+    }
+    .await;
+    metrics.webhook(&route, response.status());
+    Ok(response)
 }
 
-impl WebSocketServer {
-    fn new() -> Self {
-        Self {
-            users: Arc::new(Mutex::new(HashMap::new())),
-            next_id: Arc::new(Mutex::new(0)),
+/// `POST /hook/{token}`: a webhook to an endpoint created through
+/// `/api/endpoints`, published on that endpoint's channel only.
+#[allow(clippy::too_many_arguments)]
+async fn handle_hook(
+    token: String,
+    received: Received,
+    headers: HeaderMap,
+    body: Bytes,
+    ws_server: WebSocketServer,
+    endpoints: Endpoints,
+    forwarder: Forwarder,
+    envelopes: Arc<EnvelopeSettings>,
+    limiter: RateLimiter,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    // Tokens are left out of the labels: there are many, and they are secret.
+    let route = match received.path.strip_suffix(token.as_str()) {
+        Some(prefix) => format!("{}{{token}}", prefix),
+        None => "invalid".to_string(),
+    };
+    let metrics = ws_server.metrics.clone();
+    let response = async move {
+        let Some(endpoint) = endpoints.get(&token) else {
+            return warp::reply::with_status("No such endpoint", StatusCode::NOT_FOUND).into_response();
+        };
+        if endpoints.verify(&endpoint, &headers, &body).is_err() {
+            return warp::reply::with_status("Invalid signature", StatusCode::UNAUTHORIZED).into_response();
         }
+        deliver(endpoint.channel, received, headers, body, ws_server, forwarder, envelopes, limiter).await
     }
+    .await;
+    metrics.webhook(&route, response.status());
+    Ok(response)
+}
 
-    async fn broadcast(&self, message: impl Serialize) -> Result<(), Box<dyn std::error::Error>> {
-        info!("Starting broadcast");
-        let json = serde_json::to_string(&message)?;
-        info!("Acquiring users lock");
-        let users = self.users.lock().await;
-        info!("Broadcasting to {} users", users.len());
-        for user in users.values() {
-            info!("Sending message to user {}", user.id);
-            if user.tx.send(Message::Text(json.clone())).is_err() {
-                warn!("Failed to send message to user {}", user.id);
+/// Publishes a verified webhook on `channel` and queues it for forwarding.
+/// If a client answers the channel's webhooks, its answer is the response.
+#[allow(clippy::too_many_arguments)]
+async fn deliver(
+    channel: String,
+    received: Received,
+    headers: HeaderMap,
+    body: Bytes,
+    ws_server: WebSocketServer,
+    forwarder: Forwarder,
+    envelopes: Arc<EnvelopeSettings>,
+    limiter: RateLimiter,
+) -> warp::reply::Response {
+    if let Err(limited) = limiter.check_channel(received.remote_addr.ip(), &channel) {
+        return limited.response();
+    }
+    let payload = match payload::decode(&headers, &body) {
+        Ok((format, payload)) => {
+            if format != payload::Format::Json {
+                info!("Decoded {:?} webhook body on channel {}", format, channel);
             }
-            info!("Message sent to user {}", user.id);
+            payload
         }
-        info!("Broadcast completed");
-        Ok(())
+        Err(e) => {
+            warn!("Rejected webhook with invalid JSON on channel {}: {}", channel, e);
+            return warp::reply::with_status("Invalid JSON body", StatusCode::BAD_REQUEST).into_response();
+        }
+    };
+    let bridged = ws_server.bridge.responder(&channel).map(|responder| (responder, payload.clone()));
+    // Scoped so the publish error, which is not `Send`, is not held across
+    // the wait for a responder.
+    let event_id = {
+        let published = if envelopes.enabled {
+            ws_server
+                .publish_with(&channel, |id| {
+                    serde_json::to_string(&Envelope::new(id, &ws_server.node.id, &channel, &received, &headers, &payload, &envelopes))
+                })
+                .await
+        } else {
+            ws_server.publish(&channel, payload).await
+        };
+        match published {
+            Ok(event_id) => event_id,
+            Err(e) if e.is::<Backpressure>() => {
+                let reply = warp::reply::with_status("Subscribers are falling behind, retry later", StatusCode::SERVICE_UNAVAILABLE);
+                return warp::reply::with_header(reply, "retry-after", "1").into_response();
+            }
+            Err(e) => {
+                error!("Error broadcasting message: {}", e);
+                return warp::reply::with_status("Error broadcasting message", StatusCode::INTERNAL_SERVER_ERROR).into_response();
+            }
+        }
+    };
+    // The responder always gets the full request, envelopes or not.
+    let bridged = bridged.map(|(responder, payload)| {
+        let event = Envelope::new(event_id, &ws_server.node.id, &channel, &received, &headers, &payload, &envelopes);
+        (responder, serde_json::to_value(event).unwrap_or_default())
+    });
+    forwarder.forward(Forward { event_id, channel, remote_addr: received.remote_addr, headers, body });
+    if let Some((responder, event)) = bridged {
+        return ws_server.bridge.exchange(&ws_server, responder, event).await;
+    }
+    let reply = warp::reply::with_status("Message broadcasted", StatusCode::OK);
+    warp::reply::with_header(reply, "x-event-id", event_id.to_string()).into_response()
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_upgrade(
+    channel: Option<String>,
+    info: ConnectionInfo,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    ws: warp::ws::Ws,
+    permit: ConnectionPermit,
+    ws_server: WebSocketServer,
+    auth: Arc<Auth>,
+    endpoints: Endpoints,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let subscription = match Subscription::from_request(channel, info.remote_addr, &headers, &query, &auth, &endpoints) {
+        Ok(subscription) => subscription,
+        Err((body, status)) => {
+            ws_server.metrics.connection("websocket", status);
+            return Ok(warp::reply::with_status(body, status).into_response());
+        }
+    };
+    ws_server.metrics.connection("websocket", StatusCode::SWITCHING_PROTOCOLS);
+    let source = subscription.token_source;
+    let reply = ws.on_upgrade(move |socket| async move {
+        // Counts against the source IP's open connections until this one ends.
+        let _permit = permit;
+        handle_connection(socket, ws_server, subscription).await
+    });
+    if source == Some(TokenSource::Protocol) {
+        return Ok(warp::reply::with_header(reply, "sec-websocket-protocol", auth::BEARER_PROTOCOL).into_response());
     }
+    Ok(reply.into_response())
+}
 
-    async fn send_to(&self, user_id: usize, message: impl Serialize) -> Result<(), Box<dyn std::error::Error>> {
-        info!("Sending message to user {}", user_id);
-        let json = serde_json::to_string(&message)?;
-        info!("Acquiring users lock");
-        let users = self.users.lock().await;
-        if let Some(user) = users.get(&user_id) {
-            info!("Sending message to user {}", user_id);
-            if user.tx.send(Message::Text(json)).is_err() {
-                warn!("Failed to send message to user {}", user_id);
+async fn handle_connection(socket: warp::ws::WebSocket, ws_server: WebSocketServer, subscription: Subscription) {
+    let (id, mut rx) = ws_server.register(&subscription).await;
+    info!(
+        "New WebSocket connection: {} from {} (channel {}, principal {})",
+        id,
+        subscription.remote_addr,
+        subscription.channel,
+        subscription.principal.as_deref().unwrap_or("anonymous")
+    );
+    let (mut ws_sender, mut ws_receiver) = socket.split();
+
+    let receiver_server = ws_server.clone();
+    let receiver_subscription = subscription.clone();
+    let mut receiver = tokio::spawn(async move {
+        let (ws_server, subscription) = (receiver_server, receiver_subscription);
+        while let Some(Ok(msg)) = ws_receiver.next().await {
+            if msg.is_pong() {
+                ws_server.pong(id).await;
+                continue;
+            }
+            // Everything a client sends is a control message; see `protocol`.
+            let reply = if let Ok(text) = msg.to_str() {
+                protocol::handle(&ws_server, id, &subscription, text).await
+            } else if msg.is_binary() {
+                protocol::Reply::error(None, ErrorCode::InvalidMessage, "binary messages are not supported")
+            } else {
+                continue;
+            };
+            match serde_json::to_string(&reply) {
+                Ok(reply) => {
+                    let _ = ws_server.try_send_to(id, Delivery::text(None, reply)).await;
+                }
+                Err(e) => error!("Failed to serialize reply to user {}: {}", id, e),
             }
-            info!("Message sent to user {}", user_id);
-        } else {
-            warn!("User {} not found", user_id);
         }
-        Ok(())
+        info!("WebSocket connection closed: {}", id);
+        ws_server.unregister(id).await;
+    });
+
+    let mut heartbeat = ws_server.heartbeat.map(|heartbeat| {
+        let interval = tokio::time::interval_at(tokio::time::Instant::now() + heartbeat.interval, heartbeat.interval);
+        (heartbeat, interval)
+    });
+    let (mut last_ping, mut missed) = (None, 0);
+    // The server ends the connection by breaking out with the close frame to
+    // send and how to dead-letter whatever is still queued.
+    let (code, reason, undelivered, detail) = loop {
+        let delivery = tokio::select! {
+            delivery = rx.recv() => match delivery {
+                Some(delivery) => delivery,
+                None if rx.overflowed() => {
+                    // The `disconnect` policy gave up on this client; tell it
+                    // why so it can reconnect and replay from its last
+                    // acknowledged event.
+                    ws_server.metrics.eviction("slow_consumer");
+                    let detail = "queue overflowed, client disconnected".to_string();
+                    break (1008, "slow consumer".to_string(), Reason::QueueOverflow, detail);
+                }
+                // Unregistered once the receiving half saw the client leave.
+                None => return,
+            },
+            Some(heartbeat) = next_heartbeat(&mut heartbeat) => {
+                let answered = match (last_ping, ws_server.last_pong(id).await) {
+                    (Some(ping), Some(pong)) => pong >= ping,
+                    (Some(_), None) => false,
+                    (None, _) => true,
+                };
+                missed = if answered { 0 } else { missed + 1 };
+                if missed < heartbeat.max_missed {
+                    // A failed ping means the connection is gone, which the
+                    // receiving half notices on its own.
+                    last_ping = Some(Instant::now());
+                    let _ = ws_sender.send(warp::ws::Message::ping(Vec::new())).await;
+                    continue;
+                }
+                warn!("Evicting user {}: {} heartbeats missed", id, missed);
+                ws_server.metrics.eviction("missed_heartbeats");
+                let detail = format!("evicted after missing {} heartbeats", missed);
+                break (4408, "missed heartbeats".to_string(), Reason::HeartbeatTimeout, detail);
+            }
+        };
+        let text = match &delivery.message {
+            Outbound::Text(text) => text,
+            Outbound::Close { code, reason, undelivered } => {
+                break (*code, reason.clone(), *undelivered, format!("connection closed by the server: {}", reason));
+            }
+        };
+        let bytes = text.len();
+        // The frame needs its own copy, made here rather than while fanning out.
+        if ws_sender.send(warp::ws::Message::text(text.as_ref())).await.is_err() {
+            // Whatever is still queued will never be written either.
+            rx.close();
+            let undelivered = std::iter::once(delivery).chain(std::iter::from_fn(|| rx.try_recv()));
+            for delivery in undelivered {
+                let detail = format!("connection from {} closed", subscription.remote_addr);
+                ws_server.dead_letter(id, &subscription.channel, delivery, Reason::ClientClosed, detail);
+            }
+            return;
+        }
+        ws_server.metrics.bytes_sent("websocket", bytes);
+    };
+    // The peer may be unreachable, so wait neither forever on the close frame
+    // nor for it to complete the closing handshake.
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, ws_sender.send(warp::ws::Message::close_with(code, reason))).await;
+    rx.close();
+    while let Some(delivery) = rx.try_recv() {
+        ws_server.dead_letter(id, &subscription.channel, delivery, undelivered, detail.clone());
+    }
+    if tokio::time::timeout(CLOSE_TIMEOUT, &mut receiver).await.is_err() {
+        receiver.abort();
+        ws_server.unregister(id).await;
     }
 }
 
-async fn handle_webhook(body: Value, ws_server: WebSocketServer) -> Result<impl warp::Reply, warp::Rejection> {
-    // Properly handle the Result returned by broadcast
-    if let Err(e) = ws_server.broadcast(body).await {
-        error!("Error broadcasting message: {}", e);
-        return Ok(warp::reply::with_status("Error broadcasting message", warp::http::StatusCode::INTERNAL_SERVER_ERROR));
+/// Waits for the next heartbeat tick; never resolves with heartbeats disabled.
+async fn next_heartbeat(heartbeat: &mut Option<(Heartbeat, tokio::time::Interval)>) -> Option<Heartbeat> {
+    match heartbeat {
+        Some((heartbeat, interval)) => {
+            interval.tick().await;
+            Some(*heartbeat)
+        }
+        None => std::future::pending().await,
     }
-    Ok(warp::reply::with_status("Message broadcasted", warp::http::StatusCode::OK))
+}
+
+/// Matches the `/`-separated segments of a configured route path.
+fn route_prefix(path: &str) -> BoxedFilter<()> {
+    config::path_segments(path)
+        .into_iter()
+        .fold(warp::any().boxed(), |filter, segment| filter.and(warp::path(segment)).boxed())
+}
+
+async fn handle_rejection(err: warp::Rejection) -> Result<warp::reply::Response, warp::Rejection> {
+    if err.find::<body::PayloadTooLarge>().is_some() {
+        return Ok(warp::reply::with_status("Payload too large", StatusCode::PAYLOAD_TOO_LARGE).into_response());
+    }
+    if let Some(limited) = err.find::<RateLimited>() {
+        return Ok(limited.response());
+    }
+    if err.find::<tls::ClientCertRequired>().is_some() {
+        return Ok(warp::reply::with_status("Client certificate required", StatusCode::FORBIDDEN).into_response());
+    }
+    Err(err)
 }
 
 #[tokio::main]
 async fn main() {
     // Initialize the logger with a more explicit configuration
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let cli = Cli::parse();
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // `Config::load` has already validated these, so building them cannot fail.
+    let verifiers = Arc::new(config.verifiers().expect("validated configuration"));
+    let auth = Arc::new(config.auth().expect("validated configuration"));
+    let event_log = config.event_log().expect("validated configuration");
+    let tls_settings = config.tls().expect("validated configuration");
+    let metrics = Metrics::new();
+    let dead_letters = DeadLetters::new(config.limits.dead_letter_capacity);
+    let envelopes = Arc::new(config.envelope().expect("validated configuration"));
+    let forwarder = Forwarder::new(config.forward_targets().expect("validated configuration"), dead_letters.clone(), metrics.clone());
+    let require_client_cert = tls_settings.as_ref().is_some_and(|settings| settings.client_ca.is_some());
+    let tls = match tls_settings.map(ReloadableAcceptor::new).transpose() {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if cli.check {
+        info!("Configuration is valid");
+        return;
+    }
+
     info!("Starting WebSocket server");
-    let ws_server = WebSocketServer::new();
-    let ws_server_clone = ws_server.clone();
-    let ws_route = warp::path("ws")
-        .and(warp::ws())
-        .map(move |ws: warp::ws::Ws| {
-            let ws_server = ws_server.clone();
-            ws.on_upgrade(move |socket| {
-                let ws_server = ws_server.clone();
-                async move {
-                    let id = {
-                        let mut next_id = ws_server.next_id.lock().await;
-                        *next_id += 1;
-                        *next_id
-                    };
-
-                    let (tx, mut rx) = mpsc::unbounded_channel();
-                    ws_server.users.lock().await.insert(id, User { id, tx });
-
-                    info!("New WebSocket connection: {}", id);
-                    let (mut ws_sender, mut ws_receiver) = socket.split();
-
-                    tokio::spawn(async move {
-                        while let Some(Ok(msg)) = ws_receiver.next().await {
-                            if msg.is_text() {
-                                if let Ok(text) = msg.to_str() {
-                                    let _ = ws_server.broadcast(text).await;
-                                }
-                            }
-                        }
-                        info!("WebSocket connection closed: {}", id);
-                        ws_server.users.lock().await.remove(&id);
-                    });
-
-                    while let Some(msg) = rx.recv().await {
-                        if ws_sender.send(warp::ws::Message::text(msg.to_string())).await.is_err() {
-                            break;
-                        }
-                    }
+    for (channel, signature) in &config.signatures.channels {
+        info!("Verifying {} signatures on channel {}", signature.provider, channel);
+    }
+    let mut replay = ReplayBuffer::new(config.limits.replay_capacity, config.limits.replay_channels);
+    let log = match event_log {
+        Some(event_log) => {
+            let log = match EventLog::open(event_log) {
+                Ok(log) => log,
+                Err(e) => {
+                    error!("Failed to open event log: {}", e);
+                    std::process::exit(1);
                 }
-            })
-        });
+            };
+            if let Err(e) = replay.restore_from(&log) {
+                error!("Failed to restore events from the event log: {}", e);
+                std::process::exit(1);
+            }
+            let log = Arc::new(std::sync::Mutex::new(log));
+            eventlog::spawn_sync_task(log.clone());
+            Some(log)
+        }
+        None => None,
+    };
+    let ws_server = WebSocketServer::new(
+        replay,
+        log,
+        dead_letters,
+        config.queues().expect("validated configuration"),
+        metrics,
+        config.heartbeat().expect("validated configuration"),
+        config.bridge().expect("validated configuration"),
+        config.backplane().expect("validated configuration").start(),
+    );
+    info!("Node id {}", ws_server.node.id);
+    ws_server.node.spawn_receiver(ws_server.clone());
+    if !auth.is_enabled() {
+        warn!("No tokens configured; WebSocket and SSE connections are unauthenticated");
+        if auth.is_admin_open() {
+            warn!("Admin API, /api/endpoints and /metrics are open to anyone");
+        } else {
+            warn!("Admin API, /api/endpoints and /metrics are disabled until tokens are configured");
+        }
+    }
+    let endpoints = Endpoints::new(config.endpoints());
+    endpoints::spawn_expiry_task(endpoints.clone(), ws_server.clone());
+    let rate_limiter = RateLimiter::new(config.rate_limits().expect("validated configuration"), ws_server.metrics.clone());
+    ratelimit::spawn_sweep_task(rate_limiter.clone());
+    let webhook_guard = ratelimit::webhook_guard(rate_limiter.clone(), auth.clone());
+    let with_limiter = {
+        let rate_limiter = rate_limiter.clone();
+        warp::any().map(move || rate_limiter.clone())
+    };
+    let shutdown = Shutdown::new();
+    let with_server = {
+        let ws_server = ws_server.clone();
+        warp::any().map(move || ws_server.clone())
+    };
+    let with_shutdown = {
+        let shutdown = shutdown.clone();
+        warp::any().map(move || shutdown.clone())
+    };
+    let with_auth = warp::any().map(move || auth.clone());
+    let with_forwarder = warp::any().map(move || forwarder.clone());
+    let with_endpoints = warp::any().map(move || endpoints.clone());
+    let with_envelopes = warp::any().map(move || envelopes.clone());
+
+    let ws_route = route_prefix(&config.paths.ws)
+        .and(warp::path::param::<String>().map(Some).or(warp::any().map(|| None)).unify())
+        .and(warp::path::end())
+        .and(listener::connection_info())
+        .and(warp::header::headers_cloned())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::ws())
+        .and(ratelimit::upgrade_guard(rate_limiter))
+        .and(with_server.clone())
+        .and(with_auth.clone())
+        .and(with_endpoints.clone())
+        .and_then(handle_upgrade);
+
+    let sse_route = warp::get()
+        .and(route_prefix(&config.paths.events))
+        .and(warp::path::param::<String>().map(Some).or(warp::any().map(|| None)).unify())
+        .and(warp::path::end())
+        .and(listener::connection_info())
+        .and(warp::header::headers_cloned())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_server.clone())
+        .and(with_auth.clone())
+        .and(with_endpoints.clone())
+        .and_then(sse::handle_sse);
 
     let webhook_route = warp::post()
-        .and(warp::path("webhook"))
-        .and(warp::body::json())
-        .and(warp::any().map(move || ws_server_clone.clone()))
+        .and(route_prefix(&config.paths.webhook))
+        .and(warp::path::param::<String>().map(Some).or(warp::any().map(|| None)).unify())
+        .and(warp::path::end())
+        .and(tls::client_cert_guard(require_client_cert))
+        .and(webhook_guard.clone())
+        .and(envelope::received())
+        .and(warp::header::headers_cloned())
+        .and(body::limited(config.limits.max_body_bytes))
+        .and(with_server.clone())
+        .and(warp::any().map(move || verifiers.clone()))
+        .and(with_forwarder.clone())
+        .and(with_envelopes.clone())
+        .and(with_limiter.clone())
         .and_then(handle_webhook);
 
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_methods(vec!["GET", "POST", "OPTIONS"])
+    let hook_route = warp::post()
+        .and(route_prefix(&config.paths.hook))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(tls::client_cert_guard(require_client_cert))
+        .and(webhook_guard)
+        .and(envelope::received())
+        .and(warp::header::headers_cloned())
+        .and(body::limited(config.limits.max_body_bytes))
+        .and(with_server.clone())
+        .and(with_endpoints.clone())
+        .and(with_forwarder.clone())
+        .and(with_envelopes)
+        .and(with_limiter)
+        .and_then(handle_hook);
+
+    let admin_request = || {
+        warp::path::end()
+            .and(warp::header::headers_cloned())
+            .and(warp::query::<HashMap<String, String>>())
+    };
+    let deliveries_route = warp::get()
+        .and(warp::path("deliveries"))
+        .and(admin_request())
+        .and(with_auth.clone())
+        .and(with_forwarder.clone())
+        .and_then(admin::handle_deliveries);
+    let dead_letters_route = warp::path("dead-letters").and(
+        warp::get()
+            .and(admin_request())
+            .and(with_auth.clone())
+            .and(with_server.clone())
+            .and_then(admin::handle_dead_letters)
+            .or(warp::delete()
+                .and(admin_request())
+                .and(with_auth.clone())
+                .and(with_server.clone())
+                .and_then(admin::handle_purge_dead_letters))
+            .or(warp::get()
+                .and(warp::path::param::<u64>())
+                .and(admin_request())
+                .and(with_auth.clone())
+                .and(with_server.clone())
+                .and_then(admin::handle_dead_letter))
+            .or(warp::delete()
+                .and(warp::path::param::<u64>())
+                .and(admin_request())
+                .and(with_auth.clone())
+                .and(with_server.clone())
+                .and_then(admin::handle_delete_dead_letter))
+            .or(warp::post()
+                .and(warp::path::param::<u64>())
+                .and(warp::path("redeliver"))
+                .and(admin_request())
+                .and(body::limited(64 * 1024))
+                .and(with_auth.clone())
+                .and(with_server.clone())
+                .and(with_forwarder)
+                .and_then(admin::handle_redeliver)),
+    );
+    let clients_route = warp::path("clients").and(
+        warp::get()
+            .and(admin_request())
+            .and(with_auth.clone())
+            .and(with_server.clone())
+            .and_then(admin::handle_clients)
+            .or(warp::get()
+                .and(warp::path::param::<usize>())
+                .and(admin_request())
+                .and(with_auth.clone())
+                .and(with_server.clone())
+                .and_then(admin::handle_client))
+            .or(warp::delete()
+                .and(warp::path::param::<usize>())
+                .and(admin_request())
+                .and(with_auth.clone())
+                .and(with_server.clone())
+                .and_then(admin::handle_disconnect))
+            .or(warp::post()
+                .and(warp::path::param::<usize>())
+                .and(warp::path("send"))
+                .and(admin_request())
+                .and(body::limited(config.limits.max_body_bytes))
+                .and(with_auth.clone())
+                .and(with_server.clone())
+                .and_then(admin::handle_send)),
+    );
+    let channels_route = warp::post()
+        .and(warp::path("channels"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("broadcast"))
+        .and(admin_request())
+        .and(body::limited(config.limits.max_body_bytes))
+        .and(with_auth.clone())
+        .and(with_server.clone())
+        .and_then(admin::handle_channel_broadcast);
+    let admin_route = warp::path("admin").and(
+        deliveries_route
+            .or(dead_letters_route)
+            .or(clients_route)
+            .or(channels_route),
+    );
+
+    let endpoints_route = warp::path("api").and(warp::path("endpoints")).and(
+        warp::post()
+            .and(admin_request())
+            .and(body::limited(64 * 1024))
+            .and(with_auth.clone())
+            .and(with_endpoints.clone())
+            .and_then(endpoints::handle_create)
+            .or(warp::get()
+                .and(admin_request())
+                .and(with_auth.clone())
+                .and(with_endpoints.clone())
+                .and_then(endpoints::handle_list))
+            .or(warp::get()
+                .and(warp::path::param::<String>())
+                .and(admin_request())
+                .and(with_auth.clone())
+                .and(with_endpoints.clone())
+                .and_then(endpoints::handle_get))
+            .or(warp::delete()
+                .and(warp::path::param::<String>())
+                .and(admin_request())
+                .and(with_auth.clone())
+                .and(with_endpoints)
+                .and(with_server.clone())
+                .and_then(endpoints::handle_delete)),
+    );
+
+    let health_route = warp::get()
+        .and(warp::path("healthz"))
+        .and(warp::path::end())
+        .and_then(shutdown::handle_healthz)
+        .or(warp::get()
+            .and(warp::path("readyz"))
+            .and(warp::path::end())
+            .and(with_shutdown)
+            .and_then(shutdown::handle_readyz));
+
+    let metrics_route = warp::get()
+        .and(warp::path("metrics"))
+        .and(admin_request())
+        .and(with_auth.clone())
+        .and(with_server.clone())
+        .and_then(metrics::handle_metrics);
+
+    let ui_route = if config.ui.enabled {
+        let page: Arc<str> = ui::page(&config.paths, config.limits.replay_capacity, &ws_server.node.id).into();
+        warp::get().and(warp::path::end()).map(move || ui::reply(&page)).boxed()
+    } else {
+        warp::any().and_then(|| async { Err::<warp::reply::Response, _>(warp::reject::not_found()) }).boxed()
+    };
+
+    let static_route = match &config.static_dir {
+        Some(dir) => warp::fs::dir(dir.clone()).map(Reply::into_response).boxed(),
+        None => warp::any()
+            .and_then(|| async { Err::<warp::reply::Response, _>(warp::reject::not_found()) })
+            .boxed(),
+    };
+
+    let mut cors = warp::cors()
+        .allow_methods(vec!["GET", "POST", "DELETE", "OPTIONS"])
         .allow_headers(vec!["Content-Type", "Authorization"])
         .max_age(3600);
+    cors = if config.cors.origins.iter().any(|origin| origin == "*") {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(config.cors.origins.iter().map(String::as_str))
+    };
 
     // Combine all routes first, then apply CORS
     let routes = ws_route
-        .or(warp::fs::dir("public"))
+        .or(sse_route)
+        .or(ui_route)
+        .or(static_route)
         .or(webhook_route)
+        .or(hook_route)
+        .or(admin_route)
+        .or(endpoints_route)
+        .or(metrics_route)
+        .or(health_route)
+        .recover(handle_rejection)
         .with(cors);
 
-    info!("Server running on ws://127.0.0.1:3030");
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
-}
\ No newline at end of file
+    if let Some(tls) = &tls {
+        tls.reload_on_sighup();
+    }
+    let scheme = if tls.is_some() { "wss" } else { "ws" };
+    let service = warp::service(routes);
+    let mut servers = Vec::new();
+    for addr in &config.listen {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to listen on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        };
+        info!("Server running on {}://{}", scheme, addr);
+        servers.push(tokio::spawn(listener::serve(listener, tls.clone(), service.clone(), shutdown.clone())));
+    }
+    shutdown.set_ready();
+
+    shutdown::signal().await;
+    let pre_stop_delay = Duration::from_millis(config.shutdown.pre_stop_delay_ms);
+    if !pre_stop_delay.is_zero() {
+        info!("Shutting down, not ready but still serving for {:?}", pre_stop_delay);
+        shutdown.set_not_ready();
+        tokio::select! {
+            _ = tokio::time::sleep(pre_stop_delay) => {}
+            _ = shutdown::signal() => info!("Second signal, skipping the rest of the pre-stop delay"),
+        }
+    }
+    let drain_timeout = Duration::from_millis(config.shutdown.drain_timeout_ms);
+    info!("Shutting down, draining for up to {:?}", drain_timeout);
+    let deadline = tokio::time::Instant::now() + drain_timeout;
+    shutdown.begin();
+    futures_util::future::join_all(servers).await;
+    if tokio::time::timeout_at(deadline, shutdown.drained()).await.is_err() {
+        warn!("Drain timeout reached with {} requests in flight", shutdown.in_flight());
+    }
+    ws_server.close_all(1001, "server shutting down", Reason::Shutdown).await;
+    if tokio::time::timeout_at(deadline, ws_server.closed()).await.is_err() {
+        warn!("Drain timeout reached with {} connections still open", ws_server.users.len());
+    }
+    if let Some(log) = ws_server.log.clone() {
+        match tokio::task::spawn_blocking(move || log.lock().unwrap().sync()).await {
+            Ok(Err(e)) => warn!("Failed to sync event log: {}", e),
+            Err(e) => warn!("Event log sync task failed: {}", e),
+            Ok(Ok(())) => {}
+        }
+    }
+    info!("Shutdown complete");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoints::EndpointSettings;
+    use crate::ratelimit::RateLimitSettings;
+
+    fn endpoints() -> Endpoints {
+        Endpoints::new(EndpointSettings {
+            capacity: 10,
+            max_ttl: None,
+            hook_path: "/hook".to_string(),
+            ws_path: "/ws".to_string(),
+            tls: false,
+            tolerance_secs: 300,
+        })
+    }
+
+    /// Creates an endpoint through the API, returning its token.
+    async fn create(endpoints: &Endpoints, body: &'static str) -> String {
+        let mut auth = Auth::default();
+        auth.set_anonymous_admin(true);
+        let (headers, query, body) = (HeaderMap::new(), HashMap::new(), Bytes::from_static(body.as_bytes()));
+        let response = endpoints::handle_create(headers, query, body, Arc::new(auth), endpoints.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()["id"].as_str().unwrap().to_string()
+    }
+
+    async fn post_hook(token: &str, endpoints: &Endpoints, ws_server: &WebSocketServer) -> StatusCode {
+        let received = Received {
+            method: warp::http::Method::POST,
+            path: format!("/hook/{}", token),
+            query: String::new(),
+            remote_addr: "127.0.0.1:1".parse().unwrap(),
+            received_at: 0,
+        };
+        let metrics = Metrics::new();
+        let forwarder = Forwarder::new(Vec::new(), DeadLetters::new(0), metrics.clone());
+        let envelopes = Arc::new(EnvelopeSettings { enabled: true, headers: None });
+        let limiter = RateLimiter::new(RateLimitSettings::default(), metrics);
+        let (headers, body) = (HeaderMap::new(), Bytes::from_static(b"{}"));
+        let (ws_server, endpoints) = (ws_server.clone(), endpoints.clone());
+        let response = handle_hook(token.to_string(), received, headers, body, ws_server, endpoints, forwarder, envelopes, limiter);
+        response.await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn hook_accepts_webhooks_only_for_live_endpoints() {
+        let (ws_server, endpoints) = (WebSocketServer::for_tests(), endpoints());
+        assert_eq!(post_hook("unknown", &endpoints, &ws_server).await, StatusCode::NOT_FOUND);
+
+        let token = create(&endpoints, "").await;
+        assert_eq!(post_hook(&token, &endpoints, &ws_server).await, StatusCode::OK);
+        endpoints.remove(&token);
+        assert_eq!(post_hook(&token, &endpoints, &ws_server).await, StatusCode::NOT_FOUND);
+
+        let signed = create(&endpoints, r#"{"signature": {"provider": "github", "secret": "change-me"}}"#).await;
+        assert_eq!(post_hook(&signed, &endpoints, &ws_server).await, StatusCode::UNAUTHORIZED);
+    }
+}
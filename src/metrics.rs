@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use warp::http::{HeaderMap, StatusCode};
+use warp::Reply;
+use crate::admin;
+use crate::auth::Auth;
+use crate::server::WebSocketServer;
+
+/// Upper bounds, in seconds, of the fan-out latency histogram buckets.
+const FANOUT_BUCKETS: &[f64] = &[0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// A counter partitioned by label values, in the order of `labels`.
+struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterVec {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self { name, help, labels, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    fn add(&self, labels: &[&str], n: u64) {
+        let labels = labels.iter().map(|label| label.to_string()).collect();
+        *self.values.lock().unwrap().entry(labels).or_default() += n;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        for (values, n) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{} {}", self.name, label_set(self.labels, values, None), n);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Non-cumulative count per bucket of `FANOUT_BUCKETS`, plus `+Inf`.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, Histogram>>,
+}
+
+impl HistogramVec {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self { name, help, labels, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    fn observe(&self, labels: &[&str], value: Duration) {
+        let seconds = value.as_secs_f64();
+        let labels = labels.iter().map(|label| label.to_string()).collect();
+        let mut values = self.values.lock().unwrap();
+        let histogram = values.entry(labels).or_default();
+        histogram.buckets.resize(FANOUT_BUCKETS.len() + 1, 0);
+        let bucket = FANOUT_BUCKETS.iter().position(|&bound| seconds <= bound).unwrap_or(FANOUT_BUCKETS.len());
+        histogram.buckets[bucket] += 1;
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        for (values, histogram) in self.values.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (i, n) in histogram.buckets.iter().enumerate() {
+                cumulative += n;
+                let le = FANOUT_BUCKETS.get(i).map_or("+Inf".to_string(), f64::to_string);
+                let _ = writeln!(out, "{}_bucket{} {}", self.name, label_set(self.labels, values, Some(&le)), cumulative);
+            }
+            let labels = label_set(self.labels, values, None);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, labels, histogram.count);
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Formats `{name="value",...}`, with an `le` label last for histogram buckets.
+fn label_set(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> =
+        names.iter().zip(values).map(|(name, value)| format!("{}=\"{}\"", name, escape(value))).collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+struct Registry {
+    webhooks: CounterVec,
+    connections: CounterVec,
+    fanout: HistogramVec,
+    messages_sent: CounterVec,
+    bytes_sent: CounterVec,
+    dropped: CounterVec,
+    forwards: CounterVec,
+    evictions: CounterVec,
+    rate_limited: CounterVec,
+}
+
+/// Process-wide counters for `GET /metrics`. Gauges such as connected
+/// clients and queue depth are read from the server when scraped instead.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            registry: Arc::new(Registry {
+                webhooks: CounterVec::new(
+                    "nwebhook_webhooks_received_total",
+                    "Webhook requests handled, by route and response status.",
+                    &["route", "status"],
+                ),
+                connections: CounterVec::new(
+                    "nwebhook_connection_attempts_total",
+                    "WebSocket upgrade and SSE requests, by transport and response status.",
+                    &["transport", "status"],
+                ),
+                fanout: HistogramVec::new(
+                    "nwebhook_fanout_duration_seconds",
+                    "Time taken to queue a message for every recipient.",
+                    &["kind"],
+                ),
+                messages_sent: CounterVec::new(
+                    "nwebhook_messages_queued_total",
+                    "Messages queued for clients, by kind (publish, broadcast or direct).",
+                    &["kind"],
+                ),
+                bytes_sent: CounterVec::new(
+                    "nwebhook_sent_bytes_total",
+                    "Payload bytes written to clients, by transport.",
+                    &["transport"],
+                ),
+                dropped: CounterVec::new(
+                    "nwebhook_dropped_messages_total",
+                    "Messages for clients that were dead-lettered, by reason.",
+                    &["reason"],
+                ),
+                forwards: CounterVec::new(
+                    "nwebhook_forward_attempts_total",
+                    "Attempts to forward webhooks downstream, by target and outcome.",
+                    &["target", "outcome"],
+                ),
+                evictions: CounterVec::new(
+                    "nwebhook_client_evictions_total",
+                    "Clients disconnected by the server, by reason.",
+                    &["reason"],
+                ),
+                rate_limited: CounterVec::new(
+                    "nwebhook_rate_limited_total",
+                    "Requests refused with 429, by the limit they exceeded.",
+                    &["limit"],
+                ),
+            }),
+        }
+    }
+
+    pub fn webhook(&self, route: &str, status: StatusCode) {
+        self.registry.webhooks.add(&[route, status.as_str()], 1);
+    }
+
+    pub fn connection(&self, transport: &str, status: StatusCode) {
+        self.registry.connections.add(&[transport, status.as_str()], 1);
+    }
+
+    pub fn fanout(&self, kind: &str, recipients: usize, elapsed: Duration) {
+        self.registry.fanout.observe(&[kind], elapsed);
+        self.registry.messages_sent.add(&[kind], recipients as u64);
+    }
+
+    pub fn bytes_sent(&self, transport: &str, bytes: usize) {
+        self.registry.bytes_sent.add(&[transport], bytes as u64);
+    }
+
+    pub fn dropped(&self, reason: &str) {
+        self.registry.dropped.add(&[reason], 1);
+    }
+
+    pub fn forward(&self, target: &str, outcome: &str) {
+        self.registry.forwards.add(&[target, outcome], 1);
+    }
+
+    pub fn eviction(&self, reason: &str) {
+        self.registry.evictions.add(&[reason], 1);
+    }
+
+    pub fn rate_limited(&self, limit: &str) {
+        self.registry.rate_limited.add(&[limit], 1);
+    }
+
+    fn render(&self, out: &mut String) {
+        let registry = &self.registry;
+        registry.webhooks.render(out);
+        registry.connections.render(out);
+        registry.fanout.render(out);
+        registry.messages_sent.render(out);
+        registry.bytes_sent.render(out);
+        registry.dropped.render(out);
+        registry.forwards.render(out);
+        registry.evictions.render(out);
+        registry.rate_limited.render(out);
+    }
+}
+
+/// `GET /metrics`: Prometheus text exposition format. Needs the same token
+/// as the admin API, since it names channels and clients.
+pub async fn handle_metrics(
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    auth: Arc<Auth>,
+    ws_server: WebSocketServer,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Err((body, status)) = admin::authorize(&auth, &headers, &query) {
+        return Ok(warp::reply::with_status(body, status).into_response());
+    }
+    let mut out = String::new();
+    {
+        let mut users = Vec::new();
+        ws_server.users.for_each(None, |user| users.push((user.id, user.channel.clone(), user.tx.len())));
+        users.sort();
+        let mut clients: BTreeMap<&str, usize> = BTreeMap::new();
+        for (_, channel, _) in &users {
+            *clients.entry(channel).or_default() += 1;
+        }
+        header(&mut out, "nwebhook_connected_clients", "Connected WebSocket and SSE clients, by channel.", "gauge");
+        for (channel, n) in clients {
+            let _ = writeln!(out, "nwebhook_connected_clients{{channel=\"{}\"}} {}", escape(channel), n);
+        }
+        header(&mut out, "nwebhook_client_queue_depth", "Messages waiting to be written to each client.", "gauge");
+        for (id, channel, depth) in &users {
+            let _ = writeln!(out, "nwebhook_client_queue_depth{{client=\"{}\",channel=\"{}\"}} {}", id, escape(channel), depth);
+        }
+    }
+    ws_server.metrics.render(&mut out);
+    Ok(warp::reply::with_header(out, "content-type", "text/plain; version=0.0.4").into_response())
+}
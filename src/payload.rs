@@ -0,0 +1,261 @@
+use base64::Engine;
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, XmlVersion};
+use serde_json::{json, Map, Value};
+use warp::http::HeaderMap;
+
+/// How a webhook body was turned into the JSON payload sent to subscribers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Empty,
+    Json,
+    /// `application/x-www-form-urlencoded`, as an object of fields.
+    Form,
+    /// XML, as nested objects; see [`xml_to_json`].
+    Xml,
+    /// Any other UTF-8 body: `{"content_type": ..., "text": ...}`.
+    Text,
+    /// Anything else: `{"content_type": ..., "base64": ...}`.
+    Binary,
+}
+
+/// The declared media type of a request, lowercased and without parameters.
+pub fn content_type(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get("content-type")?.to_str().ok()?;
+    let media_type = value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    (!media_type.is_empty()).then_some(media_type)
+}
+
+fn is_json(media_type: &str) -> bool {
+    media_type == "application/json" || media_type.ends_with("+json")
+}
+
+fn is_xml(media_type: &str) -> bool {
+    media_type == "application/xml" || media_type == "text/xml" || media_type.ends_with("+xml")
+}
+
+/// Converts a webhook body to JSON according to its content type. Only a body
+/// declared as JSON can fail to decode; everything else falls back to the raw
+/// text or bytes, tagged with the original content type.
+pub fn decode(headers: &HeaderMap, body: &[u8]) -> Result<(Format, Value), serde_json::Error> {
+    if body.is_empty() {
+        return Ok((Format::Empty, Value::Null));
+    }
+    let media_type = content_type(headers);
+    match media_type.as_deref() {
+        Some(media_type) if is_json(media_type) => return Ok((Format::Json, serde_json::from_slice(body)?)),
+        // `curl -d` labels everything as a form, so JSON bodies sent that way
+        // keep working as before.
+        Some("application/x-www-form-urlencoded") => {
+            return Ok(match serde_json::from_slice(body) {
+                Ok(value) => (Format::Json, value),
+                Err(_) => (Format::Form, form_to_json(body)),
+            })
+        }
+        Some(media_type) if is_xml(media_type) => {
+            if let Some(value) = std::str::from_utf8(body).ok().and_then(xml_to_json) {
+                return Ok((Format::Xml, value));
+            }
+        }
+        None => {
+            if let Ok(value) = serde_json::from_slice(body) {
+                return Ok((Format::Json, value));
+            }
+        }
+        Some(_) => {}
+    }
+    let content_type = media_type.unwrap_or_else(|| "application/octet-stream".to_string());
+    Ok(match std::str::from_utf8(body) {
+        Ok(text) => (Format::Text, json!({ "content_type": content_type, "text": text })),
+        Err(_) => {
+            let encoded = base64::engine::general_purpose::STANDARD.encode(body);
+            (Format::Binary, json!({ "content_type": content_type, "base64": encoded }))
+        }
+    })
+}
+
+/// Adds `value` under `key`, turning repeated keys into arrays.
+pub fn insert_repeated(map: &mut Map<String, Value>, key: String, value: Value) {
+    match map.get_mut(&key) {
+        Some(Value::Array(values)) => values.push(value),
+        Some(existing) => {
+            let first = existing.take();
+            *existing = Value::Array(vec![first, value]);
+        }
+        None => {
+            map.insert(key, value);
+        }
+    }
+}
+
+/// Form fields as a JSON object of strings; repeated fields become arrays.
+pub fn form_to_json(body: &[u8]) -> Value {
+    let mut fields = Map::new();
+    for (key, value) in form_urlencoded::parse(body) {
+        insert_repeated(&mut fields, key.into_owned(), Value::String(value.into_owned()));
+    }
+    Value::Object(fields)
+}
+
+/// An element being built while parsing XML.
+struct Element {
+    name: String,
+    fields: Map<String, Value>,
+    text: String,
+}
+
+impl Element {
+    fn open(start: &BytesStart) -> Option<Self> {
+        let mut fields = Map::new();
+        for attribute in start.attributes() {
+            let attribute = attribute.ok()?;
+            let key = format!("@{}", attribute.key.as_ref());
+            let value = attribute.normalized_value(XmlVersion::Implicit1_0).ok()?;
+            fields.insert(key, Value::String(value.into_owned()));
+        }
+        Some(Self { name: start.name().as_ref().to_string(), fields, text: String::new() })
+    }
+
+    /// Elements with only text become strings; otherwise attributes are
+    /// `@name` keys, children are keyed by tag name and text is `#text`.
+    fn close(mut self) -> (String, Value) {
+        let text = self.text.trim();
+        if self.fields.is_empty() {
+            return (self.name, Value::String(text.to_string()));
+        }
+        if !text.is_empty() {
+            self.fields.insert("#text".to_string(), Value::String(text.to_string()));
+        }
+        (self.name, Value::Object(self.fields))
+    }
+}
+
+/// Converts an XML document to `{"<root>": ...}`, or `None` if it is not
+/// well-formed.
+fn xml_to_json(xml: &str) -> Option<Value> {
+    let mut reader = Reader::from_str(xml);
+    let mut stack: Vec<Element> = Vec::new();
+    let mut root = None;
+    loop {
+        let event = reader.read_event().ok()?;
+        let closed = match event {
+            Event::Start(start) => {
+                stack.push(Element::open(&start)?);
+                None
+            }
+            Event::Empty(start) => Some(Element::open(&start)?.close()),
+            Event::End(_) => Some(stack.pop()?.close()),
+            Event::Text(text) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(&text.xml10_content());
+                }
+                None
+            }
+            Event::CData(data) => {
+                stack.last_mut()?.text.push_str(&data.xml10_content());
+                None
+            }
+            Event::GeneralRef(reference) => {
+                let element = stack.last_mut()?;
+                match reference.resolve_char_ref().ok()? {
+                    Some(c) => element.text.push(c),
+                    None => element.text.push_str(resolve_predefined_entity(&reference)?),
+                }
+                None
+            }
+            Event::Eof => break,
+            Event::Decl(_) | Event::PI(_) | Event::Comment(_) | Event::DocType(_) => None,
+        };
+        if let Some((name, value)) = closed {
+            match stack.last_mut() {
+                Some(parent) => insert_repeated(&mut parent.fields, name, value),
+                None if root.is_none() => root = Some(json!({ name: value })),
+                None => return None,
+            }
+        }
+    }
+    if stack.is_empty() {
+        root
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(content_type: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", content_type.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn xml_elements_attributes_and_text() {
+        let xml = r#"<?xml version="1.0"?>
+            <!-- a notification -->
+            <order id="7" status="paid">
+                <customer>Ada</customer>
+                <item sku="a">first</item>
+                <item sku="b"/>
+                <note><![CDATA[<fragile>]]> &amp; &#x263A;</note>
+                <empty></empty>
+            </order>"#;
+        assert_eq!(
+            xml_to_json(xml),
+            Some(json!({"order": {
+                "@id": "7",
+                "@status": "paid",
+                "customer": "Ada",
+                "item": [{"@sku": "a", "#text": "first"}, {"@sku": "b"}],
+                "note": "<fragile> & \u{263A}",
+                "empty": "",
+            }}))
+        );
+        assert_eq!(xml_to_json(r#"<a b="x &amp; y"/>"#), Some(json!({"a": {"@b": "x & y"}})));
+    }
+
+    #[test]
+    fn malformed_xml_is_rejected() {
+        for xml in ["", "<a>", "<a></b>", "<a/><b/>", "<a>&nosuch;</a>", "text only", "<a x='1' x='2'/>"] {
+            assert_eq!(xml_to_json(xml), None, "{:?}", xml);
+        }
+    }
+
+    #[test]
+    fn form_fields_repeat_as_arrays() {
+        assert_eq!(
+            form_to_json(b"a=1&b=two+words&a=2&c=%E2%98%BA&a=3&empty="),
+            json!({"a": ["1", "2", "3"], "b": "two words", "c": "\u{263A}", "empty": ""})
+        );
+        assert_eq!(form_to_json(b""), json!({}));
+    }
+
+    #[test]
+    fn decodes_by_content_type() {
+        let decoded = |content_type: Option<&str>, body: &[u8]| {
+            decode(&content_type.map(typed).unwrap_or_default(), body).unwrap()
+        };
+        assert_eq!(decoded(Some("application/json"), b""), (Format::Empty, Value::Null));
+        assert_eq!(decoded(Some("application/vnd.api+json; charset=utf-8"), br#"{"a":1}"#), (Format::Json, json!({"a": 1})));
+        assert_eq!(decoded(Some("application/x-www-form-urlencoded"), br#"{"a":1}"#), (Format::Json, json!({"a": 1})));
+        assert_eq!(decoded(Some("application/x-www-form-urlencoded"), b"a=1"), (Format::Form, json!({"a": "1"})));
+        assert_eq!(decoded(Some("Text/XML"), b"<a>1</a>"), (Format::Xml, json!({"a": "1"})));
+        assert_eq!(
+            decoded(Some("application/xml"), b"<a>"),
+            (Format::Text, json!({"content_type": "application/xml", "text": "<a>"}))
+        );
+        assert_eq!(decoded(None, b"[1]"), (Format::Json, json!([1])));
+        assert_eq!(
+            decoded(None, b"hello"),
+            (Format::Text, json!({"content_type": "application/octet-stream", "text": "hello"}))
+        );
+        assert_eq!(
+            decoded(Some("image/png"), &[0x89, b'P', b'N', b'G']),
+            (Format::Binary, json!({"content_type": "image/png", "base64": "iVBORw=="}))
+        );
+        assert!(decode(&typed("application/json"), b"{").is_err());
+    }
+}
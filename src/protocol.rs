@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use log::{error, info, warn};
+use crate::bridge::BridgeResponse;
+use crate::filter::Filter;
+use crate::server::{self, Backpressure, ClientInfo, WebSocketServer, ALL_CHANNELS};
+use crate::subscription::Subscription;
+
+/// Version of the WebSocket control protocol. Requests may omit `v`; replies
+/// always carry it.
+pub const PROTOCOL_VERSION: u64 = 1;
+
+/// A message from a client: `{"v": 1, "type": ..., "id": ..., ...}`. The
+/// optional `id` is any JSON value and is echoed on the reply.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Request {
+    /// Starts (or resumes) receiving events, replacing any previous filter;
+    /// a missing or null filter lets every event through.
+    Subscribe {
+        #[serde(default)]
+        filter: Option<Filter>,
+    },
+    /// Stops receiving events until the next subscribe.
+    Unsubscribe,
+    Ping,
+    /// Acknowledges every event up to and including `event_id`.
+    Ack { event_id: u64 },
+    /// Publishes `data` to `channel`, by default the client's own. Requires
+    /// a publish grant for that channel.
+    Publish {
+        #[serde(default)]
+        channel: Option<String>,
+        data: Value,
+    },
+    Whoami,
+    /// Makes this client the one that answers its channel's webhooks: each
+    /// is sent to it as a `request` and waits for the matching `response`.
+    /// Requires a publish grant for the channel.
+    Respond,
+    /// The HTTP response to the webhook sent as request `request_id`.
+    Response {
+        request_id: u64,
+        #[serde(default)]
+        status: Option<u16>,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        #[serde(default)]
+        body: Option<Value>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    UnsupportedVersion,
+    InvalidMessage,
+    InvalidChannel,
+    Forbidden,
+    /// A subscriber with the `block` queue policy is full; retry later.
+    Unavailable,
+    /// A response named a request that is not waiting for this client.
+    UnknownRequest,
+    /// The responder bridge is disabled, as it is with the redis backplane.
+    Unsupported,
+    Internal,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReplyBody {
+    Subscribed { filter: Option<Filter> },
+    Unsubscribed,
+    Pong,
+    Acked { event_id: u64 },
+    /// `event_id` is absent for broadcasts to every channel, which are not
+    /// recorded as events.
+    Published { channel: String, event_id: Option<u64> },
+    Whoami { client: ClientInfo, may_publish: bool },
+    Responding { channel: String },
+    Responded { request_id: u64 },
+    Error { code: ErrorCode, message: String },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Reply {
+    pub v: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    #[serde(flatten)]
+    pub body: ReplyBody,
+}
+
+impl Reply {
+    pub fn error(id: Option<Value>, code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { v: PROTOCOL_VERSION, id, body: ReplyBody::Error { code, message: message.into() } }
+    }
+}
+
+/// Splits a client message into its `id`, if any, and the request, checking
+/// the version; failures are the error to reply with.
+fn parse(text: &str) -> (Option<Value>, Result<Request, (ErrorCode, String)>) {
+    let Ok(mut message) = serde_json::from_str::<Map<String, Value>>(text) else {
+        return (None, Err((ErrorCode::InvalidMessage, "expected a JSON object with a \"type\"".to_string())));
+    };
+    let id = message.remove("id");
+    let request = match message.remove("v") {
+        Some(v) if v.as_u64() != Some(PROTOCOL_VERSION) => Err((
+            ErrorCode::UnsupportedVersion,
+            format!("unsupported protocol version {}, expected {}", v, PROTOCOL_VERSION),
+        )),
+        _ => serde_json::from_value(Value::Object(message)).map_err(|e| (ErrorCode::InvalidMessage, e.to_string())),
+    };
+    (id, request)
+}
+
+/// Handles one text message from the WebSocket client registered as
+/// `user_id` and returns the reply to send it.
+pub async fn handle(ws_server: &WebSocketServer, user_id: usize, subscription: &Subscription, text: &str) -> Reply {
+    let (id, request) = parse(text);
+    let request = match request {
+        Ok(request) => request,
+        Err((code, message)) => {
+            warn!("Invalid message from user {}: {}", user_id, message);
+            return Reply::error(id, code, message);
+        }
+    };
+    let body = match request {
+        Request::Subscribe { filter } => {
+            if let Some(Err(e)) = filter.as_ref().map(Filter::validate) {
+                return Reply::error(id, ErrorCode::InvalidMessage, e);
+            }
+            info!("User {} subscribed with filter {:?}", user_id, filter);
+            let _ = ws_server.subscribe(user_id, filter.clone()).await;
+            ReplyBody::Subscribed { filter }
+        }
+        Request::Unsubscribe => {
+            info!("User {} unsubscribed", user_id);
+            let _ = ws_server.unsubscribe(user_id).await;
+            ReplyBody::Unsubscribed
+        }
+        Request::Ping => ReplyBody::Pong,
+        Request::Ack { event_id } => {
+            let _ = ws_server.ack(user_id, event_id).await;
+            ReplyBody::Acked { event_id }
+        }
+        Request::Publish { channel, data } => {
+            let channel = channel.unwrap_or_else(|| subscription.channel.clone());
+            if channel != ALL_CHANNELS && !server::is_valid_channel(&channel) {
+                return Reply::error(id, ErrorCode::InvalidChannel, format!("invalid channel name {:?}", channel));
+            }
+            if !subscription.publish.allows(&channel) {
+                warn!("User {} is not allowed to publish to channel {}", user_id, channel);
+                return Reply::error(id, ErrorCode::Forbidden, format!("not allowed to publish to {}", channel));
+            }
+            let published = if channel == ALL_CHANNELS {
+                ws_server.broadcast(data).await.map(|()| None)
+            } else {
+                ws_server.publish(&channel, data).await.map(Some)
+            };
+            match published {
+                Ok(event_id) => ReplyBody::Published { channel, event_id },
+                Err(e) if e.is::<Backpressure>() => {
+                    return Reply::error(id, ErrorCode::Unavailable, "subscribers are falling behind, retry later");
+                }
+                Err(e) => {
+                    error!("Error publishing message from user {}: {}", user_id, e);
+                    return Reply::error(id, ErrorCode::Internal, "error publishing message");
+                }
+            }
+        }
+        Request::Whoami => match ws_server.client_info(user_id).await {
+            Some(client) => ReplyBody::Whoami { client, may_publish: subscription.publish.allows(&subscription.channel) },
+            None => return Reply::error(id, ErrorCode::Internal, "client is no longer registered"),
+        },
+        Request::Respond => {
+            if !ws_server.bridge.is_enabled() {
+                return Reply::error(id, ErrorCode::Unsupported, "responding is not available with the redis backplane");
+            }
+            let channel = subscription.channel.clone();
+            if channel == ALL_CHANNELS {
+                return Reply::error(id, ErrorCode::InvalidChannel, "only clients of a single channel can respond");
+            }
+            if !subscription.publish.allows(&channel) {
+                warn!("User {} is not allowed to respond on channel {}", user_id, channel);
+                return Reply::error(id, ErrorCode::Forbidden, format!("not allowed to respond on {}", channel));
+            }
+            ws_server.bridge.designate(&channel, user_id);
+            ReplyBody::Responding { channel }
+        }
+        Request::Response { request_id, status, headers, body } => {
+            let response = match BridgeResponse::parse(status, headers, body) {
+                Ok(response) => response,
+                Err(e) => return Reply::error(id, ErrorCode::InvalidMessage, e),
+            };
+            if !ws_server.bridge.complete(user_id, request_id, response) {
+                return Reply::error(id, ErrorCode::UnknownRequest, format!("request {} is not waiting for a response", request_id));
+            }
+            ReplyBody::Responded { request_id }
+        }
+    };
+    Reply { v: PROTOCOL_VERSION, id, body }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::auth::PublishGrant;
+
+    fn subscription(publish: PublishGrant) -> Subscription {
+        Subscription {
+            channel: "orders".to_string(),
+            remote_addr: "127.0.0.1:1".parse().unwrap(),
+            principal: None,
+            token_source: None,
+            replay: Default::default(),
+            publish,
+        }
+    }
+
+    async fn reply(ws_server: &WebSocketServer, user_id: usize, subscription: &Subscription, request: Value) -> Value {
+        serde_json::to_value(handle(ws_server, user_id, subscription, &request.to_string()).await).unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_every_request_type() {
+        let ws_server = WebSocketServer::for_tests();
+        let subscription = subscription(PublishGrant::All);
+        let (user_id, _rx) = ws_server.register(&subscription).await;
+        let filter = json!({"equals": {"path": "/action", "value": "opened"}});
+        let exchanges = [
+            (json!({"type": "subscribe", "filter": filter}), json!({"type": "subscribed", "filter": filter})),
+            (json!({"type": "unsubscribe"}), json!({"type": "unsubscribed"})),
+            (json!({"type": "ping"}), json!({"type": "pong"})),
+            (json!({"type": "publish", "data": {}}), json!({"type": "published", "channel": "orders", "event_id": 1})),
+            (
+                json!({"type": "publish", "channel": "*", "data": {}}),
+                json!({"type": "published", "channel": "*", "event_id": null}),
+            ),
+            (json!({"type": "ack", "event_id": 1}), json!({"type": "acked", "event_id": 1})),
+            (json!({"type": "respond"}), json!({"type": "responding", "channel": "orders"})),
+        ];
+        for (i, (mut request, mut expected)) in exchanges.into_iter().enumerate() {
+            request["v"] = json!(1);
+            request["id"] = json!(i);
+            expected["v"] = json!(1);
+            expected["id"] = json!(i);
+            assert_eq!(reply(&ws_server, user_id, &subscription, request).await, expected);
+        }
+
+        let whoami = reply(&ws_server, user_id, &subscription, json!({"type": "whoami", "id": "me"})).await;
+        assert_eq!((&whoami["type"], &whoami["id"], &whoami["may_publish"]), (&json!("whoami"), &json!("me"), &json!(true)));
+        assert_eq!((&whoami["client"]["id"], &whoami["client"]["last_ack"]), (&json!(user_id), &json!(1)));
+
+        let response = json!({"type": "response", "id": 9, "request_id": 5, "status": 204});
+        let response = reply(&ws_server, user_id, &subscription, response).await;
+        assert_eq!((&response["id"], &response["code"]), (&json!(9), &json!("unknown_request")));
+    }
+
+    #[tokio::test]
+    async fn refuses_other_protocol_versions() {
+        let ws_server = WebSocketServer::for_tests();
+        let subscription = subscription(PublishGrant::None);
+        for v in [json!(2), json!(0), json!("1"), json!(null)] {
+            let reply = reply(&ws_server, 1, &subscription, json!({"v": v, "type": "ping", "id": 3})).await;
+            let expected = (&json!("error"), &json!("unsupported_version"), &json!(3));
+            assert_eq!((&reply["type"], &reply["code"], &reply["id"]), expected);
+            assert_eq!(reply["v"], PROTOCOL_VERSION);
+        }
+        // `v` may be left out.
+        assert_eq!(reply(&ws_server, 1, &subscription, json!({"type": "ping"})).await, json!({"v": 1, "type": "pong"}));
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_get_error_replies() {
+        let ws_server = WebSocketServer::for_tests();
+        let subscription = subscription(PublishGrant::None);
+        for text in ["not json", "[1]", "{\"id\": 1"] {
+            let reply = serde_json::to_value(handle(&ws_server, 1, &subscription, text).await).unwrap();
+            assert_eq!((&reply["code"], reply.get("id")), (&json!("invalid_message"), None), "{}", text);
+        }
+        // Once the message is an object, its id comes back whatever is wrong with the rest.
+        let requests = [
+            json!({"type": "shout", "id": "a"}),
+            json!({"id": "a"}),
+            json!({"type": "ack", "id": "a"}),
+            json!({"type": "ack", "id": "a", "event_id": "latest"}),
+            json!({"type": "subscribe", "id": "a", "filter": {"nonsense": 1}}),
+        ];
+        for request in requests {
+            let reply = reply(&ws_server, 1, &subscription, request.clone()).await;
+            assert_eq!((&reply["code"], &reply["id"]), (&json!("invalid_message"), &json!("a")), "{}", request);
+        }
+    }
+
+    #[tokio::test]
+    async fn publishing_requires_a_grant() {
+        let ws_server = WebSocketServer::for_tests();
+        let grant = PublishGrant::Channels(["audit".to_string()].into_iter().collect());
+        for subscription in [subscription(PublishGrant::None), subscription(grant)] {
+            let (user_id, _rx) = ws_server.register(&subscription).await;
+            for channel in [json!(null), json!("orders"), json!("*")] {
+                let request = json!({"type": "publish", "id": 1, "channel": channel, "data": {}});
+                let reply = reply(&ws_server, user_id, &subscription, request).await;
+                assert_eq!((&reply["code"], &reply["id"]), (&json!("forbidden"), &json!(1)));
+            }
+            let respond = reply(&ws_server, user_id, &subscription, json!({"type": "respond"})).await;
+            assert_eq!(respond["code"], "forbidden");
+        }
+        let request = json!({"type": "publish", "channel": "Not Valid", "data": {}});
+        assert_eq!(reply(&ws_server, 1, &subscription(PublishGrant::All), request).await["code"], "invalid_channel");
+        assert_eq!(ws_server.replay.lock().await.next_id(), 1);
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use crate::server::Delivery;
+
+/// What happens when a message arrives for a client whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Evict the oldest queued message to make room.
+    DropOldest,
+    /// Discard the new message.
+    DropNewest,
+    /// Close the connection; the client is expected to reconnect and replay.
+    Disconnect,
+    /// Refuse the webhook with 503 so the sender retries later.
+    Block,
+}
+
+impl OverflowPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "drop_oldest" => Some(OverflowPolicy::DropOldest),
+            "drop_newest" => Some(OverflowPolicy::DropNewest),
+            "disconnect" => Some(OverflowPolicy::Disconnect),
+            "block" => Some(OverflowPolicy::Block),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OverflowPolicy::DropOldest => "drop_oldest",
+            OverflowPolicy::DropNewest => "drop_newest",
+            OverflowPolicy::Disconnect => "disconnect",
+            OverflowPolicy::Block => "block",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLimit {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+/// Queue limits for subscribers of each channel.
+#[derive(Debug, Clone)]
+pub struct QueueSettings {
+    pub default: QueueLimit,
+    pub channels: HashMap<String, QueueLimit>,
+}
+
+impl QueueSettings {
+    pub fn for_channel(&self, channel: &str) -> QueueLimit {
+        self.channels.get(channel).copied().unwrap_or(self.default)
+    }
+}
+
+/// Why a message was not queued; each variant hands the message back.
+#[derive(Debug)]
+pub enum PushError {
+    /// The receiving connection is gone.
+    Closed(Delivery),
+    /// The queue is full and its policy is not to evict.
+    Full(Delivery),
+    /// The queue was full and has been closed under the `disconnect` policy.
+    Overflowed(Delivery),
+}
+
+struct State {
+    items: VecDeque<Delivery>,
+    /// Slots held by outstanding [`Permit`]s, which count against capacity.
+    reserved: usize,
+    receiver_closed: bool,
+    sender_dropped: bool,
+    overflowed: bool,
+}
+
+struct Shared {
+    limit: QueueLimit,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+/// Creates the outbound queue of one client.
+pub fn queue(limit: QueueLimit) -> (QueueSender, QueueReceiver) {
+    let shared = Arc::new(Shared {
+        limit,
+        state: Mutex::new(State {
+            items: VecDeque::new(),
+            reserved: 0,
+            receiver_closed: false,
+            sender_dropped: false,
+            overflowed: false,
+        }),
+        notify: Notify::new(),
+    });
+    (QueueSender { shared: shared.clone() }, QueueReceiver { shared })
+}
+
+pub struct QueueSender {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for QueueSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueueSender").field("limit", &self.shared.limit).field("len", &self.len()).finish()
+    }
+}
+
+impl QueueSender {
+    /// Queues `delivery` according to the queue's overflow policy. Returns
+    /// the message evicted to make room for it, if any.
+    pub fn send(&self, delivery: Delivery) -> Result<Option<Delivery>, PushError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.receiver_closed || state.overflowed {
+            return Err(PushError::Closed(delivery));
+        }
+        let mut evicted = None;
+        if state.items.len() + state.reserved >= self.shared.limit.capacity {
+            match self.shared.limit.policy {
+                OverflowPolicy::DropOldest => evicted = state.items.pop_front(),
+                OverflowPolicy::DropNewest | OverflowPolicy::Block => return Err(PushError::Full(delivery)),
+                OverflowPolicy::Disconnect => {
+                    state.overflowed = true;
+                    drop(state);
+                    self.shared.notify.notify_one();
+                    return Err(PushError::Overflowed(delivery));
+                }
+            }
+        }
+        state.items.push_back(delivery);
+        drop(state);
+        self.shared.notify.notify_one();
+        Ok(evicted)
+    }
+
+    /// Queues `delivery` regardless of capacity, for replayed backlogs whose
+    /// size the client chose.
+    pub fn send_unbounded(&self, delivery: Delivery) {
+        self.shared.state.lock().unwrap().items.push_back(delivery);
+        self.shared.notify.notify_one();
+    }
+
+    /// Puts `delivery` ahead of everything queued, regardless of capacity.
+    pub fn send_first(&self, delivery: Delivery) {
+        self.shared.state.lock().unwrap().items.push_front(delivery);
+        self.shared.notify.notify_one();
+    }
+
+    /// Sets aside room for one message, which `send` can no longer take, or
+    /// returns `None` if the queue is full. The slot is freed when the permit
+    /// is used or dropped.
+    pub fn reserve(&self) -> Option<Permit> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.items.len() + state.reserved >= self.shared.limit.capacity {
+            return None;
+        }
+        state.reserved += 1;
+        Some(Permit { shared: self.shared.clone(), used: false })
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().items.len()
+    }
+
+    pub fn limit(&self) -> QueueLimit {
+        self.shared.limit
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().sender_dropped = true;
+        self.shared.notify.notify_one();
+    }
+}
+
+/// A slot reserved in a queue by [`QueueSender::reserve`].
+pub struct Permit {
+    shared: Arc<Shared>,
+    used: bool,
+}
+
+impl Permit {
+    /// Queues `delivery` in the reserved slot.
+    pub fn send(mut self, delivery: Delivery) -> Result<(), PushError> {
+        self.used = true;
+        let mut state = self.shared.state.lock().unwrap();
+        state.reserved -= 1;
+        if state.receiver_closed || state.overflowed {
+            return Err(PushError::Closed(delivery));
+        }
+        state.items.push_back(delivery);
+        drop(state);
+        self.shared.notify.notify_one();
+        Ok(())
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if !self.used {
+            self.shared.state.lock().unwrap().reserved -= 1;
+        }
+    }
+}
+
+pub struct QueueReceiver {
+    shared: Arc<Shared>,
+}
+
+impl QueueReceiver {
+    /// Waits for the next message. Returns `None` once the sender is gone and
+    /// the queue is drained, or as soon as the queue overflowed under the
+    /// `disconnect` policy.
+    pub async fn recv(&mut self) -> Option<Delivery> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.overflowed {
+                    return None;
+                }
+                if let Some(delivery) = state.items.pop_front() {
+                    return Some(delivery);
+                }
+                if state.sender_dropped {
+                    return None;
+                }
+            }
+            // A notification sent since the check above is stored as a
+            // permit, so this cannot miss a wakeup.
+            self.shared.notify.notified().await;
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<Delivery> {
+        self.shared.state.lock().unwrap().items.pop_front()
+    }
+
+    /// Refuses further messages; those already queued can still be taken
+    /// with `try_recv`.
+    pub fn close(&mut self) {
+        self.shared.state.lock().unwrap().receiver_closed = true;
+    }
+
+    /// Whether the queue was closed because the client fell too far behind.
+    pub fn overflowed(&self) -> bool {
+        self.shared.state.lock().unwrap().overflowed
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limited(capacity: usize, policy: OverflowPolicy) -> (QueueSender, QueueReceiver) {
+        queue(QueueLimit { capacity, policy })
+    }
+
+    fn event(id: u64) -> Delivery {
+        Delivery::text(Some(id), id.to_string())
+    }
+
+    fn drain(rx: &mut QueueReceiver) -> Vec<Option<u64>> {
+        std::iter::from_fn(|| rx.try_recv()).map(|delivery| delivery.id).collect()
+    }
+
+    #[test]
+    fn drop_oldest_evicts_to_make_room() {
+        let (tx, mut rx) = limited(2, OverflowPolicy::DropOldest);
+        assert!(tx.send(event(1)).unwrap().is_none());
+        assert!(tx.send(event(2)).unwrap().is_none());
+        assert_eq!(tx.send(event(3)).unwrap().and_then(|evicted| evicted.id), Some(1));
+        assert_eq!(drain(&mut rx), [Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn drop_newest_and_block_refuse_when_full() {
+        for policy in [OverflowPolicy::DropNewest, OverflowPolicy::Block] {
+            let (tx, mut rx) = limited(1, policy);
+            tx.send(event(1)).unwrap();
+            assert!(matches!(tx.send(event(2)), Err(PushError::Full(delivery)) if delivery.id == Some(2)), "{}", policy);
+            assert_eq!(drain(&mut rx), [Some(1)]);
+            tx.send(event(3)).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_the_queue_on_overflow() {
+        let (tx, mut rx) = limited(1, OverflowPolicy::Disconnect);
+        tx.send(event(1)).unwrap();
+        assert!(matches!(tx.send(event(2)), Err(PushError::Overflowed(_))));
+        assert!(rx.overflowed());
+        assert!(rx.recv().await.is_none());
+        assert!(matches!(tx.send(event(3)), Err(PushError::Closed(_))));
+    }
+
+    #[test]
+    fn replays_and_close_frames_ignore_capacity() {
+        let (tx, mut rx) = limited(1, OverflowPolicy::Block);
+        tx.send(event(2)).unwrap();
+        tx.send_unbounded(event(3));
+        tx.send_first(Delivery::close(1001, "bye", crate::deadletter::Reason::Shutdown));
+        assert_eq!(drain(&mut rx), [None, Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn reserved_slots_count_against_capacity() {
+        let (tx, mut rx) = limited(2, OverflowPolicy::Block);
+        let permit = tx.reserve().unwrap();
+        tx.send(event(1)).unwrap();
+        // The reserved slot is not taken by a concurrent sender, and is still
+        // there for the permit.
+        assert!(matches!(tx.send(event(2)), Err(PushError::Full(_))));
+        assert!(tx.reserve().is_none());
+        permit.send(event(3)).unwrap();
+        assert_eq!(drain(&mut rx), [Some(1), Some(3)]);
+
+        let permit = tx.reserve().unwrap();
+        drop(permit);
+        tx.send(event(4)).unwrap();
+        tx.send(event(5)).unwrap();
+        assert!(tx.reserve().is_none());
+    }
+
+    #[test]
+    fn permit_to_closed_queue_hands_the_message_back() {
+        let (tx, mut rx) = limited(1, OverflowPolicy::Block);
+        let permit = tx.reserve().unwrap();
+        rx.close();
+        assert!(matches!(permit.send(event(1)), Err(PushError::Closed(delivery)) if delivery.id == Some(1)));
+        assert!(rx.try_recv().is_none());
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::http::{HeaderMap, StatusCode};
+use warp::{Filter, Reply};
+use log::warn;
+use crate::auth::Auth;
+use crate::listener::{self, ConnectionInfo};
+use crate::metrics::Metrics;
+
+/// How often buckets that have refilled completely are forgotten.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A token bucket: `burst` requests at once, refilled at `per_second`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitSettings {
+    /// Webhooks from one source IP.
+    pub webhook_ip: Option<Rate>,
+    /// Webhooks published on one channel, whoever sends them.
+    pub webhook_endpoint: Option<Rate>,
+    /// Webhooks carrying one principal's token.
+    pub webhook_key: Option<Rate>,
+    /// WebSocket upgrade requests from one source IP.
+    pub ws_upgrade_ip: Option<Rate>,
+    /// Open WebSocket connections from one source IP.
+    pub ws_connections_per_ip: Option<usize>,
+}
+
+/// Rejection for a request over one of the limits; answered with 429.
+#[derive(Debug)]
+pub struct RateLimited {
+    /// When the request would next be accepted; `None` when that depends on
+    /// other connections closing.
+    pub retry_after: Option<Duration>,
+}
+
+impl warp::reject::Reject for RateLimited {}
+
+impl RateLimited {
+    /// 429, with `Retry-After` in whole seconds when known.
+    pub fn response(&self) -> warp::reply::Response {
+        let reply = warp::reply::with_status("Too many requests", StatusCode::TOO_MANY_REQUESTS).into_response();
+        match self.retry_after {
+            Some(retry_after) => {
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                warp::reply::with_header(reply, "retry-after", secs.to_string()).into_response()
+            }
+            None => reply,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// One token bucket per key, created full on first use.
+struct Buckets<K> {
+    rate: Rate,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K: Hash + Eq> Buckets<K> {
+    fn new(rate: Rate) -> Self {
+        Self { rate, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate.per_second).min(self.rate.burst as f64)
+    }
+
+    /// Takes a token for `key`, or says how long until one is available.
+    fn take(&self, key: K) -> Result<(), Duration> {
+        self.take_at(key, Instant::now())
+    }
+
+    fn take_at(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key).or_insert(Bucket { tokens: self.rate.burst as f64, updated: now });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate.per_second))
+    }
+
+    /// Forgets full buckets, which behave exactly like ones never used.
+    fn sweep(&self) {
+        self.sweep_at(Instant::now())
+    }
+
+    fn sweep_at(&self, now: Instant) {
+        let burst = self.rate.burst as f64;
+        self.buckets.lock().unwrap().retain(|_, bucket| self.refilled(bucket, now) < burst);
+    }
+}
+
+/// Open WebSocket connections by source IP.
+type OpenConnections = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+struct Limits {
+    webhook_ip: Option<Buckets<IpAddr>>,
+    /// Keyed by channel.
+    webhook_endpoint: Option<Buckets<String>>,
+    webhook_key: Option<Buckets<String>>,
+    ws_upgrade_ip: Option<Buckets<IpAddr>>,
+    ws_connections_per_ip: Option<usize>,
+    /// Only counted when they are limited.
+    connections: OpenConnections,
+    metrics: Metrics,
+}
+
+/// Token-bucket limits on webhooks and WebSocket upgrades, and a cap on the
+/// WebSocket connections one IP may hold open.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limits: Arc<Limits>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings, metrics: Metrics) -> Self {
+        Self {
+            limits: Arc::new(Limits {
+                webhook_ip: settings.webhook_ip.map(Buckets::new),
+                webhook_endpoint: settings.webhook_endpoint.map(Buckets::new),
+                webhook_key: settings.webhook_key.map(Buckets::new),
+                ws_upgrade_ip: settings.ws_upgrade_ip.map(Buckets::new),
+                ws_connections_per_ip: settings.ws_connections_per_ip,
+                connections: Arc::new(Mutex::new(HashMap::new())),
+                metrics,
+            }),
+        }
+    }
+
+    fn refuse(&self, limit: &str, ip: IpAddr, retry_after: Option<Duration>) -> RateLimited {
+        warn!("Refused request from {} over the {} limit", ip, limit);
+        self.limits.metrics.rate_limited(limit);
+        RateLimited { retry_after }
+    }
+
+    /// Checks a webhook against the source IP and principal limits, in that
+    /// order, so a sender over its own limit does not use up its principal's
+    /// tokens.
+    fn check_webhook(&self, ip: IpAddr, headers: &HeaderMap, auth: &Auth) -> Result<(), RateLimited> {
+        let limits = &self.limits;
+        if let Some(Err(retry_after)) = limits.webhook_ip.as_ref().map(|buckets| buckets.take(ip)) {
+            return Err(self.refuse("webhook_ip", ip, Some(retry_after)));
+        }
+        if let Some(buckets) = &limits.webhook_key {
+            // Requests without a valid token are only limited by IP.
+            if let Some(Err(retry_after)) = auth.identify(headers).map(|principal| buckets.take(principal.name.clone())) {
+                return Err(self.refuse("webhook_key", ip, Some(retry_after)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a webhook against the limit of the channel it is published
+    /// on. Only checked once the channel is known to exist and the webhook
+    /// is verified, so made-up paths get no bucket and unsigned requests do
+    /// not use up a channel's tokens.
+    pub fn check_channel(&self, ip: IpAddr, channel: &str) -> Result<(), RateLimited> {
+        match self.limits.webhook_endpoint.as_ref().map(|buckets| buckets.take(channel.to_string())) {
+            Some(Err(retry_after)) => Err(self.refuse("webhook_endpoint", ip, Some(retry_after))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks an upgrade request and reserves one of the source IP's
+    /// connections, released when the returned permit is dropped.
+    fn admit_upgrade(&self, ip: IpAddr) -> Result<ConnectionPermit, warp::Rejection> {
+        let limits = &self.limits;
+        if let Some(Err(retry_after)) = limits.ws_upgrade_ip.as_ref().map(|buckets| buckets.take(ip)) {
+            return Err(warp::reject::custom(self.refuse("ws_upgrade_ip", ip, Some(retry_after))));
+        }
+        let Some(max) = limits.ws_connections_per_ip else {
+            return Ok(ConnectionPermit { held: None });
+        };
+        let mut connections = limits.connections.lock().unwrap();
+        let open = connections.entry(ip).or_default();
+        if *open >= max {
+            drop(connections);
+            return Err(warp::reject::custom(self.refuse("ws_connections_ip", ip, None)));
+        }
+        *open += 1;
+        Ok(ConnectionPermit { held: Some((limits.connections.clone(), ip)) })
+    }
+
+    fn sweep(&self) {
+        let limits = &self.limits;
+        if let Some(buckets) = &limits.webhook_ip {
+            buckets.sweep();
+        }
+        if let Some(buckets) = &limits.webhook_endpoint {
+            buckets.sweep();
+        }
+        if let Some(buckets) = &limits.webhook_key {
+            buckets.sweep();
+        }
+        if let Some(buckets) = &limits.ws_upgrade_ip {
+            buckets.sweep();
+        }
+    }
+}
+
+/// One of an IP's WebSocket connections, held for as long as it is open.
+pub struct ConnectionPermit {
+    held: Option<(OpenConnections, IpAddr)>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some((connections, ip)) = &self.held {
+            let mut connections = connections.lock().unwrap();
+            if let Some(open) = connections.get_mut(ip) {
+                *open -= 1;
+                if *open == 0 {
+                    connections.remove(ip);
+                }
+            }
+        }
+    }
+}
+
+/// Passes only webhooks within the source IP and principal limits,
+/// rejecting the others with [`RateLimited`] before their body is read.
+pub fn webhook_guard(limiter: RateLimiter, auth: Arc<Auth>) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    listener::connection_info()
+        .and(warp::header::headers_cloned())
+        .and_then(move |info: ConnectionInfo, headers: HeaderMap| {
+            let (limiter, auth) = (limiter.clone(), auth.clone());
+            async move { limiter.check_webhook(info.remote_addr.ip(), &headers, &auth).map_err(warp::reject::custom) }
+        })
+        .untuple_one()
+}
+
+/// Passes only upgrade requests within the rate limits, extracting the
+/// permit the connection must hold while it is open.
+pub fn upgrade_guard(limiter: RateLimiter) -> impl Filter<Extract = (ConnectionPermit,), Error = warp::Rejection> + Clone {
+    listener::connection_info().and_then(move |info: ConnectionInfo| {
+        let limiter = limiter.clone();
+        async move { limiter.admit_upgrade(info.remote_addr.ip()) }
+    })
+}
+
+pub fn spawn_sweep_task(limiter: RateLimiter) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            limiter.sweep();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buckets(per_second: f64, burst: u32) -> Buckets<&'static str> {
+        Buckets::new(Rate { per_second, burst })
+    }
+
+    fn later(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills() {
+        let buckets = buckets(2.0, 3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(buckets.take_at("a", start), Ok(()));
+        }
+        assert_eq!(buckets.take_at("a", start), Err(Duration::from_millis(500)));
+        // Other keys have buckets of their own.
+        assert_eq!(buckets.take_at("b", start), Ok(()));
+        // Half a token after 250ms, so another 250ms to wait.
+        assert_eq!(buckets.take_at("a", later(start, 250)), Err(Duration::from_millis(250)));
+        assert_eq!(buckets.take_at("a", later(start, 500)), Ok(()));
+        assert!(buckets.take_at("a", later(start, 500)).is_err());
+    }
+
+    #[test]
+    fn refill_is_capped_at_the_burst() {
+        let buckets = buckets(10.0, 2);
+        let start = Instant::now();
+        assert_eq!(buckets.take_at("a", start), Ok(()));
+        let idle = later(start, 60_000);
+        assert_eq!(buckets.take_at("a", idle), Ok(()));
+        assert_eq!(buckets.take_at("a", idle), Ok(()));
+        assert_eq!(buckets.take_at("a", idle), Err(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn refused_requests_do_not_use_tokens() {
+        let buckets = buckets(1.0, 1);
+        let start = Instant::now();
+        assert_eq!(buckets.take_at("a", start), Ok(()));
+        for millis in [100, 200, 300] {
+            assert!(buckets.take_at("a", later(start, millis)).is_err());
+        }
+        assert_eq!(buckets.take_at("a", later(start, 1_000)), Ok(()));
+    }
+
+    #[test]
+    fn sweep_forgets_only_full_buckets() {
+        let buckets = buckets(1.0, 2);
+        let start = Instant::now();
+        buckets.take_at("a", start).unwrap();
+        buckets.take_at("b", later(start, 900)).unwrap();
+        buckets.sweep_at(later(start, 1_000));
+        assert_eq!(buckets.buckets.lock().unwrap().keys().copied().collect::<Vec<_>>(), ["b"]);
+    }
+
+    #[test]
+    fn retry_after_rounds_up_to_whole_seconds() {
+        let retry_after = |retry_after| RateLimited { retry_after }.response().headers().get("retry-after").cloned();
+        assert_eq!(retry_after(Some(Duration::from_millis(1))).unwrap(), "1");
+        assert_eq!(retry_after(Some(Duration::from_secs(2))).unwrap(), "2");
+        assert_eq!(retry_after(Some(Duration::from_millis(2_001))).unwrap(), "3");
+        assert_eq!(retry_after(None), None);
+    }
+
+    #[test]
+    fn channel_limit_is_per_channel() {
+        let settings = RateLimitSettings { webhook_endpoint: Some(Rate { per_second: 0.001, burst: 1 }), ..Default::default() };
+        let limiter = RateLimiter::new(settings, Metrics::new());
+        let (a, b): (IpAddr, IpAddr) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        assert!(limiter.check_channel(a, "orders").is_ok());
+        // Whoever sends them.
+        assert!(limiter.check_channel(b, "orders").is_err());
+        assert!(limiter.check_channel(a, "invoices").is_ok());
+        let unlimited = RateLimiter::new(RateLimitSettings::default(), Metrics::new());
+        assert!((0..100).all(|_| unlimited.check_channel(a, "orders").is_ok()));
+    }
+}
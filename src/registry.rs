@@ -0,0 +1,120 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+/// Number of shards. A fan-out holds one shard's lock at a time, so a client
+/// connecting or leaving waits for at most one shard's worth of it.
+pub const SHARDS: usize = 32;
+
+/// Something registered under a channel.
+pub trait Member {
+    fn channel(&self) -> &str;
+}
+
+struct Shard<T> {
+    members: HashMap<usize, T>,
+    /// Channel name -> ids of this shard's members subscribed to it.
+    channels: HashMap<String, HashSet<usize>>,
+}
+
+impl<T> Default for Shard<T> {
+    fn default() -> Self {
+        Self { members: HashMap::new(), channels: HashMap::new() }
+    }
+}
+
+/// Registered clients by id, split by id into shards that are locked
+/// independently. Locks are never held across an await: every access runs a
+/// closure under the shard's lock.
+pub struct Registry<T> {
+    shards: Box<[RwLock<Shard<T>>]>,
+}
+
+impl<T: Member> Default for Registry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Member> Registry<T> {
+    pub fn new() -> Self {
+        Self { shards: (0..SHARDS).map(|_| RwLock::default()).collect() }
+    }
+
+    fn shard(&self, id: usize) -> &RwLock<Shard<T>> {
+        &self.shards[id % SHARDS]
+    }
+
+    pub fn insert(&self, id: usize, member: T) {
+        let mut shard = self.shard(id).write().unwrap();
+        if let Some(previous) = shard.members.remove(&id) {
+            Self::unindex(&mut shard, id, &previous);
+        }
+        shard.channels.entry(member.channel().to_string()).or_default().insert(id);
+        shard.members.insert(id, member);
+    }
+
+    pub fn remove(&self, id: usize) -> Option<T> {
+        let mut shard = self.shard(id).write().unwrap();
+        let member = shard.members.remove(&id)?;
+        Self::unindex(&mut shard, id, &member);
+        Some(member)
+    }
+
+    fn unindex(shard: &mut Shard<T>, id: usize, member: &T) {
+        if let Some(ids) = shard.channels.get_mut(member.channel()) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                shard.channels.remove(member.channel());
+            }
+        }
+    }
+
+    pub fn get<R>(&self, id: usize, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.shard(id).read().unwrap().members.get(&id).map(f)
+    }
+
+    pub fn get_mut<R>(&self, id: usize, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.shard(id).write().unwrap().members.get_mut(&id).map(f)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().members.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Calls `f` on the members subscribed to any of `channels`, or on every
+    /// member for `None`, one shard at a time, until it returns `Some`.
+    /// Members registered or removed meanwhile may or may not be visited.
+    pub fn find_map<R>(&self, channels: Option<&[&str]>, mut f: impl FnMut(&T) -> Option<R>) -> Option<R> {
+        for shard in self.shards.iter() {
+            let shard = shard.read().unwrap();
+            let found = match channels {
+                None => shard.members.values().find_map(&mut f),
+                Some(channels) => channels
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, name)| !channels[..*i].contains(name))
+                    .filter_map(|(_, name)| shard.channels.get(*name))
+                    .flatten()
+                    .filter_map(|id| shard.members.get(id))
+                    .find_map(&mut f),
+            };
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+
+    /// Calls `f` on the members subscribed to any of `channels`, or on every
+    /// member for `None`; see `find_map`.
+    pub fn for_each(&self, channels: Option<&[&str]>, mut f: impl FnMut(&T)) {
+        self.find_map(channels, |member| {
+            f(member);
+            None::<()>
+        });
+    }
+}
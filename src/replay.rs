@@ -0,0 +1,269 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+use crate::eventlog::EventLog;
+use crate::server::ALL_CHANNELS;
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub id: u64,
+    /// Shared with the replay buffer and every queue the event is in.
+    pub payload: Arc<str>,
+}
+
+/// What a reconnecting client asked to be replayed, from `?since=<id>`
+/// and/or `?last=N`. With both, the newest `last` events after `since` win.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayRequest {
+    pub since: Option<u64>,
+    pub last: Option<usize>,
+}
+
+impl ReplayRequest {
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Self, String> {
+        let since = match query.get("since") {
+            Some(value) => Some(value.parse().map_err(|_| format!("invalid since {:?}", value))?),
+            None => None,
+        };
+        let last = match query.get("last") {
+            Some(value) => Some(value.parse().map_err(|_| format!("invalid last {:?}", value))?),
+            None => None,
+        };
+        Ok(Self { since, last })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.since.is_none() && self.last.is_none()
+    }
+}
+
+/// Bounded per-channel history of published events, for a bounded number of
+/// channels. Event ids are global and strictly increasing, so they also
+/// order events across channels.
+#[derive(Debug)]
+pub struct ReplayBuffer {
+    next_id: u64,
+    capacity: usize,
+    max_channels: usize,
+    channels: HashMap<String, VecDeque<Event>>,
+    /// Newest event of any channel dropped to make room for another; replays
+    /// reaching back that far may be missing events.
+    evicted_up_to: u64,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize, max_channels: usize) -> Self {
+        Self { next_id: 1, capacity, max_channels, channels: HashMap::new(), evicted_up_to: 0 }
+    }
+
+    /// Refills the buffer from the tail of the on-disk log after a restart
+    /// and continues numbering after its newest event.
+    pub fn restore_from(&mut self, log: &EventLog) -> io::Result<()> {
+        log.scan(|record| self.record(&record.channel, Event { id: record.id, payload: record.payload.into() }))?;
+        self.next_id = self.next_id.max(log.last_id() + 1);
+        Ok(())
+    }
+
+    /// The id the next pushed event will get.
+    pub fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    /// Assigns the next id to `payload` and records it on `channel`.
+    pub fn push(&mut self, channel: &str, payload: String) -> Event {
+        let event = Event { id: self.next_id, payload: payload.into() };
+        self.next_id += 1;
+        self.record(channel, event.clone());
+        event
+    }
+
+    fn record(&mut self, channel: &str, event: Event) {
+        if self.capacity > 0 {
+            if self.channels.len() >= self.max_channels && !self.channels.contains_key(channel) {
+                self.evict_least_recent();
+            }
+            let buffer = self.channels.entry(channel.to_string()).or_default();
+            if buffer.len() == self.capacity {
+                buffer.pop_front();
+            }
+            buffer.push_back(event);
+        }
+    }
+
+    /// Drops the channel published to least recently, which is the one whose
+    /// newest event is the oldest.
+    fn evict_least_recent(&mut self) {
+        let newest = |buffer: &VecDeque<Event>| buffer.back().map_or(0, |event| event.id);
+        let Some(channel) = self.channels.iter().min_by_key(|(_, buffer)| newest(buffer)).map(|(name, _)| name.clone()) else {
+            return;
+        };
+        if let Some(buffer) = self.channels.remove(&channel) {
+            self.evicted_up_to = self.evicted_up_to.max(newest(&buffer));
+        }
+    }
+
+    /// Drops the events buffered for `channel`, which will see no more.
+    pub fn forget(&mut self, channel: &str) {
+        self.channels.remove(channel);
+    }
+
+    /// Returns the buffered events matching `request` for a subscriber of
+    /// `channel`, oldest first, and whether they are complete. They are not
+    /// when a buffer has already evicted events the request reaches back to,
+    /// or a whole channel was dropped since, in which case the caller should
+    /// fall back to the event log.
+    pub fn replay(&self, channel: &str, request: ReplayRequest) -> (Vec<Event>, bool) {
+        if request.is_empty() {
+            return (Vec::new(), true);
+        }
+        let since = request.since.unwrap_or(0);
+        let buffers: Vec<&VecDeque<Event>> = self
+            .channels
+            .iter()
+            .filter(|(name, _)| channel == ALL_CHANNELS || name.as_str() == channel)
+            .map(|(_, buffer)| buffer)
+            .collect();
+        let mut events: Vec<Event> = buffers
+            .iter()
+            .flat_map(|buffer| buffer.iter().filter(|event| event.id > since).cloned())
+            .collect();
+        events.sort_by_key(|event| event.id);
+        if let Some(last) = request.last {
+            let skip = events.len().saturating_sub(last);
+            events.drain(..skip);
+        }
+        let oldest_wanted = match (request.last, events.first()) {
+            (Some(last), Some(first)) if events.len() == last => first.id,
+            _ => since + 1,
+        };
+        let complete = self.capacity > 0
+            && self.evicted_up_to < oldest_wanted
+            && buffers
+                .iter()
+                .all(|buffer| buffer.len() < self.capacity || buffer.front().is_some_and(|e| e.id <= oldest_wanted));
+        (events, complete)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(events: &[Event]) -> Vec<u64> {
+        events.iter().map(|event| event.id).collect()
+    }
+
+    fn since(id: u64) -> ReplayRequest {
+        ReplayRequest { since: Some(id), last: None }
+    }
+
+    #[test]
+    fn drops_least_recently_published_channel() {
+        let mut buffer = ReplayBuffer::new(10, 2);
+        buffer.push("a", "1".to_string());
+        buffer.push("b", "2".to_string());
+        buffer.push("a", "3".to_string());
+        buffer.push("c", "4".to_string());
+        assert_eq!(buffer.channels.len(), 2);
+        assert!(!buffer.channels.contains_key("b"));
+        let (events, complete) = buffer.replay("a", since(0));
+        assert_eq!(ids(&events), [1, 3]);
+        // Event 2 went with channel b, so nothing can vouch for ids up to it.
+        assert!(!complete);
+        let (events, complete) = buffer.replay("a", since(2));
+        assert_eq!((ids(&events), complete), (vec![3], true));
+        let (events, complete) = buffer.replay(ALL_CHANNELS, since(2));
+        assert_eq!((ids(&events), complete), (vec![3, 4], true));
+    }
+
+    #[test]
+    fn forgetting_a_channel_keeps_others_complete() {
+        let mut buffer = ReplayBuffer::new(10, 10);
+        buffer.push("hook.x", "1".to_string());
+        buffer.push("a", "2".to_string());
+        buffer.forget("hook.x");
+        let (events, complete) = buffer.replay(ALL_CHANNELS, since(0));
+        assert_eq!((ids(&events), complete), (vec![2], true));
+    }
+
+    fn last(n: usize) -> ReplayRequest {
+        ReplayRequest { since: None, last: Some(n) }
+    }
+
+    /// Channel a with events 1 to 5, of which it keeps the newest 3, and
+    /// channel b with event 6.
+    fn evicting() -> ReplayBuffer {
+        let mut buffer = ReplayBuffer::new(3, 10);
+        for id in 1..=5 {
+            buffer.push("a", id.to_string());
+        }
+        buffer.push("b", "6".to_string());
+        buffer
+    }
+
+    #[test]
+    fn since_is_complete_only_while_the_buffer_reaches_back() {
+        let buffer = evicting();
+        assert!(buffer.replay("a", since(2)).1);
+        let (events, complete) = buffer.replay("a", since(1));
+        assert_eq!((ids(&events), complete), (vec![3, 4, 5], false));
+        assert!(!buffer.replay("a", since(0)).1);
+        let (events, complete) = buffer.replay("a", since(5));
+        assert_eq!((ids(&events), complete), (vec![], true));
+        // A channel that never filled its buffer has all its events.
+        let (events, complete) = buffer.replay("b", since(0));
+        assert_eq!((ids(&events), complete), (vec![6], true));
+    }
+
+    #[test]
+    fn last_is_complete_when_enough_events_are_buffered() {
+        let buffer = evicting();
+        let (events, complete) = buffer.replay("a", last(3));
+        assert_eq!((ids(&events), complete), (vec![3, 4, 5], true));
+        let (events, complete) = buffer.replay("a", last(2));
+        assert_eq!((ids(&events), complete), (vec![4, 5], true));
+        // Events 1 and 2 would have been among the last 4.
+        let (events, complete) = buffer.replay("a", last(4));
+        assert_eq!((ids(&events), complete), (vec![3, 4, 5], false));
+        let (events, complete) = buffer.replay("b", last(10));
+        assert_eq!((ids(&events), complete), (vec![6], true));
+        let (events, complete) = buffer.replay("a", ReplayRequest { since: Some(3), last: Some(1) });
+        assert_eq!((ids(&events), complete), (vec![5], true));
+    }
+
+    #[test]
+    fn all_channels_merges_in_id_order() {
+        let buffer = evicting();
+        let (events, complete) = buffer.replay(ALL_CHANNELS, since(2));
+        assert_eq!((ids(&events), complete), (vec![3, 4, 5, 6], true));
+        let (events, complete) = buffer.replay(ALL_CHANNELS, last(2));
+        assert_eq!((ids(&events), complete), (vec![5, 6], true));
+        // Channel a no longer has events 1 and 2.
+        assert!(!buffer.replay(ALL_CHANNELS, since(0)).1);
+    }
+
+    #[test]
+    fn without_capacity_nothing_is_complete() {
+        let mut buffer = ReplayBuffer::new(0, 10);
+        assert_eq!(buffer.push("a", "1".to_string()).id, 1);
+        assert_eq!(buffer.push("a", "2".to_string()).id, 2);
+        let (events, complete) = buffer.replay("a", since(0));
+        assert_eq!((ids(&events), complete), (vec![], false));
+        // Nothing asked, nothing missing.
+        let (events, complete) = buffer.replay("a", ReplayRequest::default());
+        assert_eq!((ids(&events), complete), (vec![], true));
+    }
+
+    #[test]
+    fn parses_the_query() {
+        let query = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+        };
+        assert_eq!(ReplayRequest::from_query(&query(&[])), Ok(ReplayRequest::default()));
+        assert_eq!(
+            ReplayRequest::from_query(&query(&[("since", "7"), ("last", "2")])),
+            Ok(ReplayRequest { since: Some(7), last: Some(2) })
+        );
+        assert!(ReplayRequest::from_query(&query(&[("last", "-1")])).is_err());
+    }
+}
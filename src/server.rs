@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use serde::Serialize;
+use log::{info, warn};
+use crate::backplane::Node;
+use crate::bridge::Bridge;
+use crate::deadletter::{DeadLetters, PayloadEncoding, Reason, Undeliverable};
+use crate::eventlog::{EventLog, LogRecord};
+use crate::filter::Filter;
+use crate::metrics::Metrics;
+use crate::queue::{self, OverflowPolicy, Permit, PushError, QueueReceiver, QueueSender, QueueSettings};
+use crate::registry::{Member, Registry};
+use crate::replay::ReplayBuffer;
+use crate::subscription::Subscription;
+
+/// Channel used by `POST /webhook` when no channel is given in the path.
+pub const DEFAULT_CHANNEL: &str = "default";
+
+/// Subscription key for clients connected to `/ws` without a channel; they
+/// receive the traffic of every channel.
+pub const ALL_CHANNELS: &str = "*";
+
+/// Channel names come straight from URL path segments, so keep them to a
+/// conservative character set.
+pub fn is_valid_channel(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// What a delivery asks the connection to write.
+#[derive(Debug, Clone)]
+pub enum Outbound {
+    /// Shared by every recipient of the same message, so fanning it out
+    /// does not copy it.
+    Text(Arc<str>),
+    /// `undelivered` is the dead-letter reason for whatever is still queued
+    /// behind the close frame.
+    Close { code: u16, reason: String, undelivered: Reason },
+}
+
+impl fmt::Display for Outbound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outbound::Text(text) => f.write_str(text),
+            Outbound::Close { reason, .. } => f.write_str(reason),
+        }
+    }
+}
+
+/// An entry in a user's outbound queue.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    /// Id of the published event this carries; `None` for direct messages.
+    pub id: Option<u64>,
+    pub message: Outbound,
+}
+
+impl Delivery {
+    pub fn text(id: Option<u64>, text: impl Into<Arc<str>>) -> Self {
+        Self { id, message: Outbound::Text(text.into()) }
+    }
+
+    /// Asks the connection to close with `code`; nothing queued after it is
+    /// sent, and is dead-lettered as `undelivered`.
+    pub fn close(code: u16, reason: &str, undelivered: Reason) -> Self {
+        Self { id: None, message: Outbound::Close { code, reason: reason.to_string(), undelivered } }
+    }
+}
+
+#[derive(Debug)]
+pub struct User {
+    pub id: usize,
+    pub channel: String,
+    pub remote_addr: SocketAddr,
+    /// Name of the authenticated principal, if WebSocket auth is enabled.
+    pub principal: Option<String>,
+    /// Cleared by an unsubscribe message: no events are sent until the
+    /// client subscribes again.
+    pub subscribed: bool,
+    /// Only events matching this are sent; set by a subscribe message.
+    pub filter: Option<Arc<Filter>>,
+    /// Highest event id the client has acknowledged.
+    pub last_ack: Option<u64>,
+    /// Unix time in milliseconds at which the client connected.
+    pub connected_at: u64,
+    /// When the client last answered a heartbeat ping, or connected.
+    pub last_pong: Instant,
+    pub tx: QueueSender,
+}
+
+impl User {
+    /// Whether the user wants `json`: it is subscribed and its filter lets
+    /// the event through. `parsed` caches the parsed event across users so
+    /// it is parsed at most once per send.
+    fn wants(&self, json: &str, parsed: &mut Option<serde_json::Value>) -> bool {
+        match &self.filter {
+            _ if !self.subscribed => false,
+            Some(filter) => filter.matches(parsed.get_or_insert_with(|| serde_json::from_str(json).unwrap_or_default())),
+            None => true,
+        }
+    }
+
+    pub fn info(&self) -> ClientInfo {
+        ClientInfo {
+            id: self.id,
+            channel: self.channel.clone(),
+            remote_addr: self.remote_addr,
+            principal: self.principal.clone(),
+            subscribed: self.subscribed,
+            filter: self.filter.as_deref().cloned(),
+            last_ack: self.last_ack,
+            connected_at: self.connected_at,
+            queue_depth: self.tx.len(),
+            queue_capacity: self.tx.limit().capacity,
+            queue_policy: self.tx.limit().policy,
+        }
+    }
+}
+
+impl Member for User {
+    fn channel(&self) -> &str {
+        &self.channel
+    }
+}
+
+/// A snapshot of a connected user, as reported to clients and admins.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientInfo {
+    pub id: usize,
+    pub channel: String,
+    pub remote_addr: SocketAddr,
+    pub principal: Option<String>,
+    pub subscribed: bool,
+    pub filter: Option<Filter>,
+    pub last_ack: Option<u64>,
+    pub connected_at: u64,
+    /// Messages waiting to be written to the client.
+    pub queue_depth: usize,
+    pub queue_capacity: usize,
+    pub queue_policy: queue::OverflowPolicy,
+}
+
+/// Why a message could not be queued for a user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    NoSuchUser,
+    /// The user is registered but its connection has already closed.
+    Closed,
+    /// The user's queue is full.
+    Full,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::NoSuchUser => write!(f, "no such user"),
+            SendError::Closed => write!(f, "connection closed"),
+            SendError::Full => write!(f, "queue full"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// Returned by `publish` and `broadcast`, without sending anything, when a
+/// recipient's queue is full under the `block` policy.
+#[derive(Debug)]
+pub struct Backpressure {
+    pub user_id: usize,
+}
+
+impl fmt::Display for Backpressure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "queue of user {} is full", self.user_id)
+    }
+}
+
+impl std::error::Error for Backpressure {}
+
+/// WebSocket keepalive: a ping every `interval`; a client that has answered
+/// none of the last `max_missed` is evicted.
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub max_missed: u32,
+}
+
+#[derive(Clone)]
+pub struct WebSocketServer {
+    /// Sharded so that fanning a message out to every user does not hold up
+    /// users connecting or leaving.
+    pub users: Arc<Registry<User>>,
+    pub next_id: Arc<Mutex<usize>>,
+    /// Recent events per channel. Held while publishing and while registering
+    /// a user with a replay, so that the two are ordered against each other.
+    pub replay: Arc<Mutex<ReplayBuffer>>,
+    /// Durable copy of every published event, when enabled. Only accessed
+    /// while holding `replay`, which keeps appends in id order.
+    pub log: Option<Arc<std::sync::Mutex<EventLog>>>,
+    /// Messages that could not be delivered to their user.
+    pub dead_letters: DeadLetters,
+    /// Outbound queue capacity and overflow policy by channel.
+    pub queues: Arc<QueueSettings>,
+    pub metrics: Metrics,
+    /// `None` disables heartbeats.
+    pub heartbeat: Option<Heartbeat>,
+    /// Clients answering their channel's webhooks, and the webhooks waiting.
+    pub bridge: Bridge,
+    /// Relays events to and from the other instances of a cluster.
+    pub node: Node,
+}
+
+impl WebSocketServer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        replay: ReplayBuffer,
+        log: Option<Arc<std::sync::Mutex<EventLog>>>,
+        dead_letters: DeadLetters,
+        queues: QueueSettings,
+        metrics: Metrics,
+        heartbeat: Option<Heartbeat>,
+        bridge: Bridge,
+        node: Node,
+    ) -> Self {
+        Self {
+            users: Arc::new(Registry::new()),
+            next_id: Arc::new(Mutex::new(0)),
+            replay: Arc::new(Mutex::new(replay)),
+            log,
+            dead_letters,
+            queues: Arc::new(queues),
+            metrics,
+            heartbeat,
+            bridge,
+            node,
+        }
+    }
+
+    /// Registers a new user for `subscription` and returns its id along with
+    /// the receiving end of its outbound queue. Events selected by the
+    /// subscription's replay request are queued ahead of any live traffic.
+    pub async fn register(&self, subscription: &Subscription) -> (usize, QueueReceiver) {
+        let Subscription { channel, replay, .. } = subscription;
+        let (channel, replay) = (channel.as_str(), *replay);
+        let id = {
+            let mut next_id = self.next_id.lock().await;
+            *next_id += 1;
+            *next_id
+        };
+
+        let (tx, rx) = queue::queue(self.queues.for_channel(channel));
+        // Without a replay there is nothing to order against publishing, so
+        // connecting does not wait for an event being fanned out.
+        let buffer = if replay.is_empty() { None } else { Some(self.replay.lock().await) };
+        let (mut backlog, complete) = buffer.as_ref().map_or((Vec::new(), true), |buffer| buffer.replay(channel, replay));
+        if let (false, Some(log)) = (complete, &self.log) {
+            let log = log.clone();
+            let channel = channel.to_string();
+            match tokio::task::spawn_blocking(move || log.lock().unwrap().replay(&channel, replay)).await {
+                Ok(Ok(events)) => backlog = events,
+                Ok(Err(e)) => warn!("Failed to read replay for user {} from the event log: {}", id, e),
+                Err(e) => warn!("Failed to read replay for user {} from the event log: {}", id, e),
+            }
+        }
+        if !backlog.is_empty() {
+            info!("Replaying {} events to user {}", backlog.len(), id);
+        }
+        for event in backlog {
+            tx.send_unbounded(Delivery::text(Some(event.id), event.payload));
+        }
+        let user = User {
+            id,
+            channel: channel.to_string(),
+            remote_addr: subscription.remote_addr,
+            principal: subscription.principal.clone(),
+            subscribed: true,
+            filter: None,
+            last_ack: None,
+            connected_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+            last_pong: Instant::now(),
+            tx,
+        };
+        self.users.insert(id, user);
+        drop(buffer);
+        (id, rx)
+    }
+
+    pub async fn unregister(&self, id: usize) {
+        let Some(user) = self.users.remove(id) else {
+            return;
+        };
+        self.bridge.remove_user(id);
+        info!(
+            "Unregistered user {} from {} (channel {}, principal {})",
+            id,
+            user.remote_addr,
+            user.channel,
+            user.principal.as_deref().unwrap_or("anonymous")
+        );
+    }
+
+    /// Queues a close frame for every user, ahead of any limit, so each
+    /// connection ends once it has written what it already had queued.
+    pub async fn close_all(&self, code: u16, reason: &str, undelivered: Reason) {
+        info!("Closing {} connections", self.users.len());
+        self.users.for_each(None, |user| user.tx.send_unbounded(Delivery::close(code, reason, undelivered)));
+    }
+
+    /// Resolves once every user has unregistered.
+    pub async fn closed(&self) {
+        while !self.users.is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Sends a message to every connected user regardless of channel. The
+    /// message is serialized once and shared by every recipient's queue.
+    pub async fn broadcast(&self, message: impl Serialize) -> Result<(), Box<dyn std::error::Error>> {
+        let json: Arc<str> = serde_json::to_string(&message)?.into();
+        let mut parsed = None;
+        let mut permits = self.reserve(None, &json, &mut parsed).inspect_err(|backpressure| {
+            warn!("Refusing broadcast, queue of user {} is full", backpressure.user_id);
+        })?;
+        let start = Instant::now();
+        let mut queued = 0;
+        self.users.for_each(None, |user| {
+            if self.offer(user, &json, &mut parsed, permits.remove(&user.id), Delivery::text(None, json.clone())) {
+                queued += 1;
+            }
+        });
+        self.metrics.fanout("broadcast", queued, start.elapsed());
+        self.node.broadcast(&json);
+        info!("Broadcast to {} users", queued);
+        Ok(())
+    }
+
+    /// Records a message in the event log and the channel's replay buffer and
+    /// sends it to the subscribers of `channel` and to users subscribed to all
+    /// channels. Returns the id assigned to the event once it is durable.
+    pub async fn publish(&self, channel: &str, message: impl Serialize) -> Result<u64, Box<dyn std::error::Error>> {
+        let json = serde_json::to_string(&message)?;
+        self.publish_with(channel, |_| Ok(json)).await
+    }
+
+    /// Like `publish`, for messages that embed their own event id: `build`
+    /// is given the id the event will be assigned and returns its JSON.
+    pub async fn publish_with(
+        &self,
+        channel: &str,
+        build: impl FnOnce(u64) -> serde_json::Result<String>,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        info!("Publishing to channel {}", channel);
+        let mut buffer = self.replay.lock().await;
+        let json = build(buffer.next_id())?;
+        let channels = [channel, ALL_CHANNELS];
+        let mut parsed = None;
+        // Refuse before the event is recorded, so the sender's retry is not
+        // a duplicate.
+        let mut permits = self.reserve(Some(&channels), &json, &mut parsed).inspect_err(|backpressure| {
+            warn!("Refusing event on channel {}, queue of user {} is full", channel, backpressure.user_id);
+        })?;
+        if let Some(log) = &self.log {
+            let log = log.clone();
+            let record = LogRecord { id: buffer.next_id(), channel: channel.to_string(), payload: json.clone() };
+            tokio::task::spawn_blocking(move || log.lock().unwrap().append(&record)).await??;
+        }
+        let event = buffer.push(channel, json);
+        let start = Instant::now();
+        let mut queued = 0;
+        self.users.for_each(Some(&channels), |user| {
+            let delivery = Delivery::text(Some(event.id), event.payload.clone());
+            if self.offer(user, &event.payload, &mut parsed, permits.remove(&user.id), delivery) {
+                queued += 1;
+            }
+        });
+        self.metrics.fanout("publish", queued, start.elapsed());
+        info!("Published event {} on channel {} to {} users", event.id, channel, queued);
+        self.node.publish(channel, event.id, &event.payload);
+        Ok(event.id)
+    }
+
+    /// Sends a message published on another node to the subscribers of
+    /// `channel` here, or to every user when `channel` is `None`. It is not
+    /// recorded: replay only covers events published on this node, and the
+    /// message is not refused when a queue is full but dead-lettered.
+    pub async fn relay(&self, channel: Option<&str>, payload: String) {
+        let payload: Arc<str> = payload.into();
+        let channels = channel.map(|channel| [channel, ALL_CHANNELS]);
+        let start = Instant::now();
+        let mut parsed = None;
+        let mut queued = 0;
+        self.users.for_each(channels.as_ref().map(|channels| &channels[..]), |user| {
+            if self.offer(user, &payload, &mut parsed, None, Delivery::text(None, payload.clone())) {
+                queued += 1;
+            }
+        });
+        self.metrics.fanout("relay", queued, start.elapsed());
+    }
+
+    /// Queues a message for one user. A message that cannot be queued is
+    /// dead-lettered, and the returned [`SendError`] says why.
+    pub async fn send_to(&self, user_id: usize, message: impl Serialize) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Sending message to user {}", user_id);
+        let json = serde_json::to_string(&message)?;
+        let start = Instant::now();
+        let Some(queued) = self.users.get(user_id, |user| self.enqueue(user, None, Delivery::text(None, json))) else {
+            warn!("User {} not found", user_id);
+            return Err(Box::new(SendError::NoSuchUser));
+        };
+        self.metrics.fanout("direct", queued.is_ok() as usize, start.elapsed());
+        queued?;
+        info!("Message sent to user {}", user_id);
+        Ok(())
+    }
+
+    /// Closes a user's connection with `code` ahead of anything still queued
+    /// for it, which is dead-lettered as `undelivered`.
+    pub async fn disconnect(&self, user_id: usize, code: u16, reason: &str, undelivered: Reason) -> Result<(), SendError> {
+        let close = Delivery::close(code, reason, undelivered);
+        self.users.get(user_id, |user| user.tx.send_first(close)).ok_or(SendError::NoSuchUser)?;
+        info!("Disconnecting user {}: {}", user_id, reason);
+        Ok(())
+    }
+
+    /// Every registered user, optionally only those of one channel, by id.
+    pub async fn clients(&self, channel: Option<&str>) -> Vec<ClientInfo> {
+        let mut clients = Vec::new();
+        self.users.for_each(channel.as_ref().map(std::slice::from_ref), |user| clients.push(user.info()));
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    /// Resumes sending events to a user, with `filter` replacing its
+    /// previous filter; `None` lets every event of its channel through.
+    pub async fn subscribe(&self, user_id: usize, filter: Option<Filter>) -> Result<(), SendError> {
+        let filter = filter.map(Arc::new);
+        self.users
+            .get_mut(user_id, |user| {
+                user.subscribed = true;
+                user.filter = filter;
+            })
+            .ok_or(SendError::NoSuchUser)
+    }
+
+    /// Stops sending events to a user; direct messages still reach it.
+    pub async fn unsubscribe(&self, user_id: usize) -> Result<(), SendError> {
+        self.users.get_mut(user_id, |user| user.subscribed = false).ok_or(SendError::NoSuchUser)
+    }
+
+    /// Records that a user has received every event up to `event_id`.
+    pub async fn ack(&self, user_id: usize, event_id: u64) -> Result<(), SendError> {
+        self.users.get_mut(user_id, |user| user.last_ack = user.last_ack.max(Some(event_id))).ok_or(SendError::NoSuchUser)
+    }
+
+    pub async fn pong(&self, user_id: usize) {
+        self.users.get_mut(user_id, |user| user.last_pong = Instant::now());
+    }
+
+    pub async fn last_pong(&self, user_id: usize) -> Option<Instant> {
+        self.users.get(user_id, |user| user.last_pong)
+    }
+
+    pub async fn client_info(&self, user_id: usize) -> Option<ClientInfo> {
+        self.users.get(user_id, User::info)
+    }
+
+    /// Queues `delivery` for one user. Unlike `send_to`, a failure is only
+    /// reported to the caller and not dead-lettered.
+    pub async fn try_send_to(&self, user_id: usize, delivery: Delivery) -> Result<(), SendError> {
+        let sent = self.users.get(user_id, |user| match user.tx.send(delivery) {
+            Ok(evicted) => {
+                if let Some(evicted) = evicted {
+                    self.dead_letter(user.id, &user.channel, evicted, Reason::QueueOverflow, "evicted as the oldest message".to_string());
+                }
+                Ok(())
+            }
+            Err(PushError::Full(_)) => Err(SendError::Full),
+            Err(PushError::Closed(_) | PushError::Overflowed(_)) => Err(SendError::Closed),
+        });
+        sent.unwrap_or(Err(SendError::NoSuchUser))
+    }
+
+    /// Reserves a slot for `json` in the queue of every user under the
+    /// `block` policy that would receive it, so that the check and the send
+    /// cannot be split by another message filling the queue. Fails with the
+    /// first user whose queue is full, releasing the slots already reserved.
+    fn reserve(
+        &self,
+        channels: Option<&[&str]>,
+        json: &str,
+        parsed: &mut Option<serde_json::Value>,
+    ) -> Result<HashMap<usize, Permit>, Backpressure> {
+        let mut permits = HashMap::new();
+        let full = self.users.find_map(channels, |user| {
+            if user.tx.limit().policy != OverflowPolicy::Block || !user.wants(json, parsed) {
+                return None;
+            }
+            match user.tx.reserve() {
+                Some(permit) => {
+                    permits.insert(user.id, permit);
+                    None
+                }
+                None => Some(user.id),
+            }
+        });
+        match full {
+            Some(user_id) => Err(Backpressure { user_id }),
+            None => Ok(permits),
+        }
+    }
+
+    /// Queues `delivery`, the event `json`, for `user` if it wants it, and
+    /// dead-letters it if the user's filter drops it; users not subscribed
+    /// are skipped. Returns whether it was queued.
+    fn offer(
+        &self,
+        user: &User,
+        json: &str,
+        parsed: &mut Option<serde_json::Value>,
+        permit: Option<Permit>,
+        delivery: Delivery,
+    ) -> bool {
+        if user.wants(json, parsed) {
+            return self.enqueue(user, permit, delivery).is_ok();
+        }
+        if user.subscribed {
+            let detail = "did not match the client's filter".to_string();
+            self.dead_letter(user.id, &user.channel, delivery, Reason::FilterRejected, detail);
+        }
+        false
+    }
+
+    /// Queues `delivery` for `user` under its overflow policy, or in the slot
+    /// `permit` reserved, dead-lettering whatever is dropped instead. Fails if
+    /// `delivery` itself was dropped.
+    fn enqueue(&self, user: &User, permit: Option<Permit>, delivery: Delivery) -> Result<(), SendError> {
+        let limit = user.tx.limit();
+        let pushed = match permit {
+            Some(permit) => permit.send(delivery).map(|()| None),
+            None => user.tx.send(delivery),
+        };
+        let (queued, dropped, reason, detail) = match pushed {
+            Ok(None) => return Ok(()),
+            Ok(Some(evicted)) => (Ok(()), evicted, Reason::QueueOverflow, "evicted as the oldest message".to_string()),
+            Err(PushError::Closed(delivery)) => {
+                let detail = format!("connection from {} closed", user.remote_addr);
+                (Err(SendError::Closed), delivery, Reason::ClientClosed, detail)
+            }
+            Err(PushError::Full(delivery)) => {
+                let detail = format!("queue full ({} messages, {})", limit.capacity, limit.policy);
+                (Err(SendError::Full), delivery, Reason::QueueOverflow, detail)
+            }
+            Err(PushError::Overflowed(delivery)) => {
+                warn!("Disconnecting user {}, its queue of {} messages is full", user.id, limit.capacity);
+                (Err(SendError::Closed), delivery, Reason::QueueOverflow, "queue full, disconnecting the client".to_string())
+            }
+        };
+        warn!("Failed to send message to user {}", user.id);
+        self.dead_letter(user.id, &user.channel, dropped, reason, detail);
+        queued
+    }
+
+    /// Records a message meant for a user that never reached it.
+    pub fn dead_letter(&self, user_id: usize, channel: &str, delivery: Delivery, reason: Reason, detail: String) {
+        self.metrics.dropped(&reason.to_string());
+        self.dead_letters.push(Undeliverable {
+            reason,
+            detail,
+            event_id: delivery.id,
+            channel: Some(channel.to_string()),
+            client: Some(user_id),
+            target: None,
+            payload: delivery.message.to_string(),
+            payload_encoding: PayloadEncoding::Text,
+            forward: None,
+        });
+    }
+}
+
+#[cfg(test)]
+impl WebSocketServer {
+    /// A server with small buffers, no event log and no heartbeats, alone on
+    /// an in-process backplane, for tests of the modules built on it.
+    pub fn for_tests() -> Self {
+        use crate::backplane::{BackplaneKind, BackplaneSettings};
+        use crate::queue::{OverflowPolicy, QueueLimit};
+        let queues = QueueSettings {
+            default: QueueLimit { capacity: 16, policy: OverflowPolicy::DropOldest },
+            channels: Default::default(),
+        };
+        let node = BackplaneSettings { node_id: Some("test".to_string()), kind: BackplaneKind::InProcess }.start();
+        Self::new(
+            ReplayBuffer::new(16, 16),
+            None,
+            DeadLetters::new(100),
+            queues,
+            Metrics::new(),
+            None,
+            Bridge::new(Duration::from_secs(1), true),
+            node,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::PublishGrant;
+    use crate::replay::ReplayRequest;
+
+    fn subscription(channel: &str) -> Subscription {
+        Subscription {
+            channel: channel.to_string(),
+            remote_addr: "127.0.0.1:1".parse().unwrap(),
+            principal: None,
+            token_source: None,
+            replay: ReplayRequest::default(),
+            publish: PublishGrant::None,
+        }
+    }
+
+    #[tokio::test]
+    async fn dead_letters_events_a_filter_drops() {
+        let ws_server = WebSocketServer::for_tests();
+        let (filtered, mut filtered_rx) = ws_server.register(&subscription("orders")).await;
+        let (idle, _idle_rx) = ws_server.register(&subscription("orders")).await;
+        let opened: Filter = serde_json::from_str(r#"{"equals": {"path": "/action", "value": "opened"}}"#).unwrap();
+        ws_server.subscribe(filtered, Some(opened)).await.unwrap();
+        ws_server.unsubscribe(idle).await.unwrap();
+
+        let opened = ws_server.publish("orders", serde_json::json!({"action": "opened"})).await.unwrap();
+        let closed = ws_server.publish("orders", serde_json::json!({"action": "closed"})).await.unwrap();
+        assert_eq!(filtered_rx.try_recv().and_then(|delivery| delivery.id), Some(opened));
+        assert!(filtered_rx.try_recv().is_none());
+        // Only the filtered subscriber's copy is recorded; a client that
+        // unsubscribed asked for nothing.
+        let letters = ws_server.dead_letters.list(Some(Reason::FilterRejected), None);
+        assert_eq!(letters.len(), 1);
+        let letter = &letters[0].message;
+        assert_eq!((letter.client, letter.event_id, letter.channel.as_deref()), (Some(filtered), Some(closed), Some("orders")));
+        assert_eq!(ws_server.dead_letters.list(None, None).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn close_frames_say_how_to_dead_letter_what_follows() {
+        let ws_server = WebSocketServer::for_tests();
+        let (kicked, mut kicked_rx) = ws_server.register(&subscription("orders")).await;
+        let (_, mut other_rx) = ws_server.register(&subscription("orders")).await;
+        ws_server.publish("orders", serde_json::json!({})).await.unwrap();
+        ws_server.disconnect(kicked, 1008, "kicked", Reason::Disconnected).await.unwrap();
+        ws_server.close_all(1001, "bye", Reason::Shutdown).await;
+
+        let close = |delivery: Option<Delivery>| match delivery.map(|delivery| delivery.message) {
+            Some(Outbound::Close { code, undelivered, .. }) => (code, undelivered),
+            other => panic!("expected a close frame, got {:?}", other),
+        };
+        // An administrator's close goes ahead of the queue; shutting down
+        // lets the queue drain first.
+        assert_eq!(close(kicked_rx.try_recv()), (1008, Reason::Disconnected));
+        assert!(other_rx.try_recv().is_some_and(|delivery| delivery.id == Some(1)));
+        assert_eq!(close(other_rx.try_recv()), (1001, Reason::Shutdown));
+    }
+}
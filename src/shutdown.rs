@@ -0,0 +1,136 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{watch, Notify};
+use warp::http::StatusCode;
+use warp::Reply;
+use log::error;
+
+/// Readiness and drain state shared by the listeners and `main`.
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    ready: AtomicBool,
+    in_flight: AtomicUsize,
+    idle: Notify,
+    draining: watch::Sender<bool>,
+}
+
+impl Shutdown {
+    /// Starts out not ready; see [`Shutdown::set_ready`].
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                ready: AtomicBool::new(false),
+                in_flight: AtomicUsize::new(0),
+                idle: Notify::new(),
+                draining: watch::Sender::new(false),
+            }),
+        }
+    }
+
+    /// Marks the server ready once every listener is bound.
+    pub fn set_ready(&self) {
+        self.inner.ready.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.inner.ready.load(Ordering::SeqCst)
+    }
+
+    /// Marks the server not ready while it goes on serving, so that load
+    /// balancers can take it out of rotation before [`Shutdown::begin`].
+    pub fn set_not_ready(&self) {
+        self.inner.ready.store(false, Ordering::SeqCst);
+    }
+
+    /// Marks the server not ready and tells the listeners to stop accepting
+    /// connections and to close idle ones.
+    pub fn begin(&self) {
+        self.inner.ready.store(false, Ordering::SeqCst);
+        self.inner.draining.send_replace(true);
+    }
+
+    /// Resolves once [`Shutdown::begin`] has been called.
+    pub async fn draining(&self) {
+        let mut draining = self.inner.draining.subscribe();
+        let _ = draining.wait_for(|draining| *draining).await;
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped.
+    pub fn request(&self) -> InFlight {
+        self.inner.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight { inner: self.inner.clone() }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.inner.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once no request is in flight.
+    pub async fn drained(&self) {
+        loop {
+            let idle = self.inner.idle.notified();
+            if self.in_flight() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct InFlight {
+    inner: Arc<Inner>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.inner.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.idle.notify_waiters();
+        }
+    }
+}
+
+/// Resolves on SIGINT or, on Unix, SIGTERM.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => error!("Failed to install SIGTERM handler: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Failed to install SIGINT handler: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+/// `GET /healthz`: the process is up and serving requests.
+pub async fn handle_healthz() -> Result<warp::reply::Response, warp::Rejection> {
+    Ok(warp::reply::with_status("ok", StatusCode::OK).into_response())
+}
+
+/// `GET /readyz`: 200 once listening, 503 while starting or shutting down.
+pub async fn handle_readyz(shutdown: Shutdown) -> Result<warp::reply::Response, warp::Rejection> {
+    if shutdown.is_ready() {
+        Ok(warp::reply::with_status("ready", StatusCode::OK).into_response())
+    } else {
+        Ok(warp::reply::with_status("not ready", StatusCode::SERVICE_UNAVAILABLE).into_response())
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use warp::http::HeaderMap;
+use log::warn;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Provider {
+    /// `X-Hub-Signature-256: sha256=<hex>` over the raw body.
+    GitHub,
+    /// `Stripe-Signature: t=<ts>,v1=<hex>` over `<ts>.<body>`.
+    Stripe,
+    /// `X-Slack-Signature: v0=<hex>` over `v0:<ts>:<body>`, with the
+    /// timestamp taken from `X-Slack-Request-Timestamp`.
+    Slack,
+    /// Hex HMAC-SHA256 of the raw body in a configurable header, with an
+    /// optional `sha256=` prefix.
+    Generic { header: String },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    MissingHeader(String),
+    Malformed(&'static str),
+    Expired,
+    Mismatch,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::MissingHeader(name) => write!(f, "missing signature header {}", name),
+            VerifyError::Malformed(what) => write!(f, "malformed signature: {}", what),
+            VerifyError::Expired => write!(f, "signature timestamp outside tolerance"),
+            VerifyError::Mismatch => write!(f, "signature mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    pub provider: Provider,
+    secret: Vec<u8>,
+}
+
+impl Verifier {
+    pub fn new(provider: Provider, secret: impl Into<Vec<u8>>) -> Self {
+        Self { provider, secret: secret.into() }
+    }
+
+    pub fn verify(&self, headers: &HeaderMap, body: &[u8], tolerance_secs: u64) -> Result<(), VerifyError> {
+        match &self.provider {
+            Provider::GitHub => {
+                let value = header(headers, "x-hub-signature-256")?;
+                let hex_sig = value.strip_prefix("sha256=").ok_or(VerifyError::Malformed("expected sha256= prefix"))?;
+                self.check(&[body], hex_sig)
+            }
+            Provider::Stripe => {
+                let value = header(headers, "stripe-signature")?;
+                let mut timestamp = None;
+                let mut signatures = Vec::new();
+                for part in value.split(',') {
+                    match part.trim().split_once('=') {
+                        Some(("t", t)) => timestamp = Some(t),
+                        Some(("v1", sig)) => signatures.push(sig),
+                        _ => {}
+                    }
+                }
+                let timestamp = timestamp.ok_or(VerifyError::Malformed("missing t= timestamp"))?;
+                check_timestamp(timestamp, tolerance_secs)?;
+                // Stripe sends several v1 signatures while a secret is being rolled.
+                if signatures
+                    .iter()
+                    .any(|sig| self.check(&[timestamp.as_bytes(), b".", body], sig).is_ok())
+                {
+                    Ok(())
+                } else {
+                    Err(VerifyError::Mismatch)
+                }
+            }
+            Provider::Slack => {
+                let timestamp = header(headers, "x-slack-request-timestamp")?;
+                check_timestamp(timestamp, tolerance_secs)?;
+                let value = header(headers, "x-slack-signature")?;
+                let hex_sig = value.strip_prefix("v0=").ok_or(VerifyError::Malformed("expected v0= prefix"))?;
+                self.check(&[b"v0:", timestamp.as_bytes(), b":", body], hex_sig)
+            }
+            Provider::Generic { header: name } => {
+                let value = header(headers, name)?;
+                self.check(&[body], value.strip_prefix("sha256=").unwrap_or(value))
+            }
+        }
+    }
+
+    /// Compares the HMAC of the concatenated `parts` against a hex signature
+    /// in constant time.
+    fn check(&self, parts: &[&[u8]], hex_sig: &str) -> Result<(), VerifyError> {
+        let expected = hex::decode(hex_sig.trim()).map_err(|_| VerifyError::Malformed("signature is not hex"))?;
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        for part in parts {
+            mac.update(part);
+        }
+        mac.verify_slice(&expected).map_err(|_| VerifyError::Mismatch)
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, VerifyError> {
+    headers
+        .get(name)
+        .ok_or_else(|| VerifyError::MissingHeader(name.to_string()))?
+        .to_str()
+        .map_err(|_| VerifyError::Malformed("header is not valid ASCII"))
+}
+
+fn check_timestamp(timestamp: &str, tolerance_secs: u64) -> Result<(), VerifyError> {
+    let timestamp: u64 = timestamp.trim().parse().map_err(|_| VerifyError::Malformed("timestamp is not a number"))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    if now.abs_diff(timestamp) > tolerance_secs {
+        return Err(VerifyError::Expired);
+    }
+    Ok(())
+}
+
+/// Per-channel verifiers. Channels without one accept unsigned requests.
+#[derive(Debug, Clone)]
+pub struct Verifiers {
+    by_channel: HashMap<String, Verifier>,
+    tolerance_secs: u64,
+}
+
+impl Verifiers {
+    pub fn new(tolerance_secs: u64) -> Self {
+        Self { by_channel: HashMap::new(), tolerance_secs }
+    }
+
+    pub fn insert(&mut self, channel: &str, verifier: Verifier) {
+        self.by_channel.insert(channel.to_string(), verifier);
+    }
+
+    pub fn verify(&self, channel: &str, headers: &HeaderMap, body: &[u8]) -> Result<(), VerifyError> {
+        match self.by_channel.get(channel) {
+            Some(verifier) => verifier.verify(headers, body, self.tolerance_secs).map_err(|e| {
+                warn!("Signature verification failed on channel {}: {}", channel, e);
+                e
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// From GitHub's documentation on validating webhook deliveries.
+    const GITHUB_SECRET: &str = "It's a Secret to Everybody";
+    const GITHUB_SIGNATURE: &str = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    /// From Slack's documentation on verifying requests.
+    const SLACK_SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+    const SLACK_TIMESTAMP: &str = "1531420618";
+    const SLACK_BODY: &str = "token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow&channel_id=G8PSS9T3V\
+        &channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner&command=%2Fwebhook-collect&text=\
+        &response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2FT1DC2JH3J%2F397700885554%2F96rGlfmibIGlgcZRskXaIFfN\
+        &trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c";
+    const SLACK_SIGNATURE: &str = "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503";
+
+    /// HMAC-SHA256 of `1492774577.{"id":"evt_test"}` under `whsec_test_secret`
+    /// and `whsec_old_secret`.
+    const STRIPE_BODY: &str = r#"{"id":"evt_test"}"#;
+    const STRIPE_SIGNATURE: &str = "22f7d74836ddad3284a2b80853dd2fe731655c1bfddcff3c3666a500cf3abd80";
+    const STRIPE_OLD_SIGNATURE: &str = "5ea6a3950bfdf95bcb05ee69f8bf76ffd92759b844cac711c90b9b9053593279";
+
+    /// Accepts any timestamp, for vectors signed long ago.
+    const ANY_TIME: u64 = u64::MAX;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        pairs.iter().map(|(name, value)| (warp::http::HeaderName::from_static(name), value.parse().unwrap())).collect()
+    }
+
+    fn now() -> String {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().to_string()
+    }
+
+    fn sign(secret: &str, parts: &[&[u8]]) -> String {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        parts.iter().for_each(|part| mac.update(part));
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn github_vector() {
+        let verifier = Verifier::new(Provider::GitHub, GITHUB_SECRET);
+        let signed = headers(&[("x-hub-signature-256", GITHUB_SIGNATURE)]);
+        assert_eq!(verifier.verify(&signed, b"Hello, World!", 300), Ok(()));
+        assert_eq!(verifier.verify(&signed, b"Hello, World?", 300), Err(VerifyError::Mismatch));
+        assert_eq!(
+            verifier.verify(&HeaderMap::new(), b"Hello, World!", 300),
+            Err(VerifyError::MissingHeader("x-hub-signature-256".into()))
+        );
+        let unprefixed = headers(&[("x-hub-signature-256", GITHUB_SIGNATURE.trim_start_matches("sha256="))]);
+        assert!(matches!(verifier.verify(&unprefixed, b"Hello, World!", 300), Err(VerifyError::Malformed(_))));
+    }
+
+    #[test]
+    fn slack_vector() {
+        let verifier = Verifier::new(Provider::Slack, SLACK_SECRET);
+        let signed = headers(&[("x-slack-request-timestamp", SLACK_TIMESTAMP), ("x-slack-signature", SLACK_SIGNATURE)]);
+        assert_eq!(verifier.verify(&signed, SLACK_BODY.as_bytes(), ANY_TIME), Ok(()));
+        let tampered = SLACK_BODY.replace("roadrunner", "coyote");
+        assert_eq!(verifier.verify(&signed, tampered.as_bytes(), ANY_TIME), Err(VerifyError::Mismatch));
+        // The timestamp is signed too, so it cannot be moved into the window.
+        let replayed = headers(&[("x-slack-request-timestamp", &now()), ("x-slack-signature", SLACK_SIGNATURE)]);
+        assert_eq!(verifier.verify(&replayed, SLACK_BODY.as_bytes(), 300), Err(VerifyError::Mismatch));
+    }
+
+    #[test]
+    fn stripe_vector_with_rolled_secrets() {
+        let verifier = Verifier::new(Provider::Stripe, "whsec_test_secret");
+        let value = format!("t=1492774577,v1={},v0=ignored", STRIPE_SIGNATURE);
+        assert_eq!(verifier.verify(&headers(&[("stripe-signature", &value)]), STRIPE_BODY.as_bytes(), ANY_TIME), Ok(()));
+        // While a secret is rolled Stripe signs with both; either may come first.
+        let value = format!("t=1492774577, v1={}, v1={}", STRIPE_OLD_SIGNATURE, STRIPE_SIGNATURE);
+        assert_eq!(verifier.verify(&headers(&[("stripe-signature", &value)]), STRIPE_BODY.as_bytes(), ANY_TIME), Ok(()));
+        let value = format!("t=1492774577,v1={}", STRIPE_OLD_SIGNATURE);
+        let signed = headers(&[("stripe-signature", &value)]);
+        assert_eq!(verifier.verify(&signed, STRIPE_BODY.as_bytes(), ANY_TIME), Err(VerifyError::Mismatch));
+        let untimed = headers(&[("stripe-signature", &format!("v1={}", STRIPE_SIGNATURE))]);
+        assert!(matches!(verifier.verify(&untimed, STRIPE_BODY.as_bytes(), ANY_TIME), Err(VerifyError::Malformed(_))));
+    }
+
+    #[test]
+    fn rejects_stale_timestamps_before_checking_the_signature() {
+        let stripe = Verifier::new(Provider::Stripe, "whsec_test_secret");
+        let value = format!("t=1492774577,v1={}", STRIPE_SIGNATURE);
+        let signed = headers(&[("stripe-signature", &value)]);
+        assert_eq!(stripe.verify(&signed, STRIPE_BODY.as_bytes(), 300), Err(VerifyError::Expired));
+
+        let slack = Verifier::new(Provider::Slack, SLACK_SECRET);
+        let signed = headers(&[("x-slack-request-timestamp", SLACK_TIMESTAMP), ("x-slack-signature", SLACK_SIGNATURE)]);
+        assert_eq!(slack.verify(&signed, SLACK_BODY.as_bytes(), 300), Err(VerifyError::Expired));
+
+        // Timestamps in the future are held to the same tolerance.
+        let ahead = (SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 3600).to_string();
+        let signature = sign("whsec_test_secret", &[ahead.as_bytes(), b".", STRIPE_BODY.as_bytes()]);
+        let value = format!("t={},v1={}", ahead, signature);
+        let signed = headers(&[("stripe-signature", &value)]);
+        assert_eq!(stripe.verify(&signed, STRIPE_BODY.as_bytes(), 300), Err(VerifyError::Expired));
+    }
+
+    #[test]
+    fn accepts_fresh_timestamps() {
+        let timestamp = now();
+        let signature = sign(SLACK_SECRET, &[b"v0:", timestamp.as_bytes(), b":", b"{}"]);
+        let value = format!("v0={}", signature);
+        let signed = headers(&[("x-slack-request-timestamp", &timestamp), ("x-slack-signature", &value)]);
+        assert_eq!(Verifier::new(Provider::Slack, SLACK_SECRET).verify(&signed, b"{}", 300), Ok(()));
+    }
+
+    #[test]
+    fn near_misses_are_mismatches() {
+        let verifier = Verifier::new(Provider::GitHub, GITHUB_SECRET);
+        let body = b"Hello, World!";
+        // A signature differing only in its last byte, or a prefix of the
+        // right one, fails like any other: the comparison is the MAC's
+        // constant-time one, never a byte-by-byte match on the hex.
+        let mut last_byte = GITHUB_SIGNATURE.to_string();
+        last_byte.replace_range(last_byte.len() - 2.., "00");
+        let truncated = &GITHUB_SIGNATURE[..GITHUB_SIGNATURE.len() - 2];
+        for signature in [last_byte.as_str(), truncated, "sha256="] {
+            let signed = headers(&[("x-hub-signature-256", signature)]);
+            assert_eq!(verifier.verify(&signed, body, 300), Err(VerifyError::Mismatch), "{}", signature);
+        }
+        let signed = headers(&[("x-hub-signature-256", "sha256=not-hex")]);
+        assert!(matches!(verifier.verify(&signed, body, 300), Err(VerifyError::Malformed(_))));
+    }
+
+    #[test]
+    fn generic_header_with_optional_prefix() {
+        let verifier = Verifier::new(Provider::Generic { header: "x-signature".into() }, GITHUB_SECRET);
+        let hex_sig = GITHUB_SIGNATURE.trim_start_matches("sha256=");
+        for value in [hex_sig, GITHUB_SIGNATURE] {
+            assert_eq!(verifier.verify(&headers(&[("x-signature", value)]), b"Hello, World!", 300), Ok(()));
+        }
+    }
+
+    #[test]
+    fn channels_without_a_verifier_accept_unsigned_requests() {
+        let mut verifiers = Verifiers::new(300);
+        verifiers.insert("github", Verifier::new(Provider::GitHub, GITHUB_SECRET));
+        assert_eq!(verifiers.verify("other", &HeaderMap::new(), b"{}"), Ok(()));
+        assert!(verifiers.verify("github", &HeaderMap::new(), b"{}").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use futures_util::stream;
+use warp::http::{HeaderMap, StatusCode};
+use warp::Reply;
+use log::info;
+use crate::auth::Auth;
+use crate::endpoints::Endpoints;
+use crate::listener::ConnectionInfo;
+use crate::server::{Outbound, WebSocketServer};
+use crate::subscription::Subscription;
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Unregisters the SSE subscriber once warp drops its stream, which is how a
+/// client disconnect surfaces.
+struct Registration {
+    id: usize,
+    ws_server: WebSocketServer,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let id = self.id;
+        let ws_server = self.ws_server.clone();
+        info!("SSE connection closed: {}", id);
+        tokio::spawn(async move { ws_server.unregister(id).await });
+    }
+}
+
+/// `GET /events` and `GET /events/{channel}`: the same stream WebSocket
+/// subscribers get, as `text/event-stream`. A `Last-Event-ID` header sent by
+/// a reconnecting `EventSource` resumes after that event.
+pub async fn handle_sse(
+    channel: Option<String>,
+    info: ConnectionInfo,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    ws_server: WebSocketServer,
+    auth: Arc<Auth>,
+    endpoints: Endpoints,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let mut subscription = match Subscription::from_request(channel, info.remote_addr, &headers, &query, &auth, &endpoints) {
+        Ok(subscription) => subscription,
+        Err((body, status)) => {
+            ws_server.metrics.connection("sse", status);
+            return Ok(warp::reply::with_status(body, status).into_response());
+        }
+    };
+    ws_server.metrics.connection("sse", StatusCode::OK);
+    if let Some(last_event_id) = headers.get("last-event-id").and_then(|v| v.to_str().ok()) {
+        if let Ok(since) = last_event_id.trim().parse() {
+            subscription.replay.since = Some(since);
+        }
+    }
+
+    let (id, rx) = ws_server.register(&subscription).await;
+    info!(
+        "New SSE connection: {} from {} (channel {}, principal {})",
+        id,
+        subscription.remote_addr,
+        subscription.channel,
+        subscription.principal.as_deref().unwrap_or("anonymous")
+    );
+
+    let registration = Registration { id, ws_server };
+    let events = stream::unfold((rx, registration), |(mut rx, registration)| async move {
+        let delivery = rx.recv().await?;
+        // Only text frames have an SSE representation; a close ends the stream.
+        let text = match delivery.message {
+            Outbound::Text(text) => text,
+            Outbound::Close { .. } => return None,
+        };
+        registration.ws_server.metrics.bytes_sent("sse", text.len());
+        let mut event = warp::sse::Event::default().data(text.as_ref());
+        if let Some(id) = delivery.id {
+            event = event.id(id.to_string());
+        }
+        Some((Ok::<_, Infallible>(event), (rx, registration)))
+    });
+    let keep_alive = warp::sse::keep_alive().interval(KEEP_ALIVE_INTERVAL);
+    Ok(warp::sse::reply(keep_alive.stream(events)).into_response())
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use warp::http::{HeaderMap, StatusCode};
+use log::warn;
+use crate::auth::{Auth, AuthError, PublishGrant, TokenSource};
+use crate::endpoints::{self, Endpoints};
+use crate::replay::ReplayRequest;
+use crate::server::{self, ALL_CHANNELS};
+
+/// A validated request to subscribe to a channel, shared by the WebSocket
+/// and Server-Sent Events endpoints.
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    pub channel: String,
+    pub remote_addr: SocketAddr,
+    pub principal: Option<String>,
+    pub token_source: Option<TokenSource>,
+    pub replay: ReplayRequest,
+    /// Channels the client may publish to over the WebSocket protocol.
+    pub publish: PublishGrant,
+}
+
+impl Subscription {
+    /// Resolves the channel from the optional path segment, parses the replay
+    /// parameters and authenticates the request. Failures are returned as the
+    /// body and status of the HTTP response to send instead.
+    pub fn from_request(
+        channel: Option<String>,
+        remote_addr: SocketAddr,
+        headers: &HeaderMap,
+        query: &HashMap<String, String>,
+        auth: &Auth,
+        endpoints: &Endpoints,
+    ) -> Result<Self, (&'static str, StatusCode)> {
+        let channel = match channel {
+            Some(channel) if !server::is_valid_channel(&channel) => {
+                return Err(("Invalid channel name", StatusCode::BAD_REQUEST));
+            }
+            Some(channel) => channel,
+            None => ALL_CHANNELS.to_string(),
+        };
+        let replay = match ReplayRequest::from_query(query) {
+            Ok(replay) => replay,
+            Err(e) => {
+                warn!("Rejected subscription to channel {}: {}", channel, e);
+                return Err(("Invalid replay parameters", StatusCode::BAD_REQUEST));
+            }
+        };
+        // An endpoint's channel lives as long as the endpoint, and the
+        // endpoint's token is enough to subscribe to it.
+        let authenticated = match channel.strip_prefix(endpoints::CHANNEL_PREFIX) {
+            Some(token) if endpoints.get(token).is_none() => {
+                warn!("Rejected subscription to channel {}: no such endpoint", channel);
+                return Err(("No such endpoint", StatusCode::NOT_FOUND));
+            }
+            Some(token) => auth.authenticate_with_secret(headers, query, &channel, token),
+            None => auth.authenticate(headers, query, &channel),
+        };
+        let (principal, token_source) = match authenticated {
+            Ok(authenticated) => authenticated,
+            Err(e) => {
+                warn!("Rejected subscription to channel {}: {:?}", channel, e);
+                return Err(match e {
+                    AuthError::MissingToken | AuthError::InvalidToken => ("Unauthorized", StatusCode::UNAUTHORIZED),
+                    AuthError::Forbidden => ("Forbidden", StatusCode::FORBIDDEN),
+                });
+            }
+        };
+        let publish = auth.publish_grant(principal.as_ref());
+        Ok(Self { channel, remote_addr, principal: principal.map(|p| p.name), token_source, replay, publish })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Principal;
+    use crate::endpoints::EndpointSettings;
+
+    fn endpoints() -> Endpoints {
+        Endpoints::new(EndpointSettings {
+            capacity: 10,
+            max_ttl: None,
+            hook_path: "/hook".to_string(),
+            ws_path: "/ws".to_string(),
+            tls: false,
+            tolerance_secs: 300,
+        })
+    }
+
+    fn subscribe(
+        channel: Option<&str>,
+        query: &[(&str, &str)],
+        token: Option<&str>,
+        auth: &Auth,
+    ) -> Result<Subscription, StatusCode> {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = token {
+            headers.insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        }
+        let query = query.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        let remote_addr = "127.0.0.1:1".parse().unwrap();
+        Subscription::from_request(channel.map(String::from), remote_addr, &headers, &query, auth, &endpoints())
+            .map_err(|(_, status)| status)
+    }
+
+    #[test]
+    fn rejects_bad_requests_before_authenticating() {
+        let mut auth = Auth::default();
+        auth.insert(Principal::new("ci", "secret", &["builds"], &[]));
+        assert_eq!(subscribe(Some("Not Valid"), &[], None, &auth).unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(subscribe(Some("builds"), &[("last", "all")], None, &auth).unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(subscribe(Some("hook.missing"), &[], None, &auth).unwrap_err(), StatusCode::NOT_FOUND);
+        assert_eq!(subscribe(Some("builds"), &[], None, &auth).unwrap_err(), StatusCode::UNAUTHORIZED);
+        assert_eq!(subscribe(Some("deploys"), &[], Some("secret"), &auth).unwrap_err(), StatusCode::FORBIDDEN);
+
+        let subscription = subscribe(Some("builds"), &[("since", "4")], Some("secret"), &auth).unwrap();
+        assert_eq!((subscription.channel.as_str(), subscription.principal.as_deref()), ("builds", Some("ci")));
+        assert_eq!(subscription.replay, ReplayRequest { since: Some(4), last: None });
+        let subscription = subscribe(None, &[], None, &Auth::default()).unwrap();
+        assert_eq!(subscription.channel, ALL_CHANNELS);
+    }
+}
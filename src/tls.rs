@@ -0,0 +1,141 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use warp::Filter;
+use log::{error, info, warn};
+use crate::listener::{self, ConnectionInfo};
+
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA bundle used to verify client certificates. Clients may still
+    /// connect without one; the webhook endpoint then refuses them.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsSettings {
+    /// Reads the certificate chain, key and client CA from disk. Errors name
+    /// the config key of the file that could not be used.
+    pub fn load(&self) -> Result<ServerConfig, String> {
+        let certs = read_certs(&self.cert).map_err(|e| format!("tls.cert: {}", e))?;
+        let key = read_key(&self.key).map_err(|e| format!("tls.key: {}", e))?;
+        let builder = ServerConfig::builder();
+        let builder = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(path).map_err(|e| format!("tls.client_ca: {}", e))? {
+                    roots.add(cert).map_err(|e| format!("tls.client_ca: {}", e))?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                    .allow_unauthenticated()
+                    .build()
+                    .map_err(|e| format!("tls.client_ca: {}", e))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(certs, key).map_err(|e| format!("tls.key: {}", e))?;
+        // WebSocket upgrades need HTTP/1.1.
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", path.display()));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .ok_or_else(|| format!("{}: no private key found", path.display()))
+}
+
+/// The TLS acceptor for new connections. Reloading swaps the configuration
+/// used for future handshakes only, so established connections (and their
+/// WebSockets) are untouched.
+#[derive(Clone)]
+pub struct ReloadableAcceptor {
+    settings: TlsSettings,
+    current: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl ReloadableAcceptor {
+    pub fn new(settings: TlsSettings) -> Result<Self, String> {
+        let acceptor = TlsAcceptor::from(Arc::new(settings.load()?));
+        Ok(Self { settings, current: Arc::new(RwLock::new(acceptor)) })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Re-reads the certificate files, keeping the previous configuration if
+    /// they are invalid.
+    pub fn reload(&self) {
+        match self.settings.load() {
+            Ok(config) => {
+                *self.current.write().unwrap() = TlsAcceptor::from(Arc::new(config));
+                info!("Reloaded TLS certificate from {}", self.settings.cert.display());
+            }
+            Err(e) => error!("Keeping the current TLS certificate, reload failed: {}", e),
+        }
+    }
+
+    /// Reloads the certificate whenever the process receives SIGHUP.
+    #[cfg(unix)]
+    pub fn reload_on_sighup(&self) {
+        use tokio::signal::unix::{signal, SignalKind};
+        let acceptor = self.clone();
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                error!("Failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                info!("Received SIGHUP, reloading TLS certificate");
+                acceptor.reload();
+            }
+        });
+    }
+
+    #[cfg(not(unix))]
+    pub fn reload_on_sighup(&self) {}
+}
+
+/// Rejection for webhook requests without a verified client certificate.
+#[derive(Debug)]
+pub struct ClientCertRequired;
+
+impl warp::reject::Reject for ClientCertRequired {}
+
+/// Passes only requests whose connection presented a verified client
+/// certificate, when `required` is set.
+pub fn client_cert_guard(required: bool) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    listener::connection_info()
+        .and_then(move |info: ConnectionInfo| async move {
+            if required && !info.client_cert_verified {
+                warn!("Rejected webhook from {} without a client certificate", info.remote_addr);
+                return Err(warp::reject::custom(ClientCertRequired));
+            }
+            Ok(())
+        })
+        .untuple_one()
+}
@@ -0,0 +1,24 @@
+use serde_json::json;
+use warp::Reply;
+use crate::config::Paths;
+
+const PAGE: &str = include_str!("ui/index.html");
+
+/// The page shows untrusted webhook content, so it may load nothing and talk
+/// to nothing but this server.
+const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; connect-src 'self' ws: wss:";
+
+/// The inspector page, told where the WebSocket and webhook routes are, how
+/// many events to replay on load and which node's events replay covers.
+pub fn page(paths: &Paths, replay: usize, node: &str) -> String {
+    let config = json!({ "ws": paths.ws, "webhook": paths.webhook, "replay": replay, "node": node });
+    // `<` cannot end the surrounding script element once escaped.
+    PAGE.replace("__NWEBHOOK_CONFIG__", &config.to_string().replace('<', "\\u003c"))
+}
+
+/// `GET /`: serves `page` as HTML.
+pub fn reply(page: &str) -> warp::reply::Response {
+    let reply = warp::reply::html(page.to_string());
+    warp::reply::with_header(reply, "content-security-policy", CONTENT_SECURITY_POLICY).into_response()
+}